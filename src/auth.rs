@@ -0,0 +1,39 @@
+use std::hint::black_box;
+
+/// Checks a token sent by a client against a configured one without bailing out at the first differing byte, so
+/// response times don't reveal how much of a guessed token was right.
+pub fn token_matches(provided: Option<&str>, expected: &str) -> bool {
+    let provided = match provided {
+        Some(provided) => provided.as_bytes(),
+        None => return false,
+    };
+
+    // Only the length of the token can leak, which isn't secret.
+    if provided.len() != expected.len() {
+        return false;
+    }
+
+    let difference = provided
+        .iter()
+        .zip(expected.as_bytes())
+        .fold(0u8, |difference, (a, b)| difference | black_box(a ^ b));
+
+    black_box(difference) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_identical_tokens_match() {
+        assert!(token_matches(Some("hunter2"), "hunter2"));
+
+        assert!(!token_matches(None, "hunter2"));
+        assert!(!token_matches(Some(""), "hunter2"));
+        assert!(!token_matches(Some("hunter"), "hunter2"));
+        assert!(!token_matches(Some("hunter22"), "hunter2"));
+        assert!(!token_matches(Some("hunter3"), "hunter2"));
+        assert!(!token_matches(Some("Hunter2"), "hunter2"));
+    }
+}
@@ -1,4 +1,5 @@
 use base64_simd::forgiving_decode_to_vec;
+use core::fmt;
 use hyper::Method;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -12,6 +13,7 @@ pub enum Resources {
     Invites,
     Interactions,
     OAuth2,
+    Users,
     None,
 }
 
@@ -24,21 +26,23 @@ impl Resources {
             "invites" => Self::Invites,
             "interactions" => Self::Interactions,
             "oauth2" => Self::OAuth2,
+            "users" => Self::Users,
             _ => Self::None,
         }
     }
 }
 
-impl ToString for Resources {
-    fn to_string(&self) -> String {
+impl fmt::Display for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Channels => "channels".to_string(),
-            Self::Guilds => "guilds".to_string(),
-            Self::Webhooks => "webhooks".to_string(),
-            Self::Invites => "invites".to_string(),
-            Self::Interactions => "interactions".to_string(),
-            Self::OAuth2 => "oauth2".to_string(),
-            Self::None => "".to_string(),
+            Self::Channels => write!(f, "channels"),
+            Self::Guilds => write!(f, "guilds"),
+            Self::Webhooks => write!(f, "webhooks"),
+            Self::Invites => write!(f, "invites"),
+            Self::Interactions => write!(f, "interactions"),
+            Self::OAuth2 => write!(f, "oauth2"),
+            Self::Users => write!(f, "users"),
+            Self::None => write!(f, ""),
         }
     }
 }
@@ -50,24 +54,33 @@ pub struct BucketInfo {
     pub route_bucket: String,
     pub route_display_bucket: String,
 
-    pub require_auth: bool,
+    /// Whether this is a webhook route authenticated by an interaction token, so its bucket only lives as long as the token does
+    pub uses_interaction_token: bool,
 }
 
 impl BucketInfo {
     pub fn new(method: &Method, path: &str) -> Result<Self, ProxyError> {
-        let path_segments = path.split("/").skip(3).collect::<Vec<&str>>();
+        let mut path_segments = path.split('/').skip(2).collect::<Vec<&str>>();
+
+        // Discord accepts both /api/vNN/... and the version-less /api/..., so the version is dropped before bucketing
+        if path_segments
+            .first()
+            .is_some_and(|segment| is_api_version(segment))
+        {
+            path_segments.remove(0);
+        }
 
-        if path_segments.len() == 0 {
+        if path_segments
+            .first()
+            .is_none_or(|segment| segment.is_empty())
+        {
             return Err(ProxyError::InvalidRequest(format!(
-                "Invalid Path: {}",
+                "Invalid Path: {}, expected /api/v{{version}}/{{resource}} or /api/{{resource}}",
                 path
             )));
         }
 
         let resource = Resources::from_str(path_segments[0]);
-        let require_auth = (resource == Resources::Webhooks && path.split("/").count() != 2)
-            || resource == Resources::OAuth2
-            || resource == Resources::Interactions;
 
         let mut bucket_info = Self {
             resource,
@@ -75,7 +88,7 @@ impl BucketInfo {
             route_bucket: String::new(),
             route_display_bucket: String::new(),
 
-            require_auth,
+            uses_interaction_token: false,
         };
 
         let major_bucket = match bucket_info.resource {
@@ -87,7 +100,7 @@ impl BucketInfo {
                     return Ok(bucket_info);
                 }
 
-                format!("channels/{}", path_segments[1])
+                format!("channels/{}", required_segment(&path_segments, 1, path)?)
             }
             Resources::Guilds => {
                 if path_segments.len() == 3 && path_segments[2] == "channels" {
@@ -103,13 +116,33 @@ impl BucketInfo {
                 }
             }
             Resources::Interactions => {
-                if path_segments.len() == 4 && path_segments[2] == "callback" {
-                    bucket_info.append(&format!("interactions/{}/!/callback", path_segments[1]));
+                // Interaction tokens are always the third segment (e.g. interactions/:id/:token/callback), so they need to be masked
+                if path_segments.len() >= 3 {
+                    bucket_info.append(&format!("interactions/{}/!", path_segments[1]));
+
+                    for segment in &path_segments[3..] {
+                        bucket_info.append(&format!("/{}", segment));
+                    }
 
                     return Ok(bucket_info);
                 }
 
-                format!("interactions/{}", path_segments[1])
+                format!(
+                    "interactions/{}",
+                    required_segment(&path_segments, 1, path)?
+                )
+            }
+            Resources::Users => {
+                // User ids aren't a major parameter, unlike @me which always refers to the current user
+                if path_segments.len() >= 2 {
+                    if is_snowflake(path_segments[1]) {
+                        "users/!*".to_string()
+                    } else {
+                        format!("users/{}", path_segments[1])
+                    }
+                } else {
+                    "users".to_string()
+                }
             }
             _ => {
                 if path_segments.len() >= 2 {
@@ -136,8 +169,14 @@ impl BucketInfo {
                     && method == Method::DELETE
                     && path_segments[i - 1] == "messages"
                 {
-                    let snowflake = u64::from_str_radix(segment, 10).expect("Radix must be 10.");
-                    let message_age_ms = get_snowflake_age_ms(snowflake);
+                    // 20 digit snowflakes can still overflow a u64, those just use the regular bucket
+                    let message_age_ms = match segment.parse::<u64>() {
+                        Ok(snowflake) => get_snowflake_age_ms(snowflake),
+                        Err(_) => {
+                            bucket_info.append("/!*");
+                            continue;
+                        }
+                    };
 
                     if message_age_ms > 14 * 24 * 60 * 60 * 1000 {
                         bucket_info.append("/!14d");
@@ -154,6 +193,15 @@ impl BucketInfo {
                 continue;
             }
 
+            // Bulk deletes have a bucket of their own, which mustn't be confused with the single message buckets next to it
+            if bucket_info.resource == Resources::Channels
+                && *segment == "bulk-delete"
+                && path_segments[i - 1] == "messages"
+            {
+                bucket_info.append("/bulk-delete");
+                break;
+            }
+
             // Split reactions into modify/query buckets
             if bucket_info.resource == Resources::Channels && *segment == "reactions" {
                 if method == Method::PUT || method == Method::DELETE {
@@ -165,11 +213,15 @@ impl BucketInfo {
                 break;
             }
 
-            if segment.len() >= 64 {
+            // Webhook tokens always follow the webhook id (e.g. webhooks/:id/:token/messages/:message_id), so they're masked no matter their length
+            let is_webhook_token = bucket_info.resource == Resources::Webhooks && i == 2;
+
+            if segment.len() >= 64 || is_webhook_token {
                 if let Some(interaction_id) = match bucket_info.resource {
                     Resources::Webhooks => is_interaction_webhook(segment),
                     _ => None,
                 } {
+                    bucket_info.uses_interaction_token = true;
                     bucket_info.append_hidden(&format!("/{}", interaction_id), "/!interaction");
                 } else {
                     bucket_info.append("/!");
@@ -195,6 +247,64 @@ impl BucketInfo {
     }
 }
 
+fn required_segment<'a>(
+    path_segments: &[&'a str],
+    index: usize,
+    path: &str,
+) -> Result<&'a str, ProxyError> {
+    match path_segments.get(index) {
+        Some(segment) if !segment.is_empty() => Ok(segment),
+        _ => Err(ProxyError::InvalidRequest(format!(
+            "Invalid Path: {}",
+            path
+        ))),
+    }
+}
+
+/// Top level resources of Discord's API, on top of the ones bucketed specially in `Resources`
+const OTHER_KNOWN_RESOURCES: [&str; 8] = [
+    "applications",
+    "gateway",
+    "stickers",
+    "sticker-packs",
+    "stage-instances",
+    "voice",
+    "soundboard-default-sounds",
+    "lobbies",
+];
+
+/// Whether a path's top level resource is one Discord's API has, so requests for anything else can be answered
+/// locally instead of costing a round trip for a 404.
+pub fn is_known_resource(path: &str) -> bool {
+    // Empty segments are skipped, as paths are only normalized into their buckets after this check
+    let mut path_segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .skip(1)
+        .peekable();
+
+    if path_segments
+        .peek()
+        .is_some_and(|segment| is_api_version(segment))
+    {
+        path_segments.next();
+    }
+
+    match path_segments.next() {
+        Some(resource) => {
+            Resources::from_str(resource) != Resources::None
+                || OTHER_KNOWN_RESOURCES.contains(&resource)
+        }
+        None => false,
+    }
+}
+
+pub fn is_api_version(segment: &str) -> bool {
+    segment
+        .strip_prefix('v')
+        .is_some_and(|version| !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()))
+}
+
 fn is_snowflake(s: &str) -> bool {
     let length = s.len();
 
@@ -208,7 +318,7 @@ fn get_snowflake_age_ms(snowflake: u64) -> u64 {
         .expect("Time went backwards.")
         .as_millis() as u64;
 
-    now - timestamp
+    now.saturating_sub(timestamp)
 }
 
 fn is_interaction_webhook(token: &str) -> Option<String> {
@@ -216,16 +326,270 @@ fn is_interaction_webhook(token: &str) -> Option<String> {
         return None;
     }
 
-    let interaction_data = String::from_utf8(
-        forgiving_decode_to_vec(token.as_bytes())
-            .expect("Failed to decode base64 interaction data."),
-    )
-    .expect("Interaction data is not valid UTF-8.");
+    let interaction_data =
+        String::from_utf8(forgiving_decode_to_vec(token.as_bytes()).ok()?).ok()?;
+
+    interaction_data
+        .split(':')
+        .nth(1)
+        .map(|interaction_id| interaction_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route_bucket(method: Method, path: &str) -> String {
+        BucketInfo::new(&method, path)
+            .unwrap_or_else(|err| panic!("{} failed to bucket: {}", path, err))
+            .route_bucket
+    }
+
+    /// An interaction token as Discord hands them out, `interaction:{id}:{secret}` base64 encoded
+    fn interaction_token(interaction_id: &str) -> String {
+        base64_simd::STANDARD.encode_to_string(format!(
+            "interaction:{}:aSecretPartOfTheTokenThatIsLongEnoughToBeMasked",
+            interaction_id
+        ))
+    }
 
-    let interaction_id = interaction_data.split(":").skip(1).next();
-    if interaction_id.is_none() {
-        None
-    } else {
-        Some(interaction_id.unwrap().to_string())
+    #[test]
+    fn interaction_tokens_are_masked() {
+        let token = interaction_token("1100000000000000001");
+
+        for (method, path, expected_bucket, expected_display) in [
+            (
+                Method::POST,
+                format!(
+                    "/api/v10/interactions/1100000000000000001/{}/callback",
+                    token
+                ),
+                "interactions/1100000000000000001/!/callback",
+                "interactions/1100000000000000001/!/callback",
+            ),
+            (
+                Method::POST,
+                format!("/api/v10/webhooks/1000000000000000001/{}", token),
+                "webhooks/1000000000000000001/1100000000000000001",
+                "webhooks/1000000000000000001/!interaction",
+            ),
+            (
+                Method::PATCH,
+                format!(
+                    "/api/v10/webhooks/1000000000000000001/{}/messages/@original",
+                    token
+                ),
+                "webhooks/1000000000000000001/1100000000000000001/messages/@original",
+                "webhooks/1000000000000000001/!interaction/messages/@original",
+            ),
+        ] {
+            let bucket_info = BucketInfo::new(&method, &path).unwrap();
+
+            assert_eq!(bucket_info.route_bucket, expected_bucket);
+            assert_eq!(bucket_info.route_display_bucket, expected_display);
+            assert!(!bucket_info.route_bucket.contains(&token));
+            assert!(!bucket_info.route_display_bucket.contains(&token));
+        }
+    }
+
+    #[test]
+    fn interaction_token_routes_get_the_interaction_ttl() {
+        let token = interaction_token("1100000000000000001");
+
+        let followup = BucketInfo::new(
+            &Method::POST,
+            &format!("/api/v10/webhooks/1000000000000000001/{}", token),
+        )
+        .unwrap();
+        assert!(followup.uses_interaction_token);
+
+        let callback = BucketInfo::new(
+            &Method::POST,
+            &format!(
+                "/api/v10/interactions/1100000000000000001/{}/callback",
+                token
+            ),
+        )
+        .unwrap();
+        assert_eq!(callback.resource, Resources::Interactions);
+
+        let webhook = BucketInfo::new(
+            &Method::POST,
+            "/api/v10/webhooks/1000000000000000001/aPlainWebhookToken",
+        )
+        .unwrap();
+        assert!(!webhook.uses_interaction_token);
+    }
+
+    #[test]
+    fn user_routes_share_buckets_across_users() {
+        for (path, expected) in [
+            ("/api/v10/users/@me", "users/@me"),
+            ("/api/v10/users/@me/guilds", "users/@me/guilds"),
+            ("/api/v10/users/@me/channels", "users/@me/channels"),
+            (
+                "/api/v10/users/@me/guilds/1000000000000000001/member",
+                "users/@me/guilds/!*/member",
+            ),
+            ("/api/v10/users/1000000000000000001", "users/!*"),
+            ("/api/v10/users/1000000000000000002", "users/!*"),
+        ] {
+            assert_eq!(route_bucket(Method::GET, path), expected, "{}", path);
+        }
+
+        assert_eq!(
+            BucketInfo::new(&Method::GET, "/api/v10/users/@me")
+                .unwrap()
+                .resource,
+            Resources::Users
+        );
+    }
+
+    #[test]
+    fn versioned_and_versionless_paths_share_buckets() {
+        for route in [
+            "channels/1000000000000000001/messages/1000000000000000002",
+            "guilds/1000000000000000001/members",
+            "users/@me",
+        ] {
+            let versionless = route_bucket(Method::GET, &format!("/api/{}", route));
+
+            assert_eq!(
+                route_bucket(Method::GET, &format!("/api/v9/{}", route)),
+                versionless
+            );
+            assert_eq!(
+                route_bucket(Method::GET, &format!("/api/v10/{}", route)),
+                versionless
+            );
+        }
+    }
+
+    #[test]
+    fn recognizes_api_versions() {
+        assert!(is_api_version("v9"));
+        assert!(is_api_version("v10"));
+
+        assert!(!is_api_version("v"));
+        assert!(!is_api_version("10"));
+        assert!(!is_api_version("vX"));
+        assert!(!is_api_version("voice"));
+    }
+
+    #[test]
+    fn webhook_buckets_are_keyed_by_id_not_token() {
+        let token = "aWebhookTokenThatIsShorterThanTheUsualSixtyFourCharacters";
+
+        for (method, path, expected) in [
+            (
+                Method::POST,
+                format!("/api/v10/webhooks/1000000000000000001/{}", token),
+                "webhooks/1000000000000000001/!",
+            ),
+            (
+                Method::PATCH,
+                format!(
+                    "/api/v10/webhooks/1000000000000000001/{}/messages/1000000000000000002",
+                    token
+                ),
+                "webhooks/1000000000000000001/!/messages/!*",
+            ),
+            (
+                Method::DELETE,
+                format!(
+                    "/api/v10/webhooks/1000000000000000001/{}/messages/1000000000000000002",
+                    token
+                ),
+                "webhooks/1000000000000000001/!/messages/!*",
+            ),
+        ] {
+            let bucket_info = BucketInfo::new(&method, &path).unwrap();
+
+            assert_eq!(bucket_info.route_bucket, expected);
+            assert!(!bucket_info.route_display_bucket.contains(token));
+        }
+    }
+
+    #[test]
+    fn bulk_delete_has_its_own_bucket() {
+        let bulk_delete = route_bucket(
+            Method::POST,
+            "/api/v10/channels/1000000000000000001/messages/bulk-delete",
+        );
+        let single_delete = route_bucket(
+            Method::DELETE,
+            "/api/v10/channels/1000000000000000001/messages/1000000000000000002",
+        );
+
+        assert_eq!(
+            bulk_delete,
+            "channels/1000000000000000001/messages/bulk-delete"
+        );
+        assert_eq!(single_delete, "channels/1000000000000000001/messages/!*");
+
+        // The channel stays the major parameter
+        assert_ne!(
+            bulk_delete,
+            route_bucket(
+                Method::POST,
+                "/api/v10/channels/1000000000000000003/messages/bulk-delete",
+            )
+        );
+    }
+
+    #[test]
+    fn known_resources_are_recognized() {
+        for path in [
+            "/api/v10/channels/1000000000000000001",
+            "/api/v10/guilds/1000000000000000001/members",
+            "/api/v10/users/@me",
+            "/api/v10/gateway/bot",
+            "/api/v10/applications/1000000000000000001/commands",
+            "/api/oauth2/token",
+            "/api/v9/sticker-packs",
+            "/api//channels/1000000000000000001",
+            "//api/v10//users/@me",
+        ] {
+            assert!(is_known_resource(path), "{} should be known", path);
+        }
+
+        for path in [
+            "/api/v10/nonsense",
+            "/api/v10/channel/1000000000000000001",
+            "/api/v10",
+            "/api",
+            "/api//",
+        ] {
+            assert!(!is_known_resource(path), "{} should be unknown", path);
+        }
+    }
+
+    #[test]
+    fn truncated_paths_are_rejected_or_bucketed() {
+        for path in [
+            "/api",
+            "/api/",
+            "/api/v10",
+            "/api/v10/",
+            "/api/v10/channels",
+            "/api/v10/interactions",
+        ] {
+            assert!(
+                BucketInfo::new(&Method::GET, path).is_err(),
+                "{} should be rejected",
+                path
+            );
+        }
+
+        for (path, expected) in [
+            ("/api/v10/guilds", "guilds"),
+            ("/api/v10/users", "users"),
+            ("/api/v10/webhooks", "webhooks"),
+            ("/api/v10/invites", "invites/!"),
+            ("/api/v10/oauth2", "oauth2"),
+            ("/api/v10/gateway", "gateway"),
+        ] {
+            assert_eq!(route_bucket(Method::GET, path), expected, "{}", path);
+        }
     }
 }
@@ -0,0 +1,270 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use axum::response::Response;
+use http::{header::CACHE_CONTROL, HeaderMap, HeaderValue, Request, StatusCode};
+use hyper::{body::Bytes, Body};
+use tokio::sync::Mutex;
+
+use crate::request::response_key;
+
+pub const CACHE_HEADER: &str = "x-proxy-cache";
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// An in-memory LRU cache for successful GET responses.
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+
+    entries: Mutex<AHashMap<String, CachedResponse>>,
+    uses: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+
+            entries: Mutex::new(AHashMap::new()),
+            uses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cache key for a request, or None if its response must not be cached.
+    pub fn key(req: &Request<Body>) -> Option<String> {
+        if is_no_store(req.headers()) {
+            return None;
+        }
+
+        response_key(req)
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Response<Body>> {
+        let mut entries = self.entries.lock().await;
+
+        let entry = entries.get_mut(key)?;
+
+        if entry.expires_at <= Instant::now() {
+            entries.remove(key);
+            return None;
+        }
+
+        entry.last_used = self.uses.fetch_add(1, Ordering::Relaxed);
+
+        let mut response = Response::new(Body::from(entry.body.clone()));
+        *response.status_mut() = entry.status;
+        *response.headers_mut() = entry.headers.clone();
+
+        response
+            .headers_mut()
+            .insert(CACHE_HEADER, HeaderValue::from_static("hit"));
+        response
+            .headers_mut()
+            .insert("x-proxy-served", HeaderValue::from_static("cache"));
+
+        Some(response)
+    }
+
+    pub async fn insert(&self, key: String, status: StatusCode, headers: &HeaderMap, body: Bytes) {
+        if is_no_store(headers) {
+            return;
+        }
+
+        // Ratelimit headers would be stale by the time the response is served again
+        let mut headers = headers.clone();
+        let ratelimit_headers = headers
+            .keys()
+            .filter(|name| name.as_str().starts_with("x-ratelimit-"))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in ratelimit_headers {
+            headers.remove(name);
+        }
+
+        let mut entries = self.entries.lock().await;
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires_at > now);
+
+            // Evicting by scanning is fine for the cache sizes this is meant for
+            if entries.len() >= self.max_entries {
+                if let Some(least_recently_used) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+                {
+                    entries.remove(&least_recently_used);
+                }
+            }
+        }
+
+        entries.insert(
+            key,
+            CachedResponse {
+                status,
+                headers,
+                body,
+
+                expires_at: Instant::now() + self.ttl,
+                last_used: self.uses.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+    }
+}
+
+fn is_no_store(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| {
+            let directive = directive.trim();
+            directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("no-cache")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{ACCEPT_ENCODING, AUTHORIZATION};
+
+    use super::*;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn key(headers: &[(&str, &str)]) -> String {
+        ResponseCache::key(&request("GET", "/api/v10/users/@me", headers)).unwrap()
+    }
+
+    async fn cache(cache: &ResponseCache, key: &str, body: &'static str) {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("4"));
+
+        cache
+            .insert(
+                key.to_string(),
+                StatusCode::OK,
+                &headers,
+                Bytes::from_static(body.as_bytes()),
+            )
+            .await;
+    }
+
+    async fn cached_body(cache: &ResponseCache, key: &str) -> Option<Bytes> {
+        let response = cache.get(key).await?;
+        assert_eq!(response.headers()[CACHE_HEADER], "hit");
+        assert!(!response.headers().contains_key("x-ratelimit-remaining"));
+
+        Some(hyper::body::to_bytes(response.into_body()).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_hits_for_the_same_caller() {
+        let response_cache = ResponseCache::new(Duration::from_secs(60), 10);
+        let key = key(&[(AUTHORIZATION.as_str(), "Bot a")]);
+
+        assert!(response_cache.get(&key).await.is_none());
+        cache(&response_cache, &key, "{}").await;
+
+        assert_eq!(
+            cached_body(&response_cache, &key).await,
+            Some(Bytes::from_static(b"{}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn misses_for_other_tokens_and_encodings() {
+        let response_cache = ResponseCache::new(Duration::from_secs(60), 10);
+        cache(
+            &response_cache,
+            &key(&[(AUTHORIZATION.as_str(), "Bot a")]),
+            "{}",
+        )
+        .await;
+
+        for headers in [
+            &[(AUTHORIZATION.as_str(), "Bot b")][..],
+            &[][..],
+            &[
+                (AUTHORIZATION.as_str(), "Bot a"),
+                (ACCEPT_ENCODING.as_str(), "gzip"),
+            ][..],
+        ] {
+            assert!(response_cache.get(&key(headers)).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn misses_after_the_ttl() {
+        let response_cache = ResponseCache::new(Duration::ZERO, 10);
+        let key = key(&[]);
+
+        cache(&response_cache, &key, "{}").await;
+        assert!(response_cache.get(&key).await.is_none());
+    }
+
+    #[test]
+    fn only_cacheable_requests_have_keys() {
+        assert!(ResponseCache::key(&request("GET", "/api/v10/users/@me", &[])).is_some());
+
+        assert!(ResponseCache::key(&request("POST", "/api/v10/users/@me", &[])).is_none());
+        for directive in ["no-store", "no-cache", "max-age=0, No-Store"] {
+            assert!(ResponseCache::key(&request(
+                "GET",
+                "/api/v10/users/@me",
+                &[(CACHE_CONTROL.as_str(), directive)]
+            ))
+            .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_entry() {
+        let response_cache = ResponseCache::new(Duration::from_secs(60), 2);
+
+        cache(&response_cache, "a", "a").await;
+        cache(&response_cache, "b", "b").await;
+        // Using "a" leaves "b" as the least recently used
+        assert!(response_cache.get("a").await.is_some());
+
+        cache(&response_cache, "c", "c").await;
+
+        assert!(response_cache.get("a").await.is_some());
+        assert!(response_cache.get("b").await.is_none());
+        assert!(response_cache.get("c").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn never_caches_no_store_responses() {
+        let response_cache = ResponseCache::new(Duration::from_secs(60), 10);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        response_cache
+            .insert("a".to_string(), StatusCode::OK, &headers, Bytes::new())
+            .await;
+
+        assert!(response_cache.get("a").await.is_none());
+    }
+}
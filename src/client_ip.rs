@@ -0,0 +1,146 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use axum::extract::ConnectInfo;
+use http::{HeaderMap, Request};
+use hyper::Body;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+const FORWARDED_HEADER: &str = "forwarded";
+
+/// An IP network like `10.0.0.0/8`, a bare address is treated as a single host
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = match input.split_once('/') {
+            Some((network, prefix_len)) => (
+                network.parse::<IpAddr>().map_err(|_| ())?,
+                Some(prefix_len.parse::<u8>().map_err(|_| ())?),
+            ),
+            None => (input.parse::<IpAddr>().map_err(|_| ())?, None),
+        };
+
+        let max_prefix_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = prefix_len.unwrap_or(max_prefix_len);
+        if prefix_len > max_prefix_len {
+            return Err(());
+        }
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network) as u128,
+                u32::from(ip) as u128,
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(network), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+
+    let shift = bits - prefix_len;
+    network >> shift == ip >> shift
+}
+
+/// The address of the client that sent a request. Forwarding headers are only believed when the connection comes
+/// from a trusted proxy, otherwise any client could claim to be someone else.
+pub fn client_ip(req: &Request<Body>, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()?
+        .0
+        .ip()
+        .to_canonical();
+
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(ip));
+
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded = forwarded_for(req.headers());
+
+    // Each proxy appends the address it received the request from, so the client is the last address that isn't one of ours
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or_else(|| forwarded.first())
+        .copied()
+        .or(Some(peer))
+}
+
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    let forwarded_for = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| parse_forwarded_ip(ip.trim()))
+        .collect::<Vec<_>>();
+
+    if !forwarded_for.is_empty() {
+        return forwarded_for;
+    }
+
+    headers
+        .get_all(FORWARDED_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            name.eq_ignore_ascii_case("for")
+                .then(|| parse_forwarded_ip(value.trim().trim_matches('"')))?
+        })
+        .collect()
+}
+
+/// Accepts `1.2.3.4`, `1.2.3.4:5678`, `::1` and `[::1]:5678`
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+
+    value
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip().to_canonical())
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|value| value.strip_suffix(']'))
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+        })
+}
@@ -0,0 +1,185 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use ahash::AHashMap;
+use axum::response::Response;
+use http::{HeaderMap, HeaderValue, Request, StatusCode};
+use hyper::{body::Bytes, Body};
+use tokio::sync::broadcast;
+
+use crate::request::response_key;
+
+/// A response buffered by the request that fetched it, so identical requests waiting on it can share it
+pub struct SharedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl SharedResponse {
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+
+        response
+            .headers_mut()
+            .insert("x-proxy-served", HeaderValue::from_static("coalesced"));
+
+        response
+    }
+}
+
+pub type ResponseSender = broadcast::Sender<Arc<SharedResponse>>;
+
+/// Deduplicates identical GET requests that are in flight at the same time.
+pub struct RequestCoalescer {
+    in_flight: Mutex<AHashMap<String, (u64, ResponseSender)>>,
+    next_id: AtomicU64,
+}
+
+pub enum Coalesced<'a> {
+    /// No identical request is in flight, so this one should be sent and its response shared through the guard
+    Leader(LeaderGuard<'a>),
+    /// An identical request is already in flight, its response will be sent here
+    Follower(broadcast::Receiver<Arc<SharedResponse>>),
+}
+
+impl RequestCoalescer {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(AHashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the coalescing key for a request, or None if it can't be shared with others.
+    pub fn key(req: &Request<Body>) -> Option<String> {
+        response_key(req)
+    }
+
+    pub fn join(&self, key: String) -> Coalesced<'_> {
+        let mut in_flight = self.in_flight.lock().expect("Coalescer lock poisoned.");
+
+        if let Some((_, sender)) = in_flight.get(&key) {
+            return Coalesced::Follower(sender.subscribe());
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, _) = broadcast::channel(1);
+        in_flight.insert(key.clone(), (id, sender.clone()));
+
+        Coalesced::Leader(LeaderGuard {
+            coalescer: self,
+            key,
+            id,
+            sender,
+        })
+    }
+
+    fn remove(&self, key: &str, id: u64) {
+        let mut in_flight = self.in_flight.lock().expect("Coalescer lock poisoned.");
+
+        // A newer leader may have taken over the key already
+        if in_flight
+            .get(key)
+            .is_some_and(|(current, _)| *current == id)
+        {
+            in_flight.remove(key);
+        }
+    }
+}
+
+/// Dropping the guard without finishing lets waiting requests go ahead on their own.
+pub struct LeaderGuard<'a> {
+    coalescer: &'a RequestCoalescer,
+    key: String,
+    id: u64,
+    sender: ResponseSender,
+}
+
+impl LeaderGuard<'_> {
+    /// Stops new requests from joining, returning where to send the response if any already did.
+    pub fn close(self) -> Option<ResponseSender> {
+        // New requests must be stopped from subscribing first, so every subscriber is sure to get the response
+        self.coalescer.remove(&self.key, self.id);
+
+        if self.sender.receiver_count() == 0 {
+            return None;
+        }
+
+        Some(self.sender.clone())
+    }
+}
+
+impl Drop for LeaderGuard<'_> {
+    fn drop(&mut self) {
+        self.coalescer.remove(&self.key, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leader(coalesced: Coalesced<'_>) -> LeaderGuard<'_> {
+        match coalesced {
+            Coalesced::Leader(leader) => leader,
+            Coalesced::Follower(_) => panic!("Expected to lead the request"),
+        }
+    }
+
+    fn follower(coalesced: Coalesced<'_>) -> broadcast::Receiver<Arc<SharedResponse>> {
+        match coalesced {
+            Coalesced::Leader(_) => panic!("Expected to follow the request"),
+            Coalesced::Follower(receiver) => receiver,
+        }
+    }
+
+    #[test]
+    fn leaders_without_followers_have_nothing_to_share() {
+        let coalescer = RequestCoalescer::new();
+
+        let guard = leader(coalescer.join("key".to_string()));
+        assert!(guard.close().is_none());
+
+        // The key is free again for the next request
+        leader(coalescer.join("key".to_string()));
+    }
+
+    #[test]
+    fn followers_get_the_leaders_response() {
+        let coalescer = RequestCoalescer::new();
+
+        let guard = leader(coalescer.join("key".to_string()));
+        let mut receiver = follower(coalescer.join("key".to_string()));
+
+        let sender = guard.close().expect("A follower is waiting");
+        let shared = Arc::new(SharedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"{}"),
+        });
+        assert_eq!(sender.send(shared).ok(), Some(1));
+
+        let response = receiver.try_recv().unwrap().to_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-proxy-served"], "coalesced");
+
+        // Requests after the response was shared lead their own
+        leader(coalescer.join("key".to_string()));
+    }
+
+    #[test]
+    fn followers_go_ahead_when_the_leader_gives_up() {
+        let coalescer = RequestCoalescer::new();
+
+        let guard = leader(coalescer.join("key".to_string()));
+        let mut receiver = follower(coalescer.join("key".to_string()));
+
+        drop(guard);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use http::{
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+    HeaderMap, HeaderValue, Response,
+};
+use hyper::Body;
+use miniz_oxide::{deflate::compress_to_vec, inflate::decompress_to_vec};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+
+/// Returns true if `Accept-Encoding` allows a gzip encoded response.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';');
+            let coding = params.next().unwrap_or("").trim();
+
+            // `q=0` explicitly refuses an encoding
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .map(|q| q <= 0.0)
+                    .unwrap_or(false)
+            });
+
+            !refused && (coding.eq_ignore_ascii_case("gzip") || coding == "*")
+        })
+}
+
+fn is_gzip_encoded(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().eq_ignore_ascii_case("gzip"))
+        .unwrap_or(false)
+}
+
+/// Brings Discord's response into an encoding the caller accepts, decompressing gzip for callers that didn't ask
+/// for it and compressing plain responses for those that did if `compress` is set. Other encodings are left alone.
+pub async fn encode_for_caller(
+    response: Response<Body>,
+    caller_accepts_gzip: bool,
+    compress: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    let gzip_encoded = is_gzip_encoded(response.headers());
+
+    let should_decompress = gzip_encoded && !caller_accepts_gzip;
+    let should_compress =
+        compress && caller_accepts_gzip && !response.headers().contains_key(CONTENT_ENCODING);
+
+    if !should_decompress && !should_compress {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+
+    let encoded = if should_decompress {
+        match gunzip(&body) {
+            Some(decoded) => {
+                parts.headers.remove(CONTENT_ENCODING);
+                decoded
+            }
+            None => {
+                tracing::warn!(
+                    "Failed to decompress gzip response from Discord, passing it on as is."
+                );
+                return Ok(Response::from_parts(parts, Body::from(body)));
+            }
+        }
+    } else {
+        if body.is_empty() {
+            return Ok(Response::from_parts(parts, Body::from(body)));
+        }
+
+        parts
+            .headers
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+        gzip(&body)
+    };
+
+    parts.headers.insert(CONTENT_LENGTH, encoded.len().into());
+
+    Ok(Response::from_parts(parts, Body::from(encoded)))
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let deflated = compress_to_vec(data, 6);
+
+    let mut encoded = Vec::with_capacity(deflated.len() + 18);
+    // No mtime, flags or file name, the OS is unknown
+    encoded.extend_from_slice(&[GZIP_MAGIC[0], GZIP_MAGIC[1], 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    encoded.extend_from_slice(&deflated);
+    encoded.extend_from_slice(&crc32(data).to_le_bytes());
+    encoded.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    encoded
+}
+
+/// Decodes a single gzip member, returns None if it is malformed or fails its checksum.
+pub fn gunzip(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 18 || data[..2] != GZIP_MAGIC || data[2] != 8 {
+        return None;
+    }
+
+    let flags = data[3];
+    let mut offset = 10;
+
+    if flags & FLAG_EXTRA != 0 {
+        let extra_len = u16::from_le_bytes([*data.get(offset)?, *data.get(offset + 1)?]) as usize;
+        offset += 2 + extra_len;
+    }
+
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            offset += data.get(offset..)?.iter().position(|&byte| byte == 0)? + 1;
+        }
+    }
+
+    if flags & FLAG_HCRC != 0 {
+        offset += 2;
+    }
+
+    let trailer = data.len().checked_sub(8)?;
+    let decoded = decompress_to_vec(data.get(offset..trailer)?).ok()?;
+
+    let expected_crc = u32::from_le_bytes(data[trailer..trailer + 4].try_into().ok()?);
+    let expected_len = u32::from_le_bytes(data[trailer + 4..].try_into().ok()?);
+
+    if crc32(&decoded) != expected_crc || decoded.len() as u32 != expected_len {
+        return None;
+    }
+
+    Some(decoded)
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `gzip.compress(b"hello discord", mtime=0)` from Python
+    const PYTHON_GZIP: [u8; 33] = [
+        31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 203, 72, 205, 201, 201, 87, 72, 201, 44, 78, 206, 47, 74,
+        1, 0, 53, 94, 114, 211, 13, 0, 0, 0,
+    ];
+
+    /// `{"ok":true}` compressed by Python's `GzipFile` with a file name, which sets `FNAME`
+    const PYTHON_GZIP_WITH_NAME: [u8; 42] = [
+        31, 139, 8, 8, 0, 0, 0, 0, 2, 255, 114, 101, 112, 108, 121, 46, 106, 115, 111, 110, 0, 171,
+        86, 202, 207, 86, 178, 42, 41, 42, 77, 173, 5, 0, 144, 95, 212, 167, 11, 0, 0, 0,
+    ];
+
+    fn accept_encoding(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn crc32_matches_known_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        for data in [
+            &b""[..],
+            b"{\"id\":\"80351110224678912\"}",
+            &[0u8; 100_000][..],
+        ] {
+            assert_eq!(gunzip(&gzip(data)).as_deref(), Some(data));
+        }
+    }
+
+    #[test]
+    fn gunzips_other_encoders_output() {
+        assert_eq!(gunzip(&PYTHON_GZIP).as_deref(), Some(&b"hello discord"[..]));
+        assert_eq!(
+            gunzip(&PYTHON_GZIP_WITH_NAME).as_deref(),
+            Some(&b"{\"ok\":true}"[..])
+        );
+    }
+
+    #[test]
+    fn gunzip_rejects_corrupt_members() {
+        let mut bad_crc = PYTHON_GZIP;
+        bad_crc[25] ^= 0xff;
+        assert_eq!(gunzip(&bad_crc), None);
+
+        let mut bad_len = PYTHON_GZIP;
+        bad_len[29] += 1;
+        assert_eq!(gunzip(&bad_len), None);
+
+        assert_eq!(gunzip(&PYTHON_GZIP[..20]), None);
+        assert_eq!(gunzip(b"not gzip at all, not even close"), None);
+    }
+
+    #[test]
+    fn accepts_gzip_unless_refused() {
+        assert!(accepts_gzip(&accept_encoding("gzip")));
+        assert!(accepts_gzip(&accept_encoding("br, GZIP;q=0.5")));
+        assert!(accepts_gzip(&accept_encoding("*")));
+
+        assert!(!accepts_gzip(&accept_encoding("gzip;q=0")));
+        assert!(!accepts_gzip(&accept_encoding("gzip; q=0.0, br")));
+        assert!(!accepts_gzip(&accept_encoding("identity")));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+}
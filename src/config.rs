@@ -1,23 +1,43 @@
+use ahash::AHashMap;
+use http::{HeaderName, Uri};
+
+use crate::client_ip::Cidr;
 use std::{
     env::{self, VarError},
-    ffi::OsString,
     fmt::Display,
+    fs,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     str::FromStr,
     sync::Arc,
     time::Duration,
 };
 
+#[derive(Clone)]
 pub struct RedisEnvConfig {
     pub host: String,
     pub port: u16,
 
+    /// A replica used to reject requests to exhausted route buckets without a round trip to the master
+    pub replica_host: Option<String>,
+    pub replica_port: u16,
+
+    /// Additional endpoints ratelimit keys are spread across, each with its weight relative to the primary's 1
+    pub shards: Vec<(String, u16, u32)>,
+
     pub username: Option<String>,
     pub password: Option<String>,
 
     pub pool_size: usize,
+    pub max_pending_lock_waiters: usize,
+    pub command_timeout_ms: u64,
+    pub connect_timeout_ms: u64,
+    /// How long to wait between attempts to subscribe to lock releases, and how many to make before giving up, 0 never does
+    pub pubsub_retry_interval_ms: u64,
+    pub pubsub_max_attempts: u32,
+    /// How often to look for waited on locks that have expired, 0 disables it
+    pub lock_sweep_interval_ms: u64,
 
     pub sentinel: bool,
-    pub clustered: bool,
 
     pub sentinel_auth: bool,
     pub sentinel_master: String,
@@ -26,6 +46,45 @@ pub struct RedisEnvConfig {
 pub struct WebserverEnvConfig {
     pub host: String,
     pub port: u16,
+
+    pub uds_path: Option<String>,
+    pub enable_connect_proxy: bool,
+
+    pub dual_stack: bool,
+
+    /// PEM certificate chain and private key, the API port serves HTTPS when both are set
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+
+    pub metrics_port: Option<u16>,
+
+    /// 0 uses one worker thread per core
+    pub worker_threads: usize,
+    pub max_blocking_threads: usize,
+}
+
+impl WebserverEnvConfig {
+    pub fn socket_addr(&self, port: u16) -> Result<SocketAddr, String> {
+        if self.dual_stack {
+            return Ok(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port));
+        }
+
+        // Allow IPv6 literals to be written with or without brackets
+        let host = self
+            .host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(&self.host);
+
+        host.parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, port))
+            .map_err(|_| {
+                format!(
+                    "Invalid HOST {:?}, expected an IPv4 or IPv6 address.",
+                    self.host
+                )
+            })
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -55,35 +114,397 @@ impl Display for NewBucketStrategy {
     }
 }
 
+/// How requests sent with `Expect: 100-continue` are handled.
+#[derive(Clone, PartialEq)]
+pub enum ExpectContinueStrategy {
+    /// Answer with 100 Continue once the request has passed its ratelimit checks and its body is sent to Discord
+    Local,
+    /// Answer with 417 Expectation Failed straight away, so the caller sends the request again without the header
+    Reject,
+}
+
+impl FromStr for ExpectContinueStrategy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<ExpectContinueStrategy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "local" => Ok(ExpectContinueStrategy::Local),
+            "reject" => Ok(ExpectContinueStrategy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for ExpectContinueStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExpectContinueStrategy::Local => write!(f, "ExpectContinueStrategy::Local"),
+            ExpectContinueStrategy::Reject => write!(f, "ExpectContinueStrategy::Reject"),
+        }
+    }
+}
+
+/// What a request that has just taken a bot's global lock does when the route bucket it needs is locked by another.
+#[derive(Clone, PartialEq)]
+pub enum RouteLockWaitStrategy {
+    /// Fetch the global ratelimit while queueing on the route lock, giving up if that queue is full
+    Wait,
+    /// Fetch the global ratelimit, then check the route bucket again straight away instead of queueing
+    Optimistic,
+}
+
+impl FromStr for RouteLockWaitStrategy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<RouteLockWaitStrategy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "wait" => Ok(RouteLockWaitStrategy::Wait),
+            "optimistic" => Ok(RouteLockWaitStrategy::Optimistic),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for RouteLockWaitStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteLockWaitStrategy::Wait => write!(f, "RouteLockWaitStrategy::Wait"),
+            RouteLockWaitStrategy::Optimistic => write!(f, "RouteLockWaitStrategy::Optimistic"),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq)]
+pub enum MetricsGlobalIdMode {
+    Full,
+    Hashed,
+    None,
+}
+
+#[cfg(feature = "metrics")]
+impl FromStr for MetricsGlobalIdMode {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<MetricsGlobalIdMode, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "full" => Ok(MetricsGlobalIdMode::Full),
+            "hashed" => Ok(MetricsGlobalIdMode::Hashed),
+            "none" => Ok(MetricsGlobalIdMode::None),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Display for MetricsGlobalIdMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricsGlobalIdMode::Full => write!(f, "MetricsGlobalIdMode::Full"),
+            MetricsGlobalIdMode::Hashed => write!(f, "MetricsGlobalIdMode::Hashed"),
+            MetricsGlobalIdMode::None => write!(f, "MetricsGlobalIdMode::None"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ProxyEnvConfig {
     pub global_rl_strategy: NewBucketStrategy,
     pub route_rl_strategy: NewBucketStrategy,
+    pub route_lock_wait_strategy: RouteLockWaitStrategy,
+    pub expect_continue: ExpectContinueStrategy,
+    pub loose_route_burst: u16,
+    /// Route buckets are treated as exhausted this many requests before Discord's limit
+    pub ratelimit_safety_margin: u16,
 
     pub disable_global_rl: bool,
+    pub disable_route_rl: bool,
+    pub allow_ratelimit_bypass: bool,
+    pub allow_bucket_strategy_override: bool,
+    /// Peers whose `X-Forwarded-For` and `Forwarded` headers are believed when working out the client's address
+    pub trusted_proxies: Vec<Cidr>,
+    /// Gives each unauthenticated client its own buckets, keyed by its address, instead of sharing them all
+    pub isolate_unauthenticated_clients: bool,
+    /// Answers requests for top level resources Discord doesn't have with a 404 instead of forwarding them
+    pub reject_unknown_routes: bool,
+
+    pub default_global_rl: u16,
+    pub large_sharding_minimum: u16,
+    pub large_sharding_shard_rl: u16,
+
     pub lock_timeout: Duration,
+    pub lock_ttl_secs: u64,
+    pub request_deadline: Option<Duration>,
+    /// Sent as `Retry-After` while the proxy is disabled, 0 leaves it out
+    pub disabled_retry_after_secs: u64,
+    /// Requests taking longer than this end to end are logged with a breakdown of where the time went
+    pub slow_request_threshold: Option<Duration>,
 
     pub bucket_ttl_ms: u64,
+    pub bucket_ttl_jitter_percent: u8,
+    pub interaction_bucket_ttl_ms: u64,
+    /// Caps how long a route's limit is trusted before it is probed again, 0 leaves it at `bucket_ttl_ms`
+    pub route_bucket_max_age_ms: u64,
+    /// How many reset windows a route's limit outlives its last use, 0 keeps it for the whole bucket TTL
+    pub adaptive_bucket_ttl_windows: u64,
+
+    pub request_costs: Vec<(String, u16)>,
+    /// Routes that are slow on purpose, matched against the end of the route bucket like `request_costs`
+    pub long_lived_routes: Vec<String>,
+    /// Maps bot ids to the name of the global ratelimit group they share
+    pub global_rl_groups: AHashMap<String, String>,
+    pub response_header_strip: Vec<HeaderName>,
+
+    pub response_cache_ttl: Option<Duration>,
+    pub response_cache_max_entries: usize,
+    pub coalesce_requests: bool,
+
+    /// Always ask Discord for gzip, decompressing for callers that don't accept it
+    pub upstream_gzip: bool,
+    /// Compress uncompressed responses for callers that accept gzip
+    pub gzip_responses: bool,
+
+    pub idempotency_ttl_ms: Option<u64>,
+
+    pub use_discord_buckets: bool,
 
     pub disable_http2: bool,
-    pub clustered_redis: bool, // TODO: Clustered redis only really needs a small number of changes to the client as all keys are already namespaced, but it's not finished yet
+    pub upstream_max_idle_per_host: usize,
+    pub upstream_max_in_flight: usize,
+    /// Caps how many requests may probe new buckets for their ratelimits at once, 0 leaves it unlimited
+    pub max_concurrent_probes: usize,
+
+    /// Removed from the start of request paths, for serving the proxy under a sub-path such as `/discord`
+    pub path_prefix_strip: Option<String>,
+
+    pub discord_api_url: String,
+    pub discord_api_host: String,
+    /// The API version used for the proxy's own requests and for requests that don't specify one, e.g. `v10`
+    pub discord_api_version: String,
+    pub discord_tls_ca: Option<String>,
+    /// Hosts resolved to fixed addresses instead of through DNS
+    pub discord_resolve_overrides: AHashMap<String, Vec<IpAddr>>,
+    pub discord_allow_http: bool,
+    /// An HTTP proxy to tunnel connections to Discord through with CONNECT
+    pub discord_egress_proxy: Option<Uri>,
+    /// Hosts connected to directly even if an egress proxy is set
+    pub no_proxy: Vec<String>,
+
+    pub shadow_mode: bool,
+    pub access_log: bool,
+    /// The salt bot ids are hashed with before they're logged, if they're logged hashed
+    pub log_bot_id_salt: Option<String>,
+    pub ratelimit_audit_log: bool,
+    pub expose_proxy_ratelimit_headers: bool,
+    pub debug_auth_token: Option<String>,
+    pub admin_auth_token: Option<String>,
+    pub bucket_preload_file: Option<String>,
+    /// Tokens to check against Discord on startup, one per line
+    pub token_validation_file: Option<String>,
 
     #[cfg(feature = "metrics")]
     pub metrics_ttl: u64,
+    #[cfg(feature = "metrics")]
+    pub metrics_auth_token: Option<String>,
+    #[cfg(feature = "metrics")]
+    pub metrics_global_id_mode: MetricsGlobalIdMode,
 }
 
-pub enum EnvError {
-    NotPresent(String),
-    InvalidUnicode(String, OsString),
+impl ProxyEnvConfig {
+    /// How many requests a request to this route counts as against the proxy's ratelimits.
+    pub fn request_cost(&self, route_display_bucket: &str) -> u16 {
+        self.request_costs
+            .iter()
+            .find(|(route, _)| route_display_bucket.ends_with(route.as_str()))
+            .map(|(_, cost)| *cost)
+            .unwrap_or(1)
+    }
+
+    /// Whether requests to this route are exempt from the request deadline and slow request logging.
+    pub fn is_long_lived_route(&self, route_display_bucket: &str) -> bool {
+        self.long_lived_routes
+            .iter()
+            .any(|route| route_display_bucket.ends_with(route.as_str()))
+    }
+}
+
+fn parse_trusted_proxies(value: &str) -> Vec<Cidr> {
+    value
+        .split(',')
+        .map(|cidr| cidr.trim())
+        .filter(|cidr| !cidr.is_empty())
+        .filter_map(|cidr| match Cidr::from_str(cidr) {
+            Ok(cidr) => Some(cidr),
+            Err(_) => {
+                eprintln!("Ignoring invalid trusted proxy {:?}.", cidr);
+                None
+            }
+        })
+        .collect()
 }
 
-impl From<(String, VarError)> for EnvError {
-    fn from((key, err): (String, VarError)) -> Self {
-        match err {
-            VarError::NotPresent => EnvError::NotPresent(key),
-            VarError::NotUnicode(value) => EnvError::InvalidUnicode(key, value),
+/// Parses `host:ip,host:ip`, repeating a host gives it multiple addresses
+fn parse_resolve_overrides(value: &str) -> AHashMap<String, Vec<IpAddr>> {
+    let mut overrides: AHashMap<String, Vec<IpAddr>> = AHashMap::new();
+
+    for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+        // Hosts never contain a colon, so anything after the first one is the address, which may be IPv6
+        let parsed = entry.split_once(':').and_then(|(host, ip)| {
+            let ip = ip.trim().trim_start_matches('[').trim_end_matches(']');
+            Some((host.trim().to_ascii_lowercase(), ip.parse::<IpAddr>().ok()?))
+        });
+
+        match parsed {
+            Some((host, ip)) if !host.is_empty() => overrides.entry(host).or_default().push(ip),
+            _ => eprintln!("Ignoring invalid resolve override {:?}.", entry),
         }
     }
+
+    overrides
+}
+
+/// Only plain HTTP proxies are supported, as the tunnelled connection to Discord is encrypted anyway
+fn parse_egress_proxy(value: &str) -> Option<Uri> {
+    let uri = match value.trim().parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => {
+            eprintln!("Ignoring invalid egress proxy {:?}.", value);
+            return None;
+        }
+    };
+
+    match (uri.scheme_str(), uri.host()) {
+        (Some("http"), Some(_)) => Some(uri),
+        _ => {
+            eprintln!(
+                "Ignoring egress proxy {:?}, only http:// proxies are supported.",
+                value
+            );
+            None
+        }
+    }
+}
+
+/// Parses a comma separated `NO_PROXY` list of hosts, a leading dot is optional and matches subdomains all the same
+fn parse_no_proxy(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|host| host.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+/// Parses `host:port=weight,host:port`, the weight defaults to 1
+fn parse_redis_shards(value: &str) -> Vec<(String, u16, u32)> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let (endpoint, weight) = match entry.split_once('=') {
+                Some((endpoint, weight)) => (endpoint, weight.trim().parse().ok()),
+                None => (entry, Some(1)),
+            };
+
+            let parsed = endpoint
+                .trim()
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?, weight?)));
+
+            match parsed {
+                Some((host, port, weight)) if !host.is_empty() && weight > 0 => {
+                    Some((host, port, weight))
+                }
+                _ => {
+                    eprintln!("Ignoring invalid Redis shard {:?}.", entry);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+const DEFAULT_API_VERSION: &str = "v10";
+
+/// Accepts both `10` and `v10`
+fn parse_api_version(value: &str) -> String {
+    let version = value.trim().trim_start_matches('v');
+
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+        eprintln!(
+            "Invalid value for environment variable DISCORD_API_VERSION={:?}. Using default value of {}",
+            value, DEFAULT_API_VERSION
+        );
+
+        return DEFAULT_API_VERSION.to_string();
+    }
+
+    format!("v{}", version)
+}
+
+/// Parses `group=id,id;group=id` into a map from each bot id to its group
+fn parse_global_rl_groups(value: &str) -> AHashMap<String, String> {
+    let mut groups = AHashMap::new();
+
+    for entry in value.split(';').filter(|entry| !entry.trim().is_empty()) {
+        let (group, ids) = match entry.split_once('=') {
+            Some((group, ids)) if !group.trim().is_empty() => (group.trim(), ids),
+            _ => {
+                eprintln!("Ignoring invalid global ratelimit group {:?}.", entry);
+                continue;
+            }
+        };
+
+        for id in ids
+            .split(',')
+            .map(|id| id.trim())
+            .filter(|id| !id.is_empty())
+        {
+            if let Some(previous) = groups.insert(id.to_string(), group.to_string()) {
+                eprintln!(
+                    "Bot {} is in more than one global ratelimit group, using {} instead of {}.",
+                    id, group, previous
+                );
+            }
+        }
+    }
+
+    groups
+}
+
+fn parse_request_costs(value: &str) -> Vec<(String, u16)> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = entry
+                .rsplit_once('=')
+                .and_then(|(route, cost)| {
+                    Some((route.trim().to_string(), cost.trim().parse().ok()?))
+                })
+                .filter(|(_, cost)| *cost > 0);
+
+            if parsed.is_none() {
+                eprintln!("Ignoring invalid request cost entry {:?}.", entry);
+            }
+
+            parsed
+        })
+        .collect()
+}
+
+fn parse_header_names(value: &str) -> Vec<HeaderName> {
+    value
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| match HeaderName::from_str(name) {
+            Ok(header_name) => Some(header_name),
+            Err(_) => {
+                eprintln!("Ignoring invalid header name {:?}.", name);
+                None
+            }
+        })
+        .collect()
 }
 
 fn get_and_parse_envvar<T: FromStr + std::fmt::Display>(key: &str, default: T) -> T {
@@ -123,6 +544,19 @@ fn get_optional_envvar(key: &str) -> Option<String> {
     }
 }
 
+/// Reads a secret from the file named by `{key}_FILE` if it is set (e.g. Docker secrets), otherwise from `{key}` itself.
+fn get_secret_envvar(key: &str) -> Option<String> {
+    let file_key = format!("{}_FILE", key);
+
+    match get_optional_envvar(&file_key) {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(value) => Some(value.trim_end_matches(['\r', '\n']).to_owned()),
+            Err(err) => panic!("Failed to read {} from {}: {}", key, path, err),
+        },
+        None => get_optional_envvar(key),
+    }
+}
+
 fn get_envvar_with_default(key: &str, default: String) -> String {
     match env::var(key) {
         Ok(value) => value,
@@ -147,10 +581,9 @@ pub struct AppEnvConfig {
 impl AppEnvConfig {
     pub fn from_env() -> Self {
         let sentinel_redis = get_and_parse_envvar::<bool>("REDIS_SENTINEL", false);
-        let clustered_redis = get_and_parse_envvar::<bool>("REDIS_CLUSTER", false);
 
-        if sentinel_redis && clustered_redis {
-            panic!("Cannot use both Redis Sentinel and Redis Cluster at the same time.");
+        if get_and_parse_envvar::<bool>("REDIS_CLUSTER", false) {
+            eprintln!("REDIS_CLUSTER is set, but Redis Cluster isn't supported. Ignoring it, use REDIS_SHARDS to spread keys across several Redis instead.");
         }
 
         let sentinel_auth = get_and_parse_envvar::<bool>("REDIS_SENTINEL_AUTH", false);
@@ -162,12 +595,34 @@ impl AppEnvConfig {
         let redis_host = get_envvar_with_default("REDIS_HOST", "127.0.0.1".to_string());
         let redis_port = get_and_parse_envvar::<u16>("REDIS_PORT", default_redis_port);
 
-        let redis_user = get_optional_envvar("REDIS_USER");
-        let redis_pass = get_optional_envvar("REDIS_PASS");
+        let redis_replica_host = get_optional_envvar("REDIS_REPLICA_HOST");
+        let redis_replica_port = get_and_parse_envvar::<u16>("REDIS_REPLICA_PORT", 6379);
+        let redis_shards = get_optional_envvar("REDIS_SHARDS")
+            .map(|shards| parse_redis_shards(&shards))
+            .unwrap_or_default();
+
+        let redis_user = get_secret_envvar("REDIS_USER");
+        let redis_pass = get_secret_envvar("REDIS_PASS");
 
         let redis_pool_size = get_and_parse_envvar::<usize>("REDIS_POOL_SIZE", 128);
+        let max_pending_lock_waiters = get_and_parse_envvar::<usize>("MAX_PENDING_LOCK_WAITERS", 0);
+        let redis_command_timeout_ms = get_and_parse_envvar::<u64>("REDIS_COMMAND_TIMEOUT_MS", 0);
+        let redis_connect_timeout_ms =
+            get_and_parse_envvar::<u64>("REDIS_CONNECT_TIMEOUT_MS", 10000);
+        let redis_pubsub_retry_interval_ms =
+            get_and_parse_envvar::<u64>("REDIS_PUBSUB_RETRY_INTERVAL_MS", 5000).max(1);
+        let redis_pubsub_max_attempts = get_and_parse_envvar::<u32>("REDIS_PUBSUB_MAX_ATTEMPTS", 0);
+        let lock_sweep_interval_ms = get_and_parse_envvar::<u64>("LOCK_SWEEP_INTERVAL_MS", 0);
 
         let lock_wait_timeout = get_and_parse_envvar::<u64>("LOCK_WAIT_TIMEOUT", 500);
+        let request_deadline = get_and_parse_envvar::<u64>("REQUEST_DEADLINE_MS", 0);
+        let disabled_retry_after_secs = get_and_parse_envvar::<u64>("DISABLED_RETRY_AFTER_SECS", 0);
+        let slow_request_log_ms = get_and_parse_envvar::<u64>("SLOW_REQUEST_LOG_MS", 0);
+        let lock_ttl_secs = get_and_parse_envvar::<u64>("BUCKET_LOCK_TTL_SECONDS", 5).max(1);
+
+        if request_deadline >= lock_ttl_secs * 1000 {
+            eprintln!("BUCKET_LOCK_TTL_SECONDS should be comfortably larger than REQUEST_DEADLINE_MS, or bucket locks may expire while a request is still in flight.");
+        }
 
         let global_ratelimit_strategy = get_and_parse_envvar::<NewBucketStrategy>(
             "GLOBAL_RATELIMIT_STRATEGY",
@@ -177,55 +632,355 @@ impl AppEnvConfig {
             "ROUTE_RATELIMIT_STRATEGY",
             NewBucketStrategy::Strict,
         );
+        let route_lock_wait_strategy = get_and_parse_envvar::<RouteLockWaitStrategy>(
+            "ROUTE_LOCK_WAIT_STRATEGY",
+            RouteLockWaitStrategy::Wait,
+        );
+        let expect_continue = get_and_parse_envvar::<ExpectContinueStrategy>(
+            "EXPECT_CONTINUE",
+            ExpectContinueStrategy::Local,
+        );
+        let loose_route_burst = get_and_parse_envvar::<u16>("LOOSE_ROUTE_BURST", 5).max(1);
+        let ratelimit_safety_margin = get_and_parse_envvar::<u16>("RATELIMIT_SAFETY_MARGIN", 0);
 
         let disable_global_rl = get_and_parse_envvar::<bool>("DISABLE_GLOBAL_RATELIMIT", false);
+        let disable_route_rl = get_and_parse_envvar::<bool>("DISABLE_ROUTE_RATELIMIT", false);
+
+        let default_global_rl = get_and_parse_envvar::<u16>("DEFAULT_GLOBAL_RL", 50);
+        let large_sharding_minimum = get_and_parse_envvar::<u16>("LARGE_SHARDING_MINIMUM", 500);
+        let large_sharding_shard_rl = get_and_parse_envvar::<u16>("LARGE_SHARDING_SHARD_RL", 25);
+        let allow_ratelimit_bypass = get_and_parse_envvar::<bool>("ALLOW_RATELIMIT_BYPASS", false);
+        let allow_bucket_strategy_override =
+            get_and_parse_envvar::<bool>("ALLOW_BUCKET_STRATEGY_OVERRIDE", false);
+        let trusted_proxies =
+            parse_trusted_proxies(&get_envvar_with_default("TRUSTED_PROXIES", String::new()));
+        let isolate_unauthenticated_clients =
+            get_and_parse_envvar::<bool>("ISOLATE_UNAUTHENTICATED_CLIENTS", false);
+        let reject_unknown_routes = get_and_parse_envvar::<bool>("REJECT_UNKNOWN_ROUTES", false);
 
         let bucket_ttl_ms = get_and_parse_envvar::<u64>("BUCKET_TTL", 86400000);
+        let bucket_ttl_jitter_percent =
+            get_and_parse_envvar::<u8>("BUCKET_TTL_JITTER_PERCENT", 0).min(100);
+        let interaction_bucket_ttl_ms =
+            get_and_parse_envvar::<u64>("INTERACTION_BUCKET_TTL_MS", 15 * 60 * 1000);
+        let route_bucket_max_age_ms = get_and_parse_envvar::<u64>("ROUTE_BUCKET_MAX_AGE", 0);
+        let adaptive_bucket_ttl_windows =
+            get_and_parse_envvar::<u64>("ADAPTIVE_BUCKET_TTL_WINDOWS", 0);
+
+        let request_costs =
+            parse_request_costs(&get_envvar_with_default("REQUEST_COSTS", String::new()));
+        let long_lived_routes = get_envvar_with_default("LONG_LIVED_ROUTES", String::new())
+            .split(',')
+            .map(|route| route.trim().to_string())
+            .filter(|route| !route.is_empty())
+            .collect();
+        let global_rl_groups =
+            parse_global_rl_groups(&get_envvar_with_default("GLOBAL_RL_GROUPS", String::new()));
+        let idempotency_ttl_ms = get_and_parse_envvar::<u64>("IDEMPOTENCY_TTL_MS", 0);
+        let response_cache_ttl = get_and_parse_envvar::<u64>("RESPONSE_CACHE_TTL_MS", 0);
+        let response_cache_max_entries =
+            get_and_parse_envvar::<usize>("RESPONSE_CACHE_MAX_ENTRIES", 1000).max(1);
+        let coalesce_requests = get_and_parse_envvar::<bool>("COALESCE_REQUESTS", false);
+        let upstream_gzip = get_and_parse_envvar::<bool>("UPSTREAM_GZIP", false);
+        let gzip_responses = get_and_parse_envvar::<bool>("GZIP_RESPONSES", false);
+        let response_header_strip = parse_header_names(&get_envvar_with_default(
+            "RESPONSE_HEADER_STRIP",
+            String::new(),
+        ));
+
+        let use_discord_buckets = get_and_parse_envvar::<bool>("USE_DISCORD_BUCKETS", false);
 
         let disable_http2 = get_and_parse_envvar::<bool>("DISABLE_HTTP2", true);
+        let upstream_max_idle_per_host =
+            get_and_parse_envvar::<usize>("UPSTREAM_MAX_IDLE_PER_HOST", usize::MAX);
+        let upstream_max_in_flight = get_and_parse_envvar::<usize>("UPSTREAM_MAX_IN_FLIGHT", 0);
+        let max_concurrent_probes = get_and_parse_envvar::<usize>("MAX_CONCURRENT_PROBES", 0);
+
+        let discord_api_url =
+            get_envvar_with_default("DISCORD_API_URL", "https://discord.com".to_string())
+                .trim_end_matches('/')
+                .to_string();
+        let discord_api_host = match discord_api_url.parse::<Uri>() {
+            Ok(uri) => match uri.authority() {
+                Some(authority) => authority.to_string(),
+                None => panic!("DISCORD_API_URL must include a host."),
+            },
+            Err(_) => panic!("Invalid DISCORD_API_URL: {}", discord_api_url),
+        };
+
+        let path_prefix_strip = get_optional_envvar("PATH_PREFIX_STRIP")
+            .map(|prefix| format!("/{}", prefix.trim().trim_matches('/')))
+            .filter(|prefix| prefix != "/");
+
+        let discord_api_version = parse_api_version(&get_envvar_with_default(
+            "DISCORD_API_VERSION",
+            DEFAULT_API_VERSION.to_string(),
+        ));
+
+        let discord_tls_ca = get_optional_envvar("DISCORD_TLS_CA");
+        let discord_resolve_overrides = parse_resolve_overrides(&get_envvar_with_default(
+            "DISCORD_RESOLVE_OVERRIDE",
+            String::new(),
+        ));
+        let discord_allow_http = get_and_parse_envvar::<bool>("DISCORD_ALLOW_HTTP", false);
+        let discord_egress_proxy = get_optional_envvar("DISCORD_EGRESS_PROXY")
+            .or_else(|| get_optional_envvar("HTTPS_PROXY"))
+            .or_else(|| get_optional_envvar("https_proxy"))
+            .filter(|value| !value.trim().is_empty())
+            .and_then(|value| parse_egress_proxy(&value));
+        let no_proxy = parse_no_proxy(
+            &get_optional_envvar("NO_PROXY")
+                .or_else(|| get_optional_envvar("no_proxy"))
+                .unwrap_or_default(),
+        );
+
+        let shadow_mode = get_and_parse_envvar::<bool>("SHADOW_MODE", false);
+        let access_log = get_and_parse_envvar::<bool>("ACCESS_LOG", false);
+        let log_bot_id_salt = get_and_parse_envvar::<bool>("LOG_HASH_BOT_IDS", false)
+            .then(|| get_secret_envvar("LOG_HASH_SALT").unwrap_or_default());
+        let ratelimit_audit_log = get_and_parse_envvar::<bool>("RATELIMIT_AUDIT_LOG", false);
+        let debug_auth_token = get_optional_envvar("DEBUG_AUTH_TOKEN");
+        let admin_auth_token = get_secret_envvar("ADMIN_AUTH_TOKEN");
+        let bucket_preload_file = get_optional_envvar("BUCKET_PRELOAD_FILE");
+        let token_validation_file = get_optional_envvar("TOKEN_VALIDATION_FILE");
+        let expose_proxy_ratelimit_headers =
+            get_and_parse_envvar::<bool>("EXPOSE_PROXY_RATELIMIT_HEADERS", false);
 
         let host = get_envvar_with_default("HOST", "127.0.0.1".to_string());
         let port = get_and_parse_envvar::<u16>("PORT", 8080);
+        let uds_path = get_optional_envvar("LISTEN_UDS");
+        let enable_connect_proxy = get_and_parse_envvar::<bool>("ENABLE_CONNECT_PROXY", false);
+        let dual_stack = get_and_parse_envvar::<bool>("LISTEN_DUAL_STACK", false);
+        let tls_cert = get_optional_envvar("LISTEN_TLS_CERT");
+        let tls_key = get_optional_envvar("LISTEN_TLS_KEY");
+
+        if tls_cert.is_some() != tls_key.is_some() {
+            eprintln!(
+                "LISTEN_TLS_CERT and LISTEN_TLS_KEY must be set together, serving plain HTTP."
+            );
+        }
+
+        let worker_threads = get_and_parse_envvar::<usize>("TOKIO_WORKER_THREADS", 0);
+        let max_blocking_threads =
+            get_and_parse_envvar::<usize>("TOKIO_MAX_BLOCKING_THREADS", 512).max(1);
+        let metrics_port = get_optional_envvar("METRICS_PORT").and_then(|port| {
+            port.parse::<u16>()
+                .map_err(|_| eprintln!("Invalid METRICS_PORT {:?}, serving metrics on PORT.", port))
+                .ok()
+        });
 
         #[cfg(feature = "metrics")]
         let metrics_ttl = get_and_parse_envvar::<u64>("METRICS_TTL", 86400000);
+        #[cfg(feature = "metrics")]
+        let metrics_auth_token = get_optional_envvar("METRICS_AUTH_TOKEN");
+        #[cfg(feature = "metrics")]
+        let metrics_global_id_mode = get_and_parse_envvar::<MetricsGlobalIdMode>(
+            "METRICS_GLOBAL_ID_MODE",
+            MetricsGlobalIdMode::Full,
+        );
 
         Self {
             redis: Arc::new(RedisEnvConfig {
                 host: redis_host,
                 port: redis_port,
 
+                replica_host: redis_replica_host,
+                replica_port: redis_replica_port,
+
+                shards: redis_shards,
+
                 username: redis_user,
                 password: redis_pass,
 
                 pool_size: redis_pool_size,
+                max_pending_lock_waiters,
+                command_timeout_ms: redis_command_timeout_ms,
+                connect_timeout_ms: redis_connect_timeout_ms,
+                pubsub_retry_interval_ms: redis_pubsub_retry_interval_ms,
+                pubsub_max_attempts: redis_pubsub_max_attempts,
+                lock_sweep_interval_ms,
 
                 sentinel: sentinel_redis,
-                clustered: clustered_redis,
 
                 sentinel_auth,
                 sentinel_master,
             }),
 
-            webserver: Arc::new(WebserverEnvConfig { host, port }),
+            webserver: Arc::new(WebserverEnvConfig {
+                host,
+                port,
+                uds_path,
+                enable_connect_proxy,
+
+                dual_stack,
+
+                tls_cert,
+                tls_key,
+
+                metrics_port,
+
+                worker_threads,
+                max_blocking_threads,
+            }),
 
             proxy: Arc::new(ProxyEnvConfig {
                 bucket_ttl_ms,
+                bucket_ttl_jitter_percent,
+                interaction_bucket_ttl_ms,
+                route_bucket_max_age_ms,
+                adaptive_bucket_ttl_windows,
+
+                request_costs,
+                long_lived_routes,
+                global_rl_groups,
+                response_header_strip,
+
+                response_cache_ttl: if response_cache_ttl == 0 {
+                    None
+                } else {
+                    Some(Duration::from_millis(response_cache_ttl))
+                },
+                response_cache_max_entries,
+                coalesce_requests,
+
+                upstream_gzip,
+                gzip_responses,
+
+                idempotency_ttl_ms: if idempotency_ttl_ms == 0 {
+                    None
+                } else {
+                    Some(idempotency_ttl_ms)
+                },
+
+                use_discord_buckets,
 
                 global_rl_strategy: global_ratelimit_strategy,
                 route_rl_strategy: route_ratelimit_strategy,
+                route_lock_wait_strategy,
+                expect_continue,
+                loose_route_burst,
+                ratelimit_safety_margin,
 
                 disable_global_rl,
+                disable_route_rl,
+                allow_ratelimit_bypass,
+                allow_bucket_strategy_override,
+                trusted_proxies,
+                isolate_unauthenticated_clients,
+                reject_unknown_routes,
+
+                default_global_rl,
+                large_sharding_minimum,
+                large_sharding_shard_rl,
 
                 lock_timeout: Duration::from_millis(lock_wait_timeout),
+                lock_ttl_secs,
+                request_deadline: if request_deadline == 0 {
+                    None
+                } else {
+                    Some(Duration::from_millis(request_deadline))
+                },
+                disabled_retry_after_secs,
+                slow_request_threshold: if slow_request_log_ms == 0 {
+                    None
+                } else {
+                    Some(Duration::from_millis(slow_request_log_ms))
+                },
 
                 disable_http2,
-
-                clustered_redis,
+                upstream_max_idle_per_host,
+                upstream_max_in_flight,
+                max_concurrent_probes,
+
+                path_prefix_strip,
+
+                discord_api_url,
+                discord_api_host,
+                discord_api_version,
+                discord_tls_ca,
+                discord_resolve_overrides,
+                discord_allow_http,
+                discord_egress_proxy,
+                no_proxy,
+
+                shadow_mode,
+                access_log,
+                log_bot_id_salt,
+                ratelimit_audit_log,
+                expose_proxy_ratelimit_headers,
+                debug_auth_token,
+                admin_auth_token,
+                bucket_preload_file,
+                token_validation_file,
 
                 #[cfg(feature = "metrics")]
                 metrics_ttl,
+                #[cfg(feature = "metrics")]
+                metrics_auth_token,
+                #[cfg(feature = "metrics")]
+                metrics_global_id_mode,
             }),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use super::*;
+    use crate::request::route_display_bucket;
+
+    #[test]
+    fn global_ratelimit_groups_map_bots_to_their_group() {
+        let groups = parse_global_rl_groups(" pool = 1, 2 ;solo=3;;=4;nonsense");
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups["1"], "pool");
+        assert_eq!(groups["2"], "pool");
+        assert_eq!(groups["3"], "solo");
+        assert!(!groups.contains_key("4"));
+
+        // A bot listed twice ends up in the group listed last
+        assert_eq!(parse_global_rl_groups("a=1;b=1")["1"], "b");
+    }
+
+    #[test]
+    fn request_costs_skip_invalid_entries() {
+        assert_eq!(
+            parse_request_costs(" /messages/bulk-delete=5, /bans = 3 ,"),
+            [
+                ("/messages/bulk-delete".to_string(), 5),
+                ("/bans".to_string(), 3)
+            ]
+        );
+
+        assert!(parse_request_costs("").is_empty());
+        assert!(parse_request_costs("/bans,/bans=0,/bans=many,/bans=-1").is_empty());
+    }
+
+    #[test]
+    fn only_matching_routes_cost_more() {
+        let mut config = (*AppEnvConfig::from_env().proxy).clone();
+        config.request_costs = parse_request_costs("/messages/bulk-delete=5");
+
+        let cost = |method: &Method, path: &str| {
+            config.request_cost(&route_display_bucket(method, path).unwrap())
+        };
+
+        assert_eq!(
+            cost(
+                &Method::POST,
+                "/api/v10/channels/1000000000000000001/messages/bulk-delete"
+            ),
+            5
+        );
+        assert_eq!(
+            cost(
+                &Method::POST,
+                "/api/v10/channels/1000000000000000001/messages"
+            ),
+            1
+        );
+    }
+}
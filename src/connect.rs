@@ -0,0 +1,52 @@
+use axum::response::Response;
+use http::{Method, Request, StatusCode};
+use hyper::Body;
+use tokio::{io::copy_bidirectional, net::TcpStream};
+
+use crate::responses;
+
+/// The only destinations `CONNECT` requests may tunnel to
+const CONNECT_ALLOWLIST: &[&str] = &["gateway.discord.gg:443", "discord.com:443"];
+
+pub async fn connect(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::CONNECT {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("Response builder failed.");
+    }
+
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.as_str().to_ascii_lowercase(),
+        None => return responses::invalid_request("Missing CONNECT authority.".to_string()),
+    };
+
+    if !CONNECT_ALLOWLIST.contains(&authority.as_str()) {
+        tracing::debug!("Refused CONNECT to disallowed host {}", authority);
+        return responses::forbidden();
+    }
+
+    let mut upstream = match TcpStream::connect(&authority).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            tracing::error!("Failed to connect to {}: {:?}", authority, err);
+            return responses::bad_gateway();
+        }
+    };
+
+    tokio::spawn(async move {
+        let mut upgraded = match hyper::upgrade::on(req).await {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                tracing::error!("Failed to upgrade CONNECT request: {:?}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = copy_bidirectional(&mut upgraded, &mut upstream).await {
+            tracing::debug!("CONNECT tunnel to {} closed: {:?}", authority, err);
+        }
+    });
+
+    Response::new(Body::empty())
+}
@@ -0,0 +1,168 @@
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Method,
+};
+use hyper::{Body, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{auth::token_matches, proxy::Proxy, request::DiscordRequestInfo, responses};
+
+const DEBUG_TOKEN_HEADER: &str = "x-proxy-debug-token";
+
+#[derive(Deserialize)]
+pub struct DebugBucketQuery {
+    pub method: String,
+    pub path: String,
+}
+
+#[derive(Deserialize)]
+pub struct DebugRatelimitQuery {
+    pub method: String,
+    pub path: String,
+    /// The bot token whose buckets to look at, as it would be sent in `Authorization`. Defaults to the header itself.
+    pub token: Option<String>,
+}
+
+impl Proxy {
+    /// Shows how a request would be bucketed, without sending it to Discord or touching its ratelimits.
+    pub async fn debug_bucket(
+        &self,
+        query: DebugBucketQuery,
+        headers: &HeaderMap,
+    ) -> Response<Body> {
+        if !self.is_debug_authorized(headers) {
+            return responses::unauthorized();
+        }
+
+        let request_info = match self
+            .debug_request_info(&query.method, &query.path, headers)
+            .await
+        {
+            Ok(request_info) => request_info,
+            Err(response) => return response,
+        };
+
+        json_response(json!({
+            "resource": request_info.resource.to_string(),
+            "route_bucket": request_info.route_bucket,
+            "route_display_bucket": request_info.route_display_bucket,
+            "discord_bucket": request_info.discord_bucket,
+            "uses_global_ratelimit": request_info.uses_global_ratelimit,
+            "uses_interaction_token": request_info.uses_interaction_token,
+            "global_rl_group": request_info.global_rl_group,
+            "global_id_redis_key": request_info.global_id_redis_key,
+            "route_bucket_redis_key": request_info.route_bucket_redis_key,
+        }))
+    }
+
+    /// Shows what the proxy currently has stored for a request's route bucket. Only reads the bucket, so looking at it
+    /// never uses up any of its quota.
+    pub async fn debug_ratelimit(
+        &self,
+        query: DebugRatelimitQuery,
+        headers: &HeaderMap,
+    ) -> Response<Body> {
+        if !self.is_debug_authorized(headers) {
+            return responses::unauthorized();
+        }
+
+        let mut headers = headers.clone();
+        if let Some(token) = &query.token {
+            match HeaderValue::from_str(token) {
+                Ok(token) => headers.insert(AUTHORIZATION, token),
+                Err(_) => return responses::invalid_request("Invalid token".into()),
+            };
+        }
+
+        let request_info = match self
+            .debug_request_info(&query.method, &query.path, &headers)
+            .await
+        {
+            Ok(request_info) => request_info,
+            Err(response) => return response,
+        };
+
+        let state = match self
+            .redis_for(&request_info.route_bucket_redis_key)
+            .read_route_rl(&request_info.route_bucket_redis_key)
+            .await
+        {
+            Ok(state) if state.len() == 6 => state,
+            Ok(state) => {
+                tracing::error!("Unexpected bucket state from Redis: {:?}", state);
+                return responses::internal_error();
+            }
+            Err(err) => {
+                tracing::error!("Failed to read bucket state: {}", err);
+                return responses::internal_error();
+            }
+        };
+
+        // Redis answers -1 or -2 for keys that have no expiry or don't exist
+        let positive = |value: i64| -> Value {
+            if value >= 0 {
+                value.into()
+            } else {
+                Value::Null
+            }
+        };
+
+        let limit = positive(state[0]);
+        let remaining = match limit.as_i64() {
+            Some(limit) => (limit - state[1]).max(0).into(),
+            None => Value::Null,
+        };
+
+        json_response(json!({
+            "route_bucket": request_info.route_bucket,
+            "route_display_bucket": request_info.route_display_bucket,
+            "route_bucket_redis_key": request_info.route_bucket_redis_key,
+            "limit": limit,
+            "count": state[1],
+            "remaining": remaining,
+            "reset_after_ms": positive(state[2]),
+            "reset_at_ms": positive(state[3]),
+            "expires_at_ms": positive(state[4]),
+            "locked": state[5] == 1,
+        }))
+    }
+
+    fn is_debug_authorized(&self, headers: &HeaderMap) -> bool {
+        match &self.config.debug_auth_token {
+            Some(debug_auth_token) => token_matches(
+                headers
+                    .get(DEBUG_TOKEN_HEADER)
+                    .and_then(|token| token.to_str().ok()),
+                debug_auth_token,
+            ),
+            None => false,
+        }
+    }
+
+    async fn debug_request_info(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Result<DiscordRequestInfo, Response<Body>> {
+        let method = Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| responses::invalid_request(format!("Invalid Method: {}", method)))?;
+
+        let mut request_info = DiscordRequestInfo::new(&method, path, headers, &self.config, None)
+            .map_err(|err| responses::invalid_request(err.to_string()))?;
+
+        if self.config.use_discord_buckets {
+            self.resolve_discord_bucket(&mut request_info).await;
+        }
+
+        Ok(request_info)
+    }
+}
+
+fn json_response(body: Value) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("Response builder failed.")
+}
@@ -4,11 +4,6 @@ use thiserror::Error;
 
 use crate::proxy::Proxy;
 
-const DEFAULT: u16 = 50;
-
-const LARGE_SHARDING_MINIMUM: u16 = 500;
-const LARGE_SHARDING_INTERNAL_SHARD_RL: u16 = 25;
-
 #[derive(Deserialize)]
 struct GetGatewayBotResponse {
     // url: String,
@@ -27,22 +22,23 @@ struct SessionStartLimit {
 #[derive(Error, Debug)]
 pub enum DiscordError {
     #[error("Non 2xx Status Code fetching Global Ratelimit: {0}")]
-    DiscordError(StatusCode),
+    BadStatus(StatusCode),
 
     #[error("HTTP Error fetching Global Ratelimit: {0}")]
-    RequestError(#[from] hyper::Error),
+    Request(#[from] hyper::Error),
 
     #[error("Global Ratelimit failed to parse: {0}")]
-    ParseError(#[from] serde_json::Error),
+    Parse(#[from] serde_json::Error),
 }
 
-const GET_GATEWAY_URL: &'static str = "https://discord.com/api/v10/gateway/bot";
-
 impl Proxy {
     pub async fn fetch_discord_global_ratelimit(&self, token: &str) -> Result<u16, DiscordError> {
         let req = Request::builder()
             .method("GET")
-            .uri(GET_GATEWAY_URL)
+            .uri(format!(
+                "{}/api/{}/gateway/bot",
+                self.config.discord_api_url, self.config.discord_api_version
+            ))
             .header("Authorization", token)
             .body(Body::empty())
             .expect("Failed to build global ratelimit request.");
@@ -50,7 +46,7 @@ impl Proxy {
         let result = self.http_client.request(req).await?;
 
         if !result.status().is_success() {
-            return Err(DiscordError::DiscordError(result.status()));
+            return Err(DiscordError::BadStatus(result.status()));
         }
 
         let body = hyper::body::aggregate(result).await?;
@@ -58,16 +54,18 @@ impl Proxy {
         let gateway_bot: GetGatewayBotResponse = serde_json::from_reader(body.reader())?;
 
         let global_ratelimit = if gateway_bot.session_start_limit.max_concurrency > 1 {
-            let allowed_for_concurrency = gateway_bot.session_start_limit.max_concurrency as u16
-                * LARGE_SHARDING_INTERNAL_SHARD_RL;
+            let allowed_for_concurrency = gateway_bot
+                .session_start_limit
+                .max_concurrency
+                .saturating_mul(self.config.large_sharding_shard_rl);
 
-            if allowed_for_concurrency > LARGE_SHARDING_MINIMUM {
+            if allowed_for_concurrency > self.config.large_sharding_minimum {
                 allowed_for_concurrency
             } else {
-                LARGE_SHARDING_MINIMUM
+                self.config.large_sharding_minimum
             }
         } else {
-            DEFAULT
+            self.config.default_global_rl
         };
 
         Ok(global_ratelimit)
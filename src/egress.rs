@@ -0,0 +1,184 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use base64_simd::STANDARD;
+use hyper::{client::HttpConnector, service::Service, Uri};
+use percent_encoding::percent_decode_str;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::resolver::OverrideResolver;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Proxies answer CONNECT with a short header block, anything longer isn't one
+const MAX_CONNECT_RESPONSE_LEN: usize = 8192;
+
+struct EgressProxy {
+    uri: Uri,
+    /// `Proxy-Authorization` built from the proxy URI's credentials, if it has any
+    authorization: Option<String>,
+    no_proxy: Vec<String>,
+}
+
+impl EgressProxy {
+    fn bypasses(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        self.no_proxy.iter().any(|pattern| {
+            pattern == "*"
+                || host.eq_ignore_ascii_case(pattern)
+                || (host.len() > pattern.len()
+                    && host[host.len() - pattern.len()..].eq_ignore_ascii_case(pattern)
+                    && host.as_bytes()[host.len() - pattern.len() - 1] == b'.')
+        })
+    }
+}
+
+/// Connects to Discord directly, or through an HTTP CONNECT tunnel if an egress proxy is configured.
+#[derive(Clone)]
+pub struct EgressConnector {
+    direct: HttpConnector<OverrideResolver>,
+    proxy: Option<Arc<EgressProxy>>,
+}
+
+impl EgressConnector {
+    pub fn new(
+        direct: HttpConnector<OverrideResolver>,
+        proxy: Option<Uri>,
+        no_proxy: Vec<String>,
+    ) -> Self {
+        let proxy = proxy.map(|uri| {
+            let authorization = uri
+                .authority()
+                .and_then(|authority| authority.as_str().rsplit_once('@'))
+                .map(|(credentials, _)| basic_authorization(credentials));
+
+            Arc::new(EgressProxy {
+                uri,
+                authorization,
+                no_proxy,
+            })
+        });
+
+        Self { direct, proxy }
+    }
+}
+
+impl Service<Uri> for EgressConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.direct.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let mut direct = self.direct.clone();
+
+        let proxy = match &self.proxy {
+            Some(proxy) if !proxy.bypasses(dst.host().unwrap_or_default()) => proxy.clone(),
+            _ => return Box::pin(async move { Ok(direct.call(dst).await?) }),
+        };
+
+        Box::pin(async move {
+            let mut stream = direct.call(proxy.uri.clone()).await?;
+            tunnel(&mut stream, &dst, proxy.authorization.as_deref()).await?;
+
+            Ok(stream)
+        })
+    }
+}
+
+/// Builds a `Basic` authorization from a URI's userinfo, whose user and password may be percent-encoded.
+fn basic_authorization(userinfo: &str) -> String {
+    let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+
+    let mut credentials: Vec<u8> = percent_decode_str(user).collect();
+    credentials.push(b':');
+    credentials.extend(percent_decode_str(password));
+
+    format!("Basic {}", STANDARD.encode_to_string(credentials))
+}
+
+async fn tunnel(stream: &mut TcpStream, dst: &Uri, authorization: Option<&str>) -> io::Result<()> {
+    let host = dst
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Destination has no host"))?;
+    let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+        Some("http") => 80,
+        _ => 443,
+    });
+
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if let Some(authorization) = authorization {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes()).await?;
+
+    // Servers only speak once the TLS handshake has started, so nothing past the proxy's response can be read here
+    let mut response = Vec::with_capacity(256);
+    let mut buf = [0u8; 512];
+
+    while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+        if response.len() > MAX_CONNECT_RESPONSE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Egress proxy response too long",
+            ));
+        }
+
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Egress proxy closed the connection",
+            ));
+        }
+
+        response.extend_from_slice(&buf[..read]);
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "Egress proxy refused tunnel to {}:{}: {}",
+            host, port, status_line
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_credentials_are_percent_decoded() {
+        // user:pass
+        assert_eq!(basic_authorization("user:pass"), "Basic dXNlcjpwYXNz");
+        // us@r:p:ss word
+        assert_eq!(
+            basic_authorization("us%40r:p%3Ass%20word"),
+            "Basic dXNAcjpwOnNzIHdvcmQ="
+        );
+        // user:
+        assert_eq!(basic_authorization("user"), "Basic dXNlcjo=");
+    }
+}
@@ -0,0 +1,256 @@
+use axum::response::Response;
+use base64_simd::STANDARD;
+use fred::{prelude::RedisError, util::sha1_hash};
+use http::{HeaderName, HeaderValue, Method, Request, StatusCode};
+use hyper::{body::Bytes, Body, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{redis::ProxyRedisClient, request::DiscordRequestInfo, responses};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Stored under an idempotency key while the first request using it is still in flight
+pub const PENDING: &str = "pending";
+
+/// A response stored in Redis so it can be replayed for duplicate requests
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+/// Returns the Redis key for a mutating request's `Idempotency-Key`, if it has one.
+pub fn redis_key(req: &Request<Body>, request_info: &DiscordRequestInfo) -> Option<String> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return None;
+    }
+
+    let idempotency_key = req.headers().get(IDEMPOTENCY_KEY_HEADER)?.to_str().ok()?;
+
+    // Scoped by the token as well, as the global id alone is decoded from an unverified token
+    let token_hash = request_info
+        .token
+        .as_deref()
+        .map(sha1_hash)
+        .unwrap_or_default();
+
+    Some(format!(
+        "idempotency:{{{}}}:{}:{}",
+        request_info.global_id, token_hash, idempotency_key
+    ))
+}
+
+/// Returns the response to send for a duplicate request, or None if the key hasn't been used yet.
+pub async fn replay(
+    redis: &ProxyRedisClient,
+    key: &str,
+) -> Result<Option<Response<Body>>, RedisError> {
+    let stored = match redis.get_idempotency_key(key).await? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
+
+    if stored == PENDING {
+        return Ok(Some(responses::idempotency_conflict()));
+    }
+
+    Ok(deserialize_response(key, &stored))
+}
+
+/// Rebuilds a response stored by `serialize_response`, unreadable ones are ignored so the request goes ahead instead.
+fn deserialize_response(key: &str, stored: &str) -> Option<Response<Body>> {
+    let stored = match serde_json::from_str::<StoredResponse>(stored) {
+        Ok(stored) => stored,
+        Err(err) => {
+            tracing::warn!("Ignoring unreadable idempotent response {}: {:?}", key, err);
+            return None;
+        }
+    };
+
+    let body = match STANDARD.decode_to_vec(stored.body.as_bytes()) {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::warn!(
+                "Ignoring idempotent response {} with an unreadable body: {:?}",
+                key,
+                err
+            );
+            return None;
+        }
+    };
+
+    let status = match StatusCode::from_u16(stored.status) {
+        Ok(status) => status,
+        Err(err) => {
+            tracing::warn!(
+                "Ignoring idempotent response {} with an invalid status: {:?}",
+                key,
+                err
+            );
+            return None;
+        }
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+
+    headers.insert(
+        "x-proxy-idempotent-replay",
+        HeaderValue::from_static("true"),
+    );
+
+    Some(response)
+}
+
+pub fn serialize_response(status: StatusCode, headers: &HeaderMap, body: &Bytes) -> String {
+    let stored = StoredResponse {
+        status: status.as_u16(),
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((name.as_str().to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect(),
+        body: STANDARD.encode_to_string(body),
+    };
+
+    serde_json::to_string(&stored).expect("Failed to serialize idempotent response.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppEnvConfig;
+
+    fn request(method: Method, idempotency_key: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri("/api/v10/channels/1/messages");
+        if let Some(idempotency_key) = idempotency_key {
+            builder = builder.header(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+        }
+
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn request_info(req: &Request<Body>, token: &str) -> DiscordRequestInfo {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Authorization",
+            HeaderValue::from_str(&format!(
+                "Bot {}.GhIjKl.{}",
+                base64_simd::STANDARD_NO_PAD.encode_to_string("1000000000000000001"),
+                token
+            ))
+            .unwrap(),
+        );
+
+        let config = (*AppEnvConfig::from_env().proxy).clone();
+        DiscordRequestInfo::new(req.method(), req.uri().path(), &headers, &config, None).unwrap()
+    }
+
+    #[test]
+    fn only_mutating_requests_with_a_key_are_idempotent() {
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            let req = request(method, Some("abc"));
+            assert_eq!(redis_key(&req, &request_info(&req, "secret")), None);
+        }
+
+        let req = request(Method::POST, None);
+        assert_eq!(redis_key(&req, &request_info(&req, "secret")), None);
+
+        let req = request(Method::POST, Some("abc"));
+        let key = redis_key(&req, &request_info(&req, "secret")).unwrap();
+        assert!(key.starts_with("idempotency:{1000000000000000001}:"));
+        assert!(key.ends_with(":abc"));
+
+        // Other tokens of the same bot don't share keys
+        assert_ne!(
+            redis_key(&req, &request_info(&req, "other-secret")),
+            Some(key)
+        );
+    }
+
+    #[tokio::test]
+    async fn stored_responses_replay_as_they_were() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        headers.append("x-custom", HeaderValue::from_static("a"));
+        headers.append("x-custom", HeaderValue::from_static("b"));
+
+        let stored = serialize_response(
+            StatusCode::CREATED,
+            &headers,
+            &Bytes::from_static(b"{\"id\":\"1\"}"),
+        );
+        let response = deserialize_response("key", &stored).unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(
+            response
+                .headers()
+                .get_all("x-custom")
+                .iter()
+                .collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(response.headers()["x-proxy-idempotent-replay"], "true");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            Bytes::from_static(b"{\"id\":\"1\"}")
+        );
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn duplicates_replay_until_the_key_expires() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+        let key = format!("idempotency:{{test}}:{}", rand::random::<u64>());
+
+        // The first request goes ahead, duplicates wait for it to finish
+        assert!(replay(&redis, &key).await.unwrap().is_none());
+        assert!(redis.claim_idempotency_key(&key, 500).await.unwrap());
+        assert!(!redis.claim_idempotency_key(&key, 500).await.unwrap());
+        assert_eq!(
+            replay(&redis, &key).await.unwrap().unwrap().status(),
+            StatusCode::CONFLICT
+        );
+
+        let stored = serialize_response(StatusCode::OK, &HeaderMap::new(), &Bytes::new());
+        redis.set_idempotency_key(&key, stored, 500).await.unwrap();
+        let response = replay(&redis, &key).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-proxy-idempotent-replay"], "true");
+
+        // Once expired, the key can be used for a new request
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        assert!(replay(&redis, &key).await.unwrap().is_none());
+        assert!(redis.claim_idempotency_key(&key, 500).await.unwrap());
+    }
+
+    #[test]
+    fn unreadable_responses_are_not_replayed() {
+        for stored in [
+            "not json",
+            r#"{"status":200,"headers":[],"body":"not base64!"}"#,
+            r#"{"status":1000,"headers":[],"body":""}"#,
+        ] {
+            assert!(deserialize_response("key", stored).is_none());
+        }
+    }
+}
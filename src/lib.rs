@@ -0,0 +1,30 @@
+//! A proxy for Discord's REST API which handles ratelimits for every client using it, backed by Redis.
+//!
+//! Build a [`proxy::Proxy`] from [`config::AppEnvConfig`] and mount [`proxy::Proxy::router`] into an axum app to embed it.
+
+pub mod config;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+mod auth;
+mod buckets;
+mod cache;
+mod client_ip;
+mod coalesce;
+mod compression;
+pub mod connect;
+mod debug;
+mod discord;
+mod egress;
+mod idempotency;
+pub mod proxy;
+mod ratelimits;
+mod redis;
+mod request;
+mod resolver;
+mod responses;
+pub mod routes;
+mod warmup;
+
+pub use ratelimits::{ProxyQuota, RatelimitRetryCause, RatelimitStatus};
@@ -1,32 +1,32 @@
-use axum::{handler::Handler, routing::get, Router};
 use fred::prelude::RedisError;
-use std::{net::SocketAddr, process::exit};
+use hyper::server::{conn::AddrIncoming, Builder};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    net::{SocketAddr, TcpListener},
+    process::exit,
+};
+
+use tls::TlsAcceptor;
 use tracing_subscriber::{
     filter::LevelFilter, prelude::__tracing_subscriber_SubscriberExt, EnvFilter, Registry,
 };
+#[cfg(unix)]
+use {crate::uds::UnixAcceptor, std::path::Path};
 
-use crate::{
-    config::AppEnvConfig,
+use discord_api_proxy::{
+    config::{AppEnvConfig, WebserverEnvConfig},
+    connect::connect,
     proxy::Proxy,
-    routes::{health, metrics, proxy},
 };
 
-mod config;
-
 #[cfg(feature = "metrics")]
-mod metrics;
-
-mod buckets;
-mod discord;
-mod proxy;
-mod ratelimits;
-mod redis;
-mod request;
-mod responses;
-mod routes;
-
-#[tokio::main]
-async fn main() -> Result<(), RedisError> {
+use discord_api_proxy::metrics;
+
+mod tls;
+#[cfg(unix)]
+mod uds;
+
+fn main() -> Result<(), RedisError> {
     tracing::subscriber::set_global_default(
         Registry::default()
             .with(
@@ -47,24 +47,128 @@ async fn main() -> Result<(), RedisError> {
 
     let config = AppEnvConfig::from_env();
 
+    let worker_threads = match config.webserver.worker_threads {
+        0 => std::thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1),
+        worker_threads => worker_threads,
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .max_blocking_threads(config.webserver.max_blocking_threads)
+        .enable_all()
+        .build()
+        .expect("Failed to build Tokio runtime.");
+
+    tracing::info!(
+        "Starting Tokio runtime with {} worker threads and up to {} blocking threads.",
+        worker_threads,
+        config.webserver.max_blocking_threads
+    );
+
+    runtime.block_on(serve(config))
+}
+
+async fn serve(config: AppEnvConfig) -> Result<(), RedisError> {
     #[cfg(feature = "metrics")]
-    metrics::register_metrics();
+    metrics::register_metrics(config.proxy.metrics_global_id_mode.clone());
 
     let discord_proxy = Proxy::new(config.proxy, config.redis).await?;
 
-    let addr: SocketAddr = format!("{}:{}", config.webserver.host, config.webserver.port)
-        .parse()
-        .expect("Failed to parse socket address.");
+    let internal_app = discord_proxy.internal_router();
+    let app = discord_proxy.api_router();
+
+    // Keep metrics and health checks off the public port if a separate one is configured
+    let app = match config.webserver.metrics_port {
+        Some(metrics_port) => {
+            let metrics_addr = socket_addr_or_exit(&config.webserver, metrics_port);
+
+            tracing::info!("Serving metrics on http://{}", &metrics_addr);
+
+            let server = bind_tcp(&config.webserver, metrics_addr)
+                .serve(internal_app.into_make_service())
+                .with_graceful_shutdown(shutdown_signal());
+
+            tokio::spawn(async move {
+                if let Err(err) = server.await {
+                    eprintln!("Axum Metrics Server Error: {}", err);
+                }
+            });
+
+            app
+        }
+        None => app.merge(internal_app),
+    };
+
+    // CONNECT requests use authority-form targets, so they never match a route
+    let app = if config.webserver.enable_connect_proxy {
+        app.fallback(connect)
+    } else {
+        app
+    };
+
+    #[cfg(unix)]
+    if let Some(uds_path) = &config.webserver.uds_path {
+        let uds_path = Path::new(uds_path);
+
+        let acceptor = UnixAcceptor::bind(uds_path).expect("Failed to bind Unix domain socket.");
+
+        tracing::info!("Serving API Proxy on unix:{}", uds_path.display());
+
+        let server = axum::Server::builder(acceptor)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(shutdown_signal());
+
+        if let Err(err) = server.await {
+            eprintln!("Axum Server Error: {}", err);
+        }
+
+        if let Err(err) = std::fs::remove_file(uds_path) {
+            eprintln!("Failed to remove Unix domain socket: {}", err);
+        }
+
+        tracing::info!("Shutting down.");
+
+        exit(0);
+    }
+
+    #[cfg(not(unix))]
+    if config.webserver.uds_path.is_some() {
+        panic!("Unix domain sockets are not supported on this platform.");
+    }
+
+    let addr = socket_addr_or_exit(&config.webserver, config.webserver.port);
+
+    if let (Some(tls_cert), Some(tls_key)) = (&config.webserver.tls_cert, &config.webserver.tls_key)
+    {
+        let acceptor = TlsAcceptor::bind(
+            bind_tcp_listener(&config.webserver, addr).expect("Failed to bind TLS socket."),
+            tls_cert.clone(),
+            tls_key.clone(),
+        )
+        .expect("Failed to load LISTEN_TLS_CERT and LISTEN_TLS_KEY.");
+
+        tracing::info!("Serving API Proxy on https://{}", &addr);
+
+        let server = axum::Server::builder(acceptor)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown_signal());
+
+        if let Err(err) = server.await {
+            eprintln!("Axum Server Error: {}", err);
+        }
 
-    let app = Router::new()
-        .route("/health", get(health))
-        .route("/metrics", get(metrics).with_state(discord_proxy.clone()))
-        .route_service("/api/*path", proxy.with_state(discord_proxy));
+        tracing::info!("Shutting down.");
+
+        exit(0);
+    }
 
     tracing::info!("Serving API Proxy on http://{}", &addr);
 
-    let server = axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    // The peer address is needed to tell whether forwarding headers can be trusted
+    let server = bind_tcp(&config.webserver, addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal());
 
     if let Err(err) = server.await {
@@ -76,6 +180,53 @@ async fn main() -> Result<(), RedisError> {
     exit(0);
 }
 
+fn socket_addr_or_exit(config: &WebserverEnvConfig, port: u16) -> SocketAddr {
+    match config.socket_addr(port) {
+        Ok(addr) => addr,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    }
+}
+
+fn bind_tcp(config: &WebserverEnvConfig, addr: SocketAddr) -> Builder<AddrIncoming> {
+    if config.dual_stack {
+        axum::Server::from_tcp(bind_dual_stack(addr).expect("Failed to bind dual-stack socket."))
+            .expect("Failed to serve on dual-stack socket.")
+    } else {
+        axum::Server::bind(&addr)
+    }
+}
+
+/// Binds a non-blocking listener for acceptors that handle connections themselves
+fn bind_tcp_listener(
+    config: &WebserverEnvConfig,
+    addr: SocketAddr,
+) -> std::io::Result<TcpListener> {
+    if config.dual_stack {
+        return bind_dual_stack(addr);
+    }
+
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+/// Binds an IPv6 socket that also accepts IPv4 connections through IPv4-mapped addresses
+fn bind_dual_stack(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
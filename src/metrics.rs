@@ -1,13 +1,59 @@
-use std::sync::atomic::Ordering;
+use std::sync::{atomic::Ordering, OnceLock};
 
 use axum::response::Response;
+use fred::util::sha1_hash;
+use http::{
+    header::{ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderMap,
+};
 use hyper::Body;
 use lazy_static::lazy_static;
 use prometheus::{
-    Counter, CounterVec, Encoder, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder,
+    proto::{MetricFamily, MetricType},
+    Counter, CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounter, IntGauge, Opts,
+    Registry, TextEncoder, TEXT_FORMAT,
 };
 
-use crate::proxy::Proxy;
+use crate::{auth::token_matches, config::MetricsGlobalIdMode, proxy::Proxy, responses};
+
+/// From a small JSON payload up to Discord's largest attachment uploads
+const BODY_SIZE_BUCKETS: [f64; 8] = [
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 26214400.0,
+];
+
+static GLOBAL_ID_MODE: OnceLock<MetricsGlobalIdMode> = OnceLock::new();
+
+fn global_id_mode() -> &'static MetricsGlobalIdMode {
+    GLOBAL_ID_MODE.get_or_init(|| MetricsGlobalIdMode::Full)
+}
+
+/// Label names for a metric, with the global_id label prepended unless it has been disabled.
+fn global_id_labels(labels: &[&'static str]) -> Vec<&'static str> {
+    match global_id_mode() {
+        MetricsGlobalIdMode::None => labels.to_vec(),
+        _ => ["global_id"].iter().chain(labels).copied().collect(),
+    }
+}
+
+/// Label values for a metric, matching the names from `global_id_labels`.
+pub fn with_global_id<'a>(global_id: &'a str, values: &[&'a str]) -> Vec<&'a str> {
+    match global_id_mode() {
+        MetricsGlobalIdMode::None => values.to_vec(),
+        _ => [global_id].iter().chain(values).copied().collect(),
+    }
+}
+
+/// The value to use for the global_id label for a request.
+pub fn global_id_label(global_id: &str) -> String {
+    match global_id_mode() {
+        MetricsGlobalIdMode::Hashed => sha1_hash(global_id)[..8].to_string(),
+        _ => global_id.to_string(),
+    }
+}
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text";
+const OPENMETRICS_CONTENT_TYPE_HEADER: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
 
 lazy_static! {
     pub static ref REGISTRY: Registry = Registry::new();
@@ -17,7 +63,7 @@ lazy_static! {
             "Results of attempted Discord API requests."
         )
         .buckets(vec![0.1, 0.2, 0.3, 0.4, 0.6, 1.0, 2.5, 5.0]),
-        &["global_id", "route", "status"]
+        &global_id_labels(&["route", "status"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref DISCORD_REQUEST_COUNTER: CounterVec = CounterVec::new(
@@ -25,7 +71,7 @@ lazy_static! {
             "discord_request_counter",
             "Number of requests for which the proxy encountered an unexpected error."
         ),
-        &["global_id", "route"]
+        &global_id_labels(&["route"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref DISCORD_REQUEST_SHARED_429: CounterVec = CounterVec::new(
@@ -33,7 +79,7 @@ lazy_static! {
             "discord_request_shared_429",
             "Number of requests for which a shared 429 was encountered."
         ),
-        &["global_id", "route"]
+        &global_id_labels(&["route"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref DISCORD_REQUEST_ROUTE_429: CounterVec = CounterVec::new(
@@ -41,7 +87,7 @@ lazy_static! {
             "discord_request_route_429",
             "Number of requests for which a unique 429 was encountered."
         ),
-        &["global_id", "route"]
+        &global_id_labels(&["route"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref DISCORD_REQUEST_GLOBAL_429: CounterVec = CounterVec::new(
@@ -49,7 +95,7 @@ lazy_static! {
             "discord_request_global_429",
             "Number of requests for which a global 429 was encountered."
         ),
-        &["global_id"]
+        &global_id_labels(&[])
     )
     .expect("Failed to create metrics collector.");
     pub static ref PROXY_REQUEST_RATELIMIT_CHECK_TIMES: HistogramVec = HistogramVec::new(
@@ -58,7 +104,35 @@ lazy_static! {
             "Time taken to check ratelimits for a request."
         )
         .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25]),
-        &["global_id", "route",]
+        &global_id_labels(&["route"])
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref PROXY_GLOBAL_RATELIMIT_FETCH_TIMES: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "proxy_global_ratelimit_fetch_times",
+            "Time taken by the global lock holder to fetch the global ratelimit from Discord and release the lock."
+        )
+        .buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+        &global_id_labels(&[])
+    )
+    .expect("Failed to create metrics collector.");
+    // Only labelled by route, as these are per request already and a global id would make them huge
+    pub static ref DISCORD_REQUEST_BODY_SIZES: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "discord_request_body_sizes",
+            "Sizes in bytes of request bodies sent to Discord, from their Content-Length."
+        )
+        .buckets(BODY_SIZE_BUCKETS.to_vec()),
+        &["route"]
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref DISCORD_RESPONSE_BODY_SIZES: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "discord_response_body_sizes",
+            "Sizes in bytes of response bodies received from Discord, from their Content-Length."
+        )
+        .buckets(BODY_SIZE_BUCKETS.to_vec()),
+        &["route"]
     )
     .expect("Failed to create metrics collector.");
     pub static ref PROXY_REQUEST_COUNTER: CounterVec = CounterVec::new(
@@ -66,7 +140,7 @@ lazy_static! {
             "proxy_request_counter",
             "Number of requests for which the proxy encountered an unexpected error."
         ),
-        &["global_id", "route"]
+        &global_id_labels(&["route"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref PROXY_REQUEST_ROUTE_429: CounterVec = CounterVec::new(
@@ -74,7 +148,7 @@ lazy_static! {
             "proxy_request_route_429",
             "Number of requests ratelimited by the proxy."
         ),
-        &["global_id", "route"]
+        &global_id_labels(&["route"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref PROXY_REQUEST_GLOBAL_429: CounterVec = CounterVec::new(
@@ -82,7 +156,23 @@ lazy_static! {
             "proxy_request_global_429",
             "Number of requests ratelimited by the proxy."
         ),
-        &["global_id"]
+        &global_id_labels(&[])
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref PROXY_REQUEST_GLOBAL_DRIFTED: CounterVec = CounterVec::new(
+        Opts::new(
+            "proxy_request_global_drifted",
+            "Number of ratelimit checks retried because the global ratelimit time slice ended before they completed."
+        ),
+        &global_id_labels(&[])
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref PROXY_REQUESTS_SERVED_LOCALLY: CounterVec = CounterVec::new(
+        Opts::new(
+            "proxy_requests_served_locally",
+            "Number of requests answered by the proxy without contacting Discord."
+        ),
+        &global_id_labels(&["route", "reason"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref PROXY_REQUEST_OVERLOADED: CounterVec = CounterVec::new(
@@ -90,7 +180,7 @@ lazy_static! {
             "proxy_request_overloaded",
             "Number of requests for which the proxy was overloaded."
         ),
-        &["global_id", "route"]
+        &global_id_labels(&["route"])
     )
     .expect("Failed to create metrics collector.");
     pub static ref PROXY_REQUEST_ERRORS: Counter = Counter::new(
@@ -98,9 +188,85 @@ lazy_static! {
         "Number of requests for which the proxy encountered an unexpected error."
     )
     .expect("Failed to create metrics collector.");
+    pub static ref PROXY_UPSTREAM_ERRORS: CounterVec = CounterVec::new(
+        Opts::new(
+            "proxy_upstream_errors",
+            "Number of requests which failed to get a response from Discord, by failure kind."
+        ),
+        &["kind"]
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref PROXY_LOCKS_EXPIRED: CounterVec = CounterVec::new(
+        Opts::new(
+            "proxy_locks_expired",
+            "Number of bucket locks which expired before the lock holder could set the ratelimit, a sign the lock TTL is too short."
+        ),
+        &["lock"]
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref PROXY_COUNTERS_RESET: CounterVec = CounterVec::new(
+        Opts::new(
+            "proxy_counters_reset",
+            "Number of request counters found negative or unreadable and reset, which points to an accounting bug."
+        ),
+        &["counter"]
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref REDIS_RECONNECTS: IntCounter = IntCounter::new(
+        "redis_reconnects",
+        "Number of times a connection in the Redis pool has reconnected."
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref REDIS_LAST_RECONNECT_TIMESTAMP: IntGauge = IntGauge::new(
+        "redis_last_reconnect_timestamp_seconds",
+        "Unix timestamp of the last Redis pool reconnect."
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref DISCORD_REQUESTS_IN_FLIGHT: IntGauge = IntGauge::new(
+        "discord_requests_in_flight",
+        "Number of requests currently being sent to Discord."
+    )
+    .expect("Failed to create metrics collector.");
+    pub static ref DISCORD_BUCKET_MAPPINGS: IntGauge = IntGauge::new(
+        "discord_bucket_mappings",
+        "Number of learned Discord bucket mappings currently cached in memory."
+    )
+    .expect("Failed to create metrics collector.");
+}
+
+/// Records the Content-Length of a body, bodies streamed without one aren't counted.
+pub fn observe_body_size(histogram: &HistogramVec, route: &str, headers: &HeaderMap) {
+    let size = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+
+    if let Some(size) = size {
+        histogram.with_label_values(&[route]).observe(size as f64);
+    }
+}
+
+/// Increments a gauge for as long as it's held, so it's also decremented if the future holding it is dropped
+pub struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    pub fn inc(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
 }
 
-pub fn register_metrics() {
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub fn register_metrics(global_id_mode: MetricsGlobalIdMode) {
+    if GLOBAL_ID_MODE.set(global_id_mode).is_err() {
+        eprintln!("Metrics global_id mode was already set.");
+    }
+
     REGISTRY
         .register(Box::new(DISCORD_REQUEST_RESPONSE_TIMES.clone()))
         .expect("Failed to register metrics collector.");
@@ -125,6 +291,18 @@ pub fn register_metrics() {
         .register(Box::new(PROXY_REQUEST_RATELIMIT_CHECK_TIMES.clone()))
         .expect("Failed to register metrics collector.");
 
+    REGISTRY
+        .register(Box::new(PROXY_GLOBAL_RATELIMIT_FETCH_TIMES.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(DISCORD_REQUEST_BODY_SIZES.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(DISCORD_RESPONSE_BODY_SIZES.clone()))
+        .expect("Failed to register metrics collector.");
+
     REGISTRY
         .register(Box::new(PROXY_REQUEST_COUNTER.clone()))
         .expect("Failed to register metrics collector.");
@@ -137,6 +315,14 @@ pub fn register_metrics() {
         .register(Box::new(PROXY_REQUEST_GLOBAL_429.clone()))
         .expect("Failed to register metrics collector.");
 
+    REGISTRY
+        .register(Box::new(PROXY_REQUEST_GLOBAL_DRIFTED.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(PROXY_REQUESTS_SERVED_LOCALLY.clone()))
+        .expect("Failed to register metrics collector.");
+
     REGISTRY
         .register(Box::new(PROXY_REQUEST_OVERLOADED.clone()))
         .expect("Failed to register metrics collector.");
@@ -145,6 +331,34 @@ pub fn register_metrics() {
         .register(Box::new(PROXY_REQUEST_ERRORS.clone()))
         .expect("Failed to register metrics collector.");
 
+    REGISTRY
+        .register(Box::new(PROXY_UPSTREAM_ERRORS.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(PROXY_LOCKS_EXPIRED.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(PROXY_COUNTERS_RESET.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(REDIS_RECONNECTS.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(REDIS_LAST_RECONNECT_TIMESTAMP.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(DISCORD_REQUESTS_IN_FLIGHT.clone()))
+        .expect("Failed to register metrics collector.");
+
+    REGISTRY
+        .register(Box::new(DISCORD_BUCKET_MAPPINGS.clone()))
+        .expect("Failed to register metrics collector.");
+
     reset_metrics();
 }
 
@@ -155,29 +369,56 @@ pub fn reset_metrics() {
     DISCORD_REQUEST_ROUTE_429.reset();
     DISCORD_REQUEST_GLOBAL_429.reset();
     PROXY_REQUEST_RATELIMIT_CHECK_TIMES.reset();
+    PROXY_GLOBAL_RATELIMIT_FETCH_TIMES.reset();
+    DISCORD_REQUEST_BODY_SIZES.reset();
+    DISCORD_RESPONSE_BODY_SIZES.reset();
     PROXY_REQUEST_COUNTER.reset();
     PROXY_REQUEST_ROUTE_429.reset();
     PROXY_REQUEST_GLOBAL_429.reset();
+    PROXY_REQUEST_GLOBAL_DRIFTED.reset();
+    PROXY_REQUESTS_SERVED_LOCALLY.reset();
     PROXY_REQUEST_OVERLOADED.reset();
     PROXY_REQUEST_ERRORS.reset();
+    PROXY_UPSTREAM_ERRORS.reset();
+    PROXY_LOCKS_EXPIRED.reset();
+    PROXY_COUNTERS_RESET.reset();
 }
 
 impl Proxy {
-    pub fn get_metrics(&self) -> Response<Body> {
-        let mut buffer = Vec::new();
-        if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
-            eprintln!("Metrics could not be encoded: {}", e);
-            return Response::new(Body::from("Internal Server Error"));
-        };
+    pub fn get_metrics(&self, headers: &HeaderMap) -> Response<Body> {
+        if let Some(auth_token) = &self.config.metrics_auth_token {
+            let authorized = token_matches(
+                headers
+                    .get(AUTHORIZATION)
+                    .and_then(|auth| auth.to_str().ok())
+                    .and_then(|auth| auth.strip_prefix("Bearer ")),
+                auth_token,
+            );
 
-        let res = match String::from_utf8(buffer.clone()) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("Metrics buffer could not be converted to string: {}", e);
+            if !authorized {
+                return responses::unauthorized();
+            }
+        }
+
+        let openmetrics = accepts_openmetrics(headers);
+
+        let res = if openmetrics {
+            encode_openmetrics(&REGISTRY.gather())
+        } else {
+            let mut buffer = Vec::new();
+            if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+                eprintln!("Metrics could not be encoded: {}", e);
                 return Response::new(Body::from("Internal Server Error"));
+            };
+
+            match String::from_utf8(buffer) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Metrics buffer could not be converted to string: {}", e);
+                    return Response::new(Body::from("Internal Server Error"));
+                }
             }
         };
-        buffer.clear();
 
         let last_reset_at = self.metrics_last_reset_at.load(Ordering::Acquire);
         let current_timestamp = get_current_timestamp();
@@ -188,7 +429,151 @@ impl Proxy {
             reset_metrics();
         }
 
-        return Response::new(Body::from(res));
+        let content_type = if openmetrics {
+            OPENMETRICS_CONTENT_TYPE_HEADER
+        } else {
+            TEXT_FORMAT
+        };
+
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(res))
+            .expect("Response builder failed.")
+    }
+}
+
+fn accepts_openmetrics(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains(OPENMETRICS_CONTENT_TYPE))
+        .unwrap_or(false)
+}
+
+/// Encodes metrics in the OpenMetrics text format, which differs from Prometheus' in how counters are named, how
+/// values are escaped and in needing to be terminated.
+fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+
+    for family in families {
+        let metric_type = family.get_field_type();
+        let name = match metric_type {
+            MetricType::COUNTER => family
+                .get_name()
+                .strip_suffix("_total")
+                .unwrap_or(family.get_name()),
+            _ => family.get_name(),
+        };
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+
+        out.push_str(&format!("# TYPE {} {}\n", name, type_name));
+        if !family.get_help().is_empty() {
+            out.push_str(&format!(
+                "# HELP {} {}\n",
+                name,
+                escape_openmetrics(family.get_help())
+            ));
+        }
+
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            let mut sample = |suffix: &str, extra_label: Option<(&str, String)>, value: f64| {
+                out.push_str(name);
+                out.push_str(suffix);
+
+                let mut pairs = labels
+                    .iter()
+                    .map(|label| {
+                        format!(
+                            "{}=\"{}\"",
+                            label.get_name(),
+                            escape_openmetrics(label.get_value())
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                if let Some((label, label_value)) = &extra_label {
+                    pairs.push(format!("{}=\"{}\"", label, label_value));
+                }
+                if !pairs.is_empty() {
+                    out.push_str(&format!("{{{}}}", pairs.join(",")));
+                }
+
+                out.push_str(&format!(" {}\n", openmetrics_number(value)));
+            };
+
+            match metric_type {
+                MetricType::COUNTER => sample("_total", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => sample("", None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => sample("", None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+
+                    for bucket in histogram.get_bucket() {
+                        sample(
+                            "_bucket",
+                            Some(("le", openmetrics_number(bucket.get_upper_bound()))),
+                            bucket.get_cumulative_count() as f64,
+                        );
+                    }
+                    if !histogram
+                        .get_bucket()
+                        .iter()
+                        .any(|bucket| bucket.get_upper_bound() == f64::INFINITY)
+                    {
+                        sample(
+                            "_bucket",
+                            Some(("le", "+Inf".to_string())),
+                            histogram.get_sample_count() as f64,
+                        );
+                    }
+
+                    sample("_sum", None, histogram.get_sample_sum());
+                    sample("_count", None, histogram.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+
+                    for quantile in summary.get_quantile() {
+                        sample(
+                            "",
+                            Some(("quantile", openmetrics_number(quantile.get_quantile()))),
+                            quantile.get_value(),
+                        );
+                    }
+
+                    sample("_sum", None, summary.get_sample_sum());
+                    sample("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+fn escape_openmetrics(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn openmetrics_number(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
     }
 }
 
@@ -198,3 +583,77 @@ fn get_current_timestamp() -> u64 {
         .unwrap()
         .as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use prometheus::{Opts, Registry};
+
+    use super::*;
+
+    #[test]
+    fn openmetrics_is_only_served_when_accepted() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_openmetrics(&headers));
+
+        headers.insert(ACCEPT, HeaderValue::from_static("text/plain;version=0.0.4"));
+        assert!(!accepts_openmetrics(&headers));
+
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static(
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5",
+            ),
+        );
+        assert!(accepts_openmetrics(&headers));
+    }
+
+    #[test]
+    fn encodes_openmetrics() {
+        let registry = Registry::new();
+
+        let counter = CounterVec::new(
+            Opts::new("requests", "Requests with \"quotes\" and a \\"),
+            &["route"],
+        )
+        .unwrap();
+        let suffixed = CounterVec::new(Opts::new("errors_total", "Errors"), &["route"]).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency", "Latency").buckets(vec![0.5, 1.0]),
+            &["route"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(suffixed.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+
+        counter.with_label_values(&["a\"b\nc"]).inc();
+        suffixed.with_label_values(&["x"]).inc_by(2.0);
+        histogram.with_label_values(&["x"]).observe(0.75);
+
+        let encoded = encode_openmetrics(&registry.gather());
+        let lines = encoded.lines().collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                "# TYPE errors counter",
+                "# HELP errors Errors",
+                "errors_total{route=\"x\"} 2",
+                "# TYPE latency histogram",
+                "# HELP latency Latency",
+                "latency_bucket{route=\"x\",le=\"0.5\"} 0",
+                "latency_bucket{route=\"x\",le=\"1\"} 1",
+                "latency_bucket{route=\"x\",le=\"+Inf\"} 1",
+                "latency_sum{route=\"x\"} 0.75",
+                "latency_count{route=\"x\"} 1",
+                "# TYPE requests counter",
+                "# HELP requests Requests with \\\"quotes\\\" and a \\\\",
+                "requests_total{route=\"a\\\"b\\nc\"} 1",
+                "# EOF",
+            ]
+        );
+        assert!(encoded.ends_with("# EOF\n"));
+    }
+}
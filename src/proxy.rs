@@ -1,37 +1,81 @@
+use ahash::AHashMap;
 use fred::prelude::RedisError;
 use http::{
-    header::{CONNECTION, TRANSFER_ENCODING, UPGRADE},
-    HeaderMap,
-};
-use hyper::{
-    client::{connect::dns::GaiResolver, HttpConnector},
-    http::HeaderValue,
-    Body, Client, Response, StatusCode, Uri,
+    header::{
+        ACCEPT_ENCODING, ACCESS_CONTROL_REQUEST_METHOD, CONNECTION, EXPECT, TRANSFER_ENCODING,
+        UPGRADE,
+    },
+    HeaderMap, Method,
 };
+use hyper::{client::HttpConnector, http::HeaderValue, Body, Client, Response, StatusCode, Uri};
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore};
 use std::{
+    borrow::Cow,
+    fs::File,
+    future::Future,
+    io::BufReader,
+    net::IpAddr,
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 use thiserror::Error;
-use tracing::{trace, trace_span};
+use tokio::{
+    sync::{RwLock, Semaphore},
+    time::timeout,
+};
+use tracing::{debug, trace, trace_span};
 
 use crate::{
-    config::{ProxyEnvConfig, RedisEnvConfig},
+    buckets::{is_api_version, is_known_resource},
+    cache::{ResponseCache, CACHE_HEADER},
+    client_ip::client_ip,
+    coalesce::{Coalesced, RequestCoalescer, SharedResponse},
+    compression,
+    config::{ExpectContinueStrategy, NewBucketStrategy, ProxyEnvConfig, RedisEnvConfig},
     discord::DiscordError,
-    redis::ProxyRedisClient,
-    request::DiscordRequestInfo,
+    egress::EgressConnector,
+    idempotency,
+    ratelimits::DiscordBucketMapping,
+    redis::{ProxyRedisClient, RedisShards},
+    request::{route_display_bucket, DiscordRequestInfo},
+    resolver::OverrideResolver,
     responses,
 };
 
 #[cfg(feature = "metrics")]
-use {
-    crate::metrics,
-    std::{sync::atomic::AtomicU64, time::Instant},
-};
+use {crate::metrics, std::sync::atomic::AtomicU64};
+
+const BYPASS_RATELIMITS_HEADER: &str = "x-proxy-no-ratelimit";
+const BUCKET_STRATEGY_HEADER: &str = "x-proxy-bucket-strategy";
+const AUDIT_LOG_REASON_HEADER: &str = "x-audit-log-reason";
+
+/// State of a request that is still needed once `process` returns or is cancelled by the request deadline
+#[derive(Default)]
+struct InFlightRequest {
+    /// The route bucket redis key and lock token for a route lock held by the request
+    held_route_lock: Option<(String, String)>,
+    /// The global ratelimit redis key and lock token for a global lock held while its limit is fetched
+    held_global_lock: Option<(String, String)>,
+    route_display_bucket: Option<String>,
+    log_global_id: Option<String>,
+    client_ip: Option<IpAddr>,
+
+    ratelimit_check_time: Option<Duration>,
+    upstream_time: Option<Duration>,
+}
+
+impl InFlightRequest {
+    /// Remembers what the request is for, so it can be logged once it finishes
+    fn describe(&mut self, request_info: &DiscordRequestInfo) {
+        self.route_display_bucket = Some(request_info.route_display_bucket.clone());
+        self.log_global_id = Some(request_info.log_global_id.clone());
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum ProxyError {
@@ -44,6 +88,15 @@ pub enum ProxyError {
     #[error("Invalid Route: {0}")]
     InvalidRequest(String),
 
+    #[error("Unknown Route: {0}")]
+    UnknownRoute(String),
+
+    #[error("Expectation Failed: {0}")]
+    ExpectationFailed(String),
+
+    #[error("Method Not Allowed: {0}")]
+    MethodNotAllowed(Method),
+
     #[error("Proxied Request Failed: {0}")]
     ProxiedRequestError(#[from] hyper::Error),
 }
@@ -51,9 +104,20 @@ pub enum ProxyError {
 #[derive(Clone)]
 pub struct Proxy {
     disabled: Arc<AtomicBool>,
+    started_at: Instant,
 
+    /// The primary Redis, which also holds everything that isn't a ratelimit key
     pub redis: Arc<ProxyRedisClient>,
-    pub http_client: Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>,
+    redis_shards: Arc<RedisShards>,
+    pub http_client: Client<HttpsConnector<EgressConnector>, Body>,
+    /// Caps the number of requests in flight to Discord, if configured
+    upstream_permits: Option<Arc<Semaphore>>,
+    /// Caps the number of requests probing new buckets while holding their lock, if configured
+    pub(crate) probe_permits: Option<Arc<Semaphore>>,
+
+    pub discord_buckets: Arc<RwLock<AHashMap<String, DiscordBucketMapping>>>,
+    pub response_cache: Option<Arc<ResponseCache>>,
+    coalescer: Option<Arc<RequestCoalescer>>,
 
     #[cfg(feature = "metrics")]
     pub metrics_last_reset_at: Arc<AtomicU64>,
@@ -66,37 +130,186 @@ impl Proxy {
         config: Arc<ProxyEnvConfig>,
         redis_config: Arc<RedisEnvConfig>,
     ) -> Result<Self, RedisError> {
-        let redis_client = ProxyRedisClient::new(redis_config).await?;
+        let redis_client = Arc::new(ProxyRedisClient::new(redis_config.clone()).await?);
+
+        let mut redis_shards = RedisShards::new(
+            format!("{}:{}", redis_config.host, redis_config.port),
+            redis_client.clone(),
+        );
+
+        if redis_config.sentinel && !redis_config.shards.is_empty() {
+            eprintln!("REDIS_SHARDS is not supported with Redis Sentinel, ignoring it.");
+        } else {
+            for (host, port, weight) in &redis_config.shards {
+                let shard_config = RedisEnvConfig {
+                    host: host.clone(),
+                    port: *port,
+                    replica_host: None,
+                    shards: Vec::new(),
+                    ..(*redis_config).clone()
+                };
+
+                redis_shards.add(
+                    format!("{}:{}", host, port),
+                    *weight,
+                    Arc::new(ProxyRedisClient::new(Arc::new(shard_config)).await?),
+                );
+            }
+        }
 
-        let mut http_connector = HttpConnector::new();
+        let mut http_connector = HttpConnector::new_with_resolver(OverrideResolver::new(
+            config.discord_resolve_overrides.clone(),
+        ));
         http_connector.enforce_http(false);
 
+        let egress_connector = EgressConnector::new(
+            http_connector,
+            config.discord_egress_proxy.clone(),
+            config.no_proxy.clone(),
+        );
+
         let builder = HttpsConnectorBuilder::new()
-            .with_webpki_roots()
-            .https_only()
-            .enable_http1();
+            .with_tls_config(build_tls_config(config.discord_tls_ca.as_deref()));
+
+        let builder = if config.discord_allow_http {
+            builder.https_or_http()
+        } else {
+            builder.https_only()
+        }
+        .enable_http1();
 
         let builder = if !config.disable_http2 {
-            builder.enable_http2().wrap_connector(http_connector)
+            builder.enable_http2().wrap_connector(egress_connector)
         } else {
-            builder.wrap_connector(http_connector)
+            builder.wrap_connector(egress_connector)
         };
 
-        Ok(Self {
+        let proxy = Self {
             disabled: Arc::new(AtomicBool::new(false)),
+            started_at: Instant::now(),
+
+            redis: redis_client,
+            redis_shards: Arc::new(redis_shards),
+            http_client: Client::builder()
+                .pool_max_idle_per_host(config.upstream_max_idle_per_host)
+                .build(builder),
+            upstream_permits: match config.upstream_max_in_flight {
+                0 => None,
+                permits => Some(Arc::new(Semaphore::new(permits))),
+            },
+            probe_permits: match config.max_concurrent_probes {
+                0 => None,
+                permits => Some(Arc::new(Semaphore::new(permits))),
+            },
 
-            redis: Arc::new(redis_client),
-            http_client: Client::builder().build(builder),
+            discord_buckets: Arc::new(RwLock::new(AHashMap::new())),
+            response_cache: config
+                .response_cache_ttl
+                .map(|ttl| Arc::new(ResponseCache::new(ttl, config.response_cache_max_entries))),
+            coalescer: config
+                .coalesce_requests
+                .then(|| Arc::new(RequestCoalescer::new())),
 
             #[cfg(feature = "metrics")]
             metrics_last_reset_at: Arc::new(AtomicU64::new(0)),
 
             config,
-        })
+        };
+
+        if proxy.config.use_discord_buckets {
+            proxy.start_discord_bucket_sweep();
+        }
+
+        if let Some(bucket_preload_file) = &proxy.config.bucket_preload_file {
+            proxy.preload_buckets(bucket_preload_file).await;
+        }
+
+        if let Some(token_validation_file) = proxy.config.token_validation_file.clone() {
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.validate_tokens(&token_validation_file).await });
+        }
+
+        Ok(proxy)
+    }
+
+    /// Returns the Redis a ratelimit key lives on.
+    pub fn redis_for(&self, key: &str) -> &Arc<ProxyRedisClient> {
+        self.redis_shards.for_key(key)
+    }
+
+    /// Returns every Redis ratelimit keys are spread across, starting with the primary.
+    pub fn redis_shards(&self) -> impl Iterator<Item = &Arc<ProxyRedisClient>> {
+        self.redis_shards.clients()
+    }
+
+    /// How long ago this proxy was created.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Whether the proxy is refusing to send requests to Discord.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Acquire)
     }
 
-    pub async fn handle_request(&self, req: http::Request<Body>) -> Response<Body> {
-        let res = match self.process(req).await {
+    pub async fn handle_request(&self, mut req: http::Request<Body>) -> Response<Body> {
+        let started_at = Instant::now();
+
+        if let Some(prefix) = &self.config.path_prefix_strip {
+            strip_path_prefix(&mut req, prefix);
+        }
+
+        let method = req.method().clone();
+        let client_ip = client_ip(&req, &self.config.trusted_proxies);
+
+        let mut in_flight = InFlightRequest {
+            client_ip,
+            ..Default::default()
+        };
+
+        let long_lived = is_long_lived(&self.config, &method, req.uri().path());
+        let deadline = request_deadline(&self.config, long_lived);
+
+        let result =
+            match within_deadline(deadline, self.process_coalesced(req, &mut in_flight)).await {
+                Some(result) => result,
+                None => {
+                    tracing::warn!(
+                        "Request exceeded the deadline of {:?}.",
+                        deadline.unwrap_or_default()
+                    );
+
+                    if let Some((route_bucket_redis_key, lock_token)) = &in_flight.held_route_lock {
+                        if let Err(err) = self
+                            .redis_for(route_bucket_redis_key)
+                            .release_route_lock(route_bucket_redis_key, lock_token)
+                            .await
+                        {
+                            tracing::error!(
+                                "Failed to release route lock after request deadline: {:?}",
+                                err
+                            );
+                        }
+                    }
+
+                    if let Some((global_id_redis_key, lock_token)) = &in_flight.held_global_lock {
+                        if let Err(err) = self
+                            .redis_for(global_id_redis_key)
+                            .abandon_global_lock(global_id_redis_key, lock_token)
+                            .await
+                        {
+                            tracing::error!(
+                                "Failed to release global lock after request deadline: {:?}",
+                                err
+                            );
+                        }
+                    }
+
+                    Ok(responses::deadline_exceeded())
+                }
+            };
+
+        let mut res = match result {
             Ok(response) => response,
             Err(err) => {
                 #[cfg(feature = "metrics")]
@@ -104,9 +317,30 @@ impl Proxy {
 
                 match err {
                     ProxyError::InvalidRequest(message) => responses::invalid_request(message),
+                    ProxyError::UnknownRoute(path) => {
+                        responses::not_found(format!("Unknown Route: {}", path))
+                    }
+                    ProxyError::ExpectationFailed(message) => {
+                        responses::expectation_failed(message)
+                    }
+                    ProxyError::MethodNotAllowed(method) => {
+                        responses::method_not_allowed(method.as_str())
+                    }
                     ProxyError::ProxiedRequestError(err) => {
-                        tracing::error!("Proxied Request Failed: {:?}", err);
-                        responses::internal_error()
+                        let kind = upstream_error_kind(&err);
+
+                        tracing::error!("Proxied Request Failed ({}): {:?}", kind, err);
+
+                        #[cfg(feature = "metrics")]
+                        metrics::PROXY_UPSTREAM_ERRORS
+                            .with_label_values(&[kind])
+                            .inc();
+
+                        match kind {
+                            "connect" | "reset" => responses::bad_gateway(),
+                            "timeout" => responses::deadline_exceeded(),
+                            _ => responses::internal_error(),
+                        }
                     }
                     _ => {
                         tracing::error!("Proxying Request Failed: {:?}", err);
@@ -116,10 +350,112 @@ impl Proxy {
             }
         };
 
-        return res;
+        // Responses to HEAD never carry a body, including the proxy's own error responses
+        if method == Method::HEAD {
+            *res.body_mut() = Body::empty();
+        }
+
+        if self.config.access_log {
+            let sent_by_proxy = res.headers().contains_key("x-sent-by-proxy");
+
+            tracing::info!(
+                method = method.as_str(),
+                client_ip = in_flight.client_ip.map(|ip| ip.to_string()).as_deref(),
+                global_id = in_flight.log_global_id.as_deref(),
+                route = in_flight.route_display_bucket.as_deref().unwrap_or(""),
+                decision = request_decision(res.headers(), res.status()),
+                upstream_status = (!sent_by_proxy).then(|| res.status().as_u16()),
+                latency_ms = started_at.elapsed().as_secs_f64() * 1000.0,
+                "Request handled."
+            );
+        }
+
+        if let (Some(threshold), false) = (self.config.slow_request_threshold, long_lived) {
+            let latency = started_at.elapsed();
+
+            if latency >= threshold {
+                let sent_by_proxy = res.headers().contains_key("x-sent-by-proxy");
+
+                tracing::warn!(
+                    global_id = in_flight.log_global_id.as_deref(),
+                    route = in_flight.route_display_bucket.as_deref().unwrap_or(""),
+                    upstream_status = (!sent_by_proxy).then(|| res.status().as_u16()),
+                    latency_ms = latency.as_secs_f64() * 1000.0,
+                    ratelimit_check_ms = in_flight
+                        .ratelimit_check_time
+                        .map(|time| time.as_secs_f64() * 1000.0),
+                    upstream_ms = in_flight
+                        .upstream_time
+                        .map(|time| time.as_secs_f64() * 1000.0),
+                    "Slow request."
+                );
+            }
+        }
+
+        res
     }
 
-    async fn process(&self, mut req: http::Request<Body>) -> Result<Response<Body>, ProxyError> {
+    /// Shares the response of identical GET requests that are in flight together, if enabled.
+    async fn process_coalesced(
+        &self,
+        req: http::Request<Body>,
+        in_flight: &mut InFlightRequest,
+    ) -> Result<Response<Body>, ProxyError> {
+        let (coalescer, key) = match (&self.coalescer, RequestCoalescer::key(&req)) {
+            (Some(coalescer), Some(key)) => (coalescer, key),
+            _ => return self.process(req, in_flight).await,
+        };
+
+        let leader = match coalescer.join(key) {
+            Coalesced::Leader(leader) => leader,
+            Coalesced::Follower(mut receiver) => {
+                // Followers never reach `process` if they're served, so they're described for logging here
+                if let Ok(request_info) = DiscordRequestInfo::new(
+                    req.method(),
+                    req.uri().path(),
+                    req.headers(),
+                    &self.config,
+                    in_flight.client_ip,
+                ) {
+                    in_flight.describe(&request_info);
+                }
+
+                match receiver.recv().await {
+                    Ok(shared) => {
+                        trace!("Serving response from an identical in-flight request.");
+                        return Ok(shared.to_response());
+                    }
+                    // The other request failed without a response, so this one goes ahead by itself
+                    Err(_) => return self.process(req, in_flight).await,
+                }
+            }
+        };
+
+        let response = self.process(req, in_flight).await?;
+
+        // The body only needs buffering if another request is waiting to share it
+        let sender = match leader.close() {
+            Some(sender) => sender,
+            None => return Ok(response),
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        let _ = sender.send(Arc::new(SharedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        }));
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    async fn process(
+        &self,
+        mut req: http::Request<Body>,
+        in_flight: &mut InFlightRequest,
+    ) -> Result<Response<Body>, ProxyError> {
         let span = trace_span!("process_request");
         let _guard = span.enter();
 
@@ -127,80 +463,383 @@ impl Proxy {
         let path = req.uri().path();
         let headers = req.headers();
 
-        let request_info = DiscordRequestInfo::new(&method, path, headers)?;
+        if !is_allowed_method(&method, headers) {
+            return Err(ProxyError::MethodNotAllowed(method));
+        }
+
+        if let Some(expect) = headers.get(EXPECT) {
+            let continues = expect.as_bytes().eq_ignore_ascii_case(b"100-continue");
+
+            if !continues || self.config.expect_continue == ExpectContinueStrategy::Reject {
+                return Err(ProxyError::ExpectationFailed(format!(
+                    "Unsupported expectation: {}",
+                    String::from_utf8_lossy(expect.as_bytes())
+                )));
+            }
+        }
+
+        if self.config.reject_unknown_routes && !is_known_resource(path) {
+            return Err(ProxyError::UnknownRoute(path.to_string()));
+        }
+
+        let mut request_info =
+            DiscordRequestInfo::new(&method, path, headers, &self.config, in_flight.client_ip)?;
+        in_flight.describe(&request_info);
+
+        let bypass_ratelimits = bypasses_ratelimits(&self.config, headers);
+
+        let route_rl_strategy = match headers
+            .get(BUCKET_STRATEGY_HEADER)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(_) if !self.config.allow_bucket_strategy_override => {
+                debug!("Bucket strategy override requested, but it is not allowed. Ignoring.");
+                self.config.route_rl_strategy.clone()
+            }
+            Some(value) => match value.parse::<NewBucketStrategy>() {
+                Ok(strategy) => strategy,
+                Err(_) => {
+                    return Err(ProxyError::InvalidRequest(format!(
+                        "Invalid {} header: {}, expected strict or loose",
+                        BUCKET_STRATEGY_HEADER, value
+                    )))
+                }
+            },
+            None => self.config.route_rl_strategy.clone(),
+        };
 
         #[cfg(feature = "metrics")]
         metrics::PROXY_REQUEST_COUNTER
-            .with_label_values(&[
-                request_info.global_id.as_str(),
-                request_info.route_display_bucket.as_str(),
-            ])
+            .with_label_values(&metrics::with_global_id(
+                &request_info.metrics_global_id,
+                &[request_info.route_display_bucket.as_str()],
+            ))
             .inc();
 
         drop(_guard);
 
-        let lock_token = match self.check_ratelimits(&request_info).await? {
-            Ok(lock_token) => lock_token,
-            Err(response) => {
+        let cache_key = match &self.response_cache {
+            Some(response_cache) => match ResponseCache::key(&req) {
+                Some(cache_key) => {
+                    if let Some(response) = response_cache.get(&cache_key).await {
+                        trace!("Serving response from cache.");
+
+                        #[cfg(feature = "metrics")]
+                        metrics::PROXY_REQUESTS_SERVED_LOCALLY
+                            .with_label_values(&metrics::with_global_id(
+                                &request_info.metrics_global_id,
+                                &[request_info.route_display_bucket.as_str(), "cache"],
+                            ))
+                            .inc();
+
+                        return Ok(response);
+                    }
+
+                    Some(cache_key)
+                }
+                None => None,
+            },
+            None => None,
+        };
+
+        let idempotency_key = match self.config.idempotency_ttl_ms {
+            Some(_) => idempotency::redis_key(&req, &request_info),
+            None => None,
+        };
+
+        if let Some(idempotency_key) = &idempotency_key {
+            if let Some(response) = idempotency::replay(&self.redis, idempotency_key).await? {
+                trace!("Replaying response for duplicate idempotency key.");
                 return Ok(response);
             }
+        }
+
+        // Checked before the ratelimits, so the request neither uses up quota nor holds locks it won't use
+        if self.disabled.load(Ordering::Acquire) {
+            return Ok(responses::disabled(self.config.disabled_retry_after_secs));
+        }
+
+        if self.config.use_discord_buckets {
+            self.resolve_discord_bucket(&mut request_info).await;
+        }
+
+        let (lock_token, quota) = if bypass_ratelimits {
+            trace!("Bypassing ratelimit checks.");
+            (None, None)
+        } else {
+            let ratelimit_check_started_at = Instant::now();
+            let checked = self
+                .check_ratelimits(
+                    &request_info,
+                    &route_rl_strategy,
+                    &mut in_flight.held_global_lock,
+                )
+                .await;
+            in_flight.ratelimit_check_time = Some(ratelimit_check_started_at.elapsed());
+
+            match checked? {
+                Ok(allowed) => allowed,
+                Err(response) => {
+                    return Ok(response);
+                }
+            }
         };
 
+        if let Some(lock_token) = &lock_token {
+            in_flight.held_route_lock = Some((
+                request_info.route_bucket_redis_key.clone(),
+                lock_token.clone(),
+            ));
+        }
+
+        let caller_accepts_gzip = compression::accepts_gzip(req.headers());
+
         let headers = req.headers_mut();
 
-        headers.insert("Host", HeaderValue::from_static("discord.com"));
+        if self.config.upstream_gzip {
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+
+        headers.insert(
+            "Host",
+            HeaderValue::from_str(&self.config.discord_api_host)
+                .expect("Invalid Discord API host."),
+        );
         headers.insert(
             "User-Agent",
             HeaderValue::from_static("limbo-labs/discord-api-proxy/1.2"),
         );
 
-        // Remove HTTP2 headers
+        // Remove hop-by-hop headers
         headers.remove(CONNECTION);
         headers.remove("keep-alive");
         headers.remove("proxy-connection");
-        headers.remove(TRANSFER_ENCODING);
         headers.remove(UPGRADE);
 
+        // Hyper sends the caller its 100 Continue when the body is first read, which is only once the request is on its
+        // way to Discord. Its client never hands interim responses back, so Discord mustn't be asked for one.
+        headers.remove(EXPECT);
+
+        // HTTP/2 rejects Transfer-Encoding, but HTTP/1.1 needs it to forward chunked bodies as-is
+        if !self.config.disable_http2 {
+            headers.remove(TRANSFER_ENCODING);
+        }
+
+        headers.remove(BYPASS_RATELIMITS_HEADER);
+        headers.remove(BUCKET_STRATEGY_HEADER);
+
+        encode_audit_log_reason(headers);
+
         let path_and_query = match req.uri().path_and_query() {
             Some(path_and_query) => path_and_query.as_str(),
             None => "/",
         };
 
-        *req.uri_mut() = Uri::from_str(&format!("https://discord.com{}", path_and_query))
-            .expect("Failed to rebuild URI.");
+        *req.uri_mut() = Uri::from_str(&format!(
+            "{}{}",
+            self.config.discord_api_url,
+            with_api_version(path_and_query, &self.config.discord_api_version)
+        ))
+        .expect("Failed to rebuild URI.");
 
-        if self.disabled.load(Ordering::Acquire) {
-            return Ok(responses::overloaded());
+        if self.config.shadow_mode {
+            trace!(
+                ?lock_token,
+                "Shadow mode enabled, not sending request to Discord."
+            );
+
+            let response = responses::shadowed(&request_info.route_bucket);
+
+            if !bypass_ratelimits {
+                self.process_response(
+                    response.status(),
+                    response.headers(),
+                    &request_info,
+                    lock_token,
+                )
+                .await?;
+            }
+
+            return Ok(response);
         }
 
         #[cfg(feature = "metrics")]
         metrics::DISCORD_REQUEST_COUNTER
-            .with_label_values(&[
-                request_info.global_id.as_str(),
-                request_info.route_display_bucket.as_str(),
-            ])
+            .with_label_values(&metrics::with_global_id(
+                &request_info.metrics_global_id,
+                &[request_info.route_display_bucket.as_str()],
+            ))
             .inc();
 
+        #[cfg(feature = "metrics")]
+        metrics::observe_body_size(
+            &metrics::DISCORD_REQUEST_BODY_SIZES,
+            &request_info.route_display_bucket,
+            req.headers(),
+        );
+
         trace!(?lock_token, "Sending request to Discord.");
 
-        #[cfg(feature = "metrics")]
         let discord_request_sent_at = Instant::now();
 
-        let response = self.http_client.request(req).await?;
+        if let (Some(idempotency_key), Some(idempotency_ttl_ms)) =
+            (&idempotency_key, self.config.idempotency_ttl_ms)
+        {
+            // Another request with the same key may have been sent off while this one was checking ratelimits
+            if !self
+                .redis
+                .claim_idempotency_key(idempotency_key, idempotency_ttl_ms)
+                .await?
+            {
+                if let Some(lock_token) = &lock_token {
+                    self.redis_for(&request_info.route_bucket_redis_key)
+                        .release_route_lock(&request_info.route_bucket_redis_key, lock_token)
+                        .await?;
+                }
+
+                return Ok(idempotency::replay(&self.redis, idempotency_key)
+                    .await?
+                    .unwrap_or_else(responses::idempotency_conflict));
+            }
+        }
+
+        // A bucket's lock holder is the only request that can learn its ratelimit, so a burst of new buckets queues here instead of all hitting Discord at once
+        let probe_permit = match (&self.probe_permits, &lock_token) {
+            (Some(probe_permits), Some(_)) => Some(
+                probe_permits
+                    .acquire()
+                    .await
+                    .expect("Probe semaphore closed."),
+            ),
+            _ => None,
+        };
+
+        let upstream_permit = match &self.upstream_permits {
+            Some(upstream_permits) => Some(
+                upstream_permits
+                    .acquire()
+                    .await
+                    .expect("Upstream semaphore closed."),
+            ),
+            None => None,
+        };
+
+        #[cfg(feature = "metrics")]
+        let in_flight_gauge = metrics::GaugeGuard::inc(&metrics::DISCORD_REQUESTS_IN_FLIGHT);
+
+        let result = self.http_client.request(req).await;
+        in_flight.upstream_time = Some(discord_request_sent_at.elapsed());
+
+        #[cfg(feature = "metrics")]
+        drop(in_flight_gauge);
+
+        drop(upstream_permit);
+
+        let mut response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                if let Some(idempotency_key) = &idempotency_key {
+                    self.redis.delete_idempotency_key(idempotency_key).await?;
+                }
+
+                // The bucket's limit is still unknown, so the next request waiting on it gets to probe it instead
+                if let Some(lock_token) = &lock_token {
+                    self.redis_for(&request_info.route_bucket_redis_key)
+                        .release_route_lock(&request_info.route_bucket_redis_key, lock_token)
+                        .await?;
+                }
+
+                return Err(err.into());
+            }
+        };
 
         let status = response.status();
 
         #[cfg(feature = "metrics")]
         metrics::DISCORD_REQUEST_RESPONSE_TIMES
-            .with_label_values(&[
-                request_info.global_id.as_str(),
-                request_info.route_display_bucket.as_str(),
-                status.as_str(),
-            ])
+            .with_label_values(&metrics::with_global_id(
+                &request_info.metrics_global_id,
+                &[request_info.route_display_bucket.as_str(), status.as_str()],
+            ))
             .observe(discord_request_sent_at.elapsed().as_secs_f64());
 
-        self.process_response(status, response.headers(), &request_info, lock_token)
+        #[cfg(feature = "metrics")]
+        metrics::observe_body_size(
+            &metrics::DISCORD_RESPONSE_BODY_SIZES,
+            &request_info.route_display_bucket,
+            response.headers(),
+        );
+
+        if !bypass_ratelimits {
+            self.process_response(status, response.headers(), &request_info, lock_token)
+                .await?;
+        }
+
+        drop(probe_permit);
+
+        let response_headers = response.headers_mut();
+
+        for header_name in &self.config.response_header_strip {
+            response_headers.remove(header_name);
+        }
+
+        response_headers.insert(
+            "x-proxy-version",
+            HeaderValue::from_static(responses::PROXY_VERSION),
+        );
+
+        if let (true, Some(quota)) = (self.config.expose_proxy_ratelimit_headers, quota) {
+            if let Ok(bucket) = HeaderValue::from_str(&request_info.route_bucket) {
+                response_headers.insert("x-proxy-ratelimit-bucket", bucket);
+            }
+
+            response_headers.insert("x-proxy-ratelimit-limit", quota.limit.into());
+            response_headers.insert("x-proxy-ratelimit-remaining", quota.remaining.into());
+        }
+
+        if self.config.upstream_gzip || self.config.gzip_responses {
+            response = compression::encode_for_caller(
+                response,
+                caller_accepts_gzip,
+                self.config.gzip_responses,
+            )
             .await?;
+        }
+
+        if let (Some(response_cache), Some(cache_key)) = (&self.response_cache, cache_key) {
+            if status.is_success() {
+                let (parts, body) = response.into_parts();
+                let body = hyper::body::to_bytes(body).await?;
+
+                response_cache
+                    .insert(cache_key, parts.status, &parts.headers, body.clone())
+                    .await;
+
+                response = Response::from_parts(parts, Body::from(body));
+            }
+        }
+
+        if let (Some(idempotency_key), Some(idempotency_ttl_ms)) =
+            (idempotency_key, self.config.idempotency_ttl_ms)
+        {
+            // Failed requests can safely be retried, so their key is freed up again
+            if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                self.redis.delete_idempotency_key(&idempotency_key).await?;
+            } else {
+                let (parts, body) = response.into_parts();
+                let body = hyper::body::to_bytes(body).await?;
+
+                self.redis
+                    .set_idempotency_key(
+                        &idempotency_key,
+                        idempotency::serialize_response(parts.status, &parts.headers, &body),
+                        idempotency_ttl_ms,
+                    )
+                    .await?;
+
+                response = Response::from_parts(parts, Body::from(body));
+            }
+        }
 
         Ok(response)
     }
@@ -212,29 +851,38 @@ impl Proxy {
         request_info: &DiscordRequestInfo,
         lock_token: Option<String>,
     ) -> Result<(), ProxyError> {
-        if status == StatusCode::TOO_MANY_REQUESTS {
-            self.handle_429(request_info, headers).await;
+        if status == StatusCode::TOO_MANY_REQUESTS && self.handle_429(request_info, headers).await {
+            // Shared ratelimits belong to the resource, not the bot, so they shouldn't overwrite what we've learned for the route bucket
+            if let Some(lock_token) = lock_token {
+                if !self
+                    .redis_for(&request_info.route_bucket_redis_key)
+                    .release_route_lock(&request_info.route_bucket_redis_key, &lock_token)
+                    .await?
+                {
+                    tracing::debug!("Lock expired before we could release it after a shared 429.");
+                }
+            }
+
+            return Ok(());
         }
 
-        self.update_ratelimits(headers, &request_info, lock_token)
+        self.update_ratelimits(headers, request_info, lock_token)
             .await?;
 
         Ok(())
     }
 
-    async fn handle_429(&self, _request_info: &DiscordRequestInfo, headers: &HeaderMap) {
-        let is_shared_ratelimit = headers
-            .get("X-RateLimit-Scope")
-            .map(|v| v == "shared")
-            .unwrap_or(false);
+    /// Returns true if the 429 was caused by a shared ratelimit.
+    async fn handle_429(&self, _request_info: &DiscordRequestInfo, headers: &HeaderMap) -> bool {
+        let is_shared_ratelimit = is_shared_ratelimit(headers);
 
         if is_shared_ratelimit {
             #[cfg(feature = "metrics")]
             metrics::DISCORD_REQUEST_SHARED_429
-                .with_label_values(&[
-                    _request_info.global_id.as_str(),
-                    _request_info.route_display_bucket.as_str(),
-                ])
+                .with_label_values(&metrics::with_global_id(
+                    &_request_info.metrics_global_id,
+                    &[_request_info.route_display_bucket.as_str()],
+                ))
                 .inc();
 
             tracing::debug!("Discord returned Shared 429!");
@@ -247,14 +895,17 @@ impl Proxy {
             #[cfg(feature = "metrics")]
             if is_global {
                 metrics::DISCORD_REQUEST_GLOBAL_429
-                    .with_label_values(&[_request_info.global_id.as_str()])
+                    .with_label_values(&metrics::with_global_id(
+                        &_request_info.metrics_global_id,
+                        &[],
+                    ))
                     .inc();
             } else {
                 metrics::DISCORD_REQUEST_ROUTE_429
-                    .with_label_values(&[
-                        _request_info.global_id.as_str(),
-                        _request_info.route_display_bucket.as_str(),
-                    ])
+                    .with_label_values(&metrics::with_global_id(
+                        &_request_info.metrics_global_id,
+                        &[_request_info.route_display_bucket.as_str()],
+                    ))
                     .inc();
             }
 
@@ -264,5 +915,553 @@ impl Proxy {
                 headers.get("X-RateLimit-Scope"),
             );
         }
+
+        is_shared_ratelimit
+    }
+}
+
+fn build_tls_config(ca_path: Option<&str>) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    if let Some(ca_path) = ca_path {
+        let ca_file = File::open(ca_path).expect("Failed to open DISCORD_TLS_CA.");
+        let certs = rustls_pemfile::certs(&mut BufReader::new(ca_file))
+            .expect("Failed to parse DISCORD_TLS_CA.");
+
+        for cert in certs {
+            roots
+                .add(&Certificate(cert))
+                .expect("Invalid certificate in DISCORD_TLS_CA.");
+        }
+    }
+
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// Percent-encodes raw UTF-8 audit log reasons, as Discord expects them URL-encoded. ASCII reasons are taken to be
+/// encoded already and forwarded as-is, while a raw reason's `%` is encoded too so Discord doesn't decode it.
+fn encode_audit_log_reason(headers: &mut HeaderMap) {
+    let reason = match headers.get(AUDIT_LOG_REASON_HEADER) {
+        Some(reason)
+            if !reason
+                .as_bytes()
+                .iter()
+                .all(|b| b.is_ascii() && !b.is_ascii_control()) =>
+        {
+            reason.as_bytes()
+        }
+        _ => return,
+    };
+
+    let mut encoded = String::with_capacity(reason.len() * 3);
+    for byte in reason {
+        if byte.is_ascii() && !byte.is_ascii_control() && *byte != b'%' {
+            encoded.push(*byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    headers.insert(
+        AUDIT_LOG_REASON_HEADER,
+        HeaderValue::from_str(&encoded).expect("Encoded audit log reason is not a valid header."),
+    );
+}
+
+/// Removes a mount point from the start of a request's path, so it's bucketed and forwarded as if sent to `/api`.
+fn strip_path_prefix(req: &mut http::Request<Body>, prefix: &str) {
+    let stripped = match req
+        .uri()
+        .path_and_query()
+        .and_then(|path_and_query| path_and_query.as_str().strip_prefix(prefix))
+    {
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        _ => return,
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match stripped.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return,
+    };
+
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+}
+
+/// Pins requests that don't specify an API version to the configured one, rather than Discord's default
+fn with_api_version<'a>(path_and_query: &'a str, api_version: &str) -> Cow<'a, str> {
+    match path_and_query.strip_prefix("/api/") {
+        Some(rest) if !is_api_version(rest.split(['/', '?']).next().unwrap_or("")) => {
+            Cow::Owned(format!("/api/{}/{}", api_version, rest))
+        }
+        _ => Cow::Borrowed(path_and_query),
+    }
+}
+
+/// Discord's REST API only accepts these methods, plus OPTIONS for CORS preflights
+fn is_allowed_method(method: &Method, headers: &HeaderMap) -> bool {
+    match *method {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::PATCH
+        | Method::DELETE => true,
+        Method::OPTIONS => headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD),
+        _ => false,
+    }
+}
+
+/// Classifies why a request to Discord failed, so connection problems can be told apart from the proxy's own errors
+fn upstream_error_kind(err: &hyper::Error) -> &'static str {
+    if err.is_connect() {
+        "connect"
+    } else if err.is_timeout() {
+        "timeout"
+    } else if err.is_closed() || err.is_incomplete_message() || err.is_canceled() {
+        "reset"
+    } else {
+        "other"
+    }
+}
+
+/// Whether a request asked to skip ratelimit checks, and is allowed to.
+fn bypasses_ratelimits(config: &ProxyEnvConfig, headers: &HeaderMap) -> bool {
+    let requested = headers
+        .get(BYPASS_RATELIMITS_HEADER)
+        .map(|v| v == "true")
+        .unwrap_or(false);
+
+    if requested && !config.allow_ratelimit_bypass {
+        debug!("Ratelimit bypass requested, but it is not allowed. Ignoring.");
+        return false;
+    }
+
+    requested
+}
+
+/// Shared ratelimits belong to the resource rather than the bot, so their 429s say nothing about the route bucket.
+fn is_shared_ratelimit(headers: &HeaderMap) -> bool {
+    headers
+        .get("X-RateLimit-Scope")
+        .map(|v| v == "shared")
+        .unwrap_or(false)
+}
+
+/// Long-lived routes are slow on purpose, so they're exempt from the deadline and slow request logging
+fn is_long_lived(config: &ProxyEnvConfig, method: &Method, path: &str) -> bool {
+    !config.long_lived_routes.is_empty()
+        && route_display_bucket(method, path)
+            .is_some_and(|route| config.is_long_lived_route(&route))
+}
+
+fn request_deadline(config: &ProxyEnvConfig, long_lived: bool) -> Option<Duration> {
+    config.request_deadline.filter(|_| !long_lived)
+}
+
+/// Runs a request to completion, or gives up on it with `None` once its deadline has passed.
+async fn within_deadline<F: Future>(deadline: Option<Duration>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => timeout(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Summarizes what the proxy did with a request for the access log
+fn request_decision(headers: &HeaderMap, status: StatusCode) -> &'static str {
+    if headers.contains_key(CACHE_HEADER) {
+        return "cached";
+    }
+
+    if !headers.contains_key("x-sent-by-proxy") {
+        return "allowed";
+    }
+
+    match status.as_u16() {
+        200 => "shadowed",
+        400 | 405 => "invalid",
+        502 => "upstream_error",
+        429 => "ratelimited",
+        503 => "overloaded",
+        504 => "deadline_exceeded",
+        _ => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fred::prelude::KeysInterface;
+    use http::header::AUTHORIZATION;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{config::AppEnvConfig, redis::RatelimitCheckArgs};
+
+    /// Points the config at a stand-in for Discord that only counts the connections made to it
+    async fn counting_upstream(config: &mut ProxyEnvConfig) -> Arc<AtomicUsize> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.discord_api_url = format!("http://{}", listener.local_addr().unwrap());
+        config.discord_allow_http = true;
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        tokio::spawn(async move {
+            while listener.accept().await.is_ok() {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        connections
+    }
+
+    /// A request from a bot Redis hasn't seen yet, so its global limit is unknown too
+    fn new_bot_request(path: &str) -> http::Request<Body> {
+        let bot_id = 1_000_000_000_000_000_000u64 + rand::random::<u32>() as u64;
+
+        http::Request::builder()
+            .uri(path)
+            .header(
+                AUTHORIZATION,
+                format!(
+                    "Bot {}.GhIjKl.aSecretPartOfTheToken",
+                    base64_simd::STANDARD_NO_PAD.encode_to_string(bot_id.to_string())
+                ),
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn scoped_429_headers(scope: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-RateLimit-Scope", HeaderValue::from_static(scope));
+        headers
+    }
+
+    fn encoded_reason(reason: &[u8]) -> HeaderValue {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUDIT_LOG_REASON_HEADER,
+            HeaderValue::from_bytes(reason).unwrap(),
+        );
+
+        encode_audit_log_reason(&mut headers);
+        headers[AUDIT_LOG_REASON_HEADER].clone()
+    }
+
+    #[test]
+    fn ascii_audit_log_reasons_are_forwarded_as_is() {
+        for reason in ["Spamming", "Already%20encoded", "100%"] {
+            assert_eq!(encoded_reason(reason.as_bytes()), reason);
+        }
+
+        let mut headers = HeaderMap::new();
+        encode_audit_log_reason(&mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn utf8_audit_log_reasons_are_percent_encoded() {
+        assert_eq!(encoded_reason("Caf\u{e9}".as_bytes()), "Caf%C3%A9");
+        assert_eq!(
+            encoded_reason("100% \u{2713}".as_bytes()),
+            "100%25 %E2%9C%93"
+        );
+    }
+
+    #[test]
+    fn only_shared_scope_429s_are_shared() {
+        assert!(is_shared_ratelimit(&scoped_429_headers("shared")));
+        assert!(!is_shared_ratelimit(&scoped_429_headers("user")));
+        assert!(!is_shared_ratelimit(&scoped_429_headers("global")));
+        assert!(!is_shared_ratelimit(&HeaderMap::new()));
+    }
+
+    /// The first request to a new route bucket, holding the bucket's lock while it learns the bucket's limit
+    async fn probe_new_bucket(proxy: &Proxy) -> (DiscordRequestInfo, String) {
+        let path = format!(
+            "/api/v10/channels/{}/messages",
+            1_000_000_000_000_000_000u64 + rand::random::<u32>() as u64
+        );
+        let request_info =
+            DiscordRequestInfo::new(&Method::GET, &path, &HeaderMap::new(), &proxy.config, None)
+                .unwrap();
+        let key = &request_info.route_bucket_redis_key;
+
+        let lock_token = "lock-token".to_string();
+        let reply = proxy
+            .redis_for(key)
+            .check_route_rl(
+                key,
+                &RatelimitCheckArgs {
+                    lock_token: &lock_token,
+                    cost: 1,
+                    lock_ttl_secs: 5,
+                    global_loose_limit: 0,
+                    route_loose_limit: 0,
+                    route_safety_margin: 0,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply[2], "1");
+
+        (request_info, lock_token)
+    }
+
+    /// A 429 for a bucket of 5 requests a second
+    fn route_429_headers(scope: &'static str) -> HeaderMap {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let reset = format!("{}.{:03}", now / 1000 + 1, now % 1000);
+
+        let mut headers = scoped_429_headers(scope);
+        headers.insert("X-RateLimit-Limit", HeaderValue::from_static("5"));
+        headers.insert("X-RateLimit-Remaining", HeaderValue::from_static("0"));
+        headers.insert("X-RateLimit-Reset", HeaderValue::from_str(&reset).unwrap());
+        headers.insert("X-RateLimit-Reset-After", HeaderValue::from_static("1.000"));
+        headers
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn shared_429s_release_the_lock_without_learning_the_bucket() {
+        let app_config = AppEnvConfig::from_env();
+        let proxy = Proxy::new(app_config.proxy.clone(), app_config.redis.clone())
+            .await
+            .unwrap();
+
+        for (scope, learns_bucket) in [("shared", false), ("user", true)] {
+            let (request_info, lock_token) = probe_new_bucket(&proxy).await;
+            let key = &request_info.route_bucket_redis_key;
+            let redis = proxy.redis_for(key);
+
+            proxy
+                .process_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &route_429_headers(scope),
+                    &request_info,
+                    Some(lock_token),
+                )
+                .await
+                .unwrap();
+
+            let limit: Option<String> = redis.pool.get(key.as_str()).await.unwrap();
+            assert_eq!(limit.is_some(), learns_bucket, "{} 429", scope);
+
+            let lock: Option<String> = redis.pool.get(format!("{}:lock", key)).await.unwrap();
+            assert_eq!(lock, None, "{} 429 should release the lock", scope);
+        }
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn route_429s_stay_closed_for_their_retry_after() {
+        let app_config = AppEnvConfig::from_env();
+        let proxy = Proxy::new(app_config.proxy.clone(), app_config.redis.clone())
+            .await
+            .unwrap();
+
+        let (request_info, lock_token) = probe_new_bucket(&proxy).await;
+        let key = &request_info.route_bucket_redis_key;
+        let redis = proxy.redis_for(key);
+
+        // Discord's reset headers say a second, but its Retry-After says half a minute
+        let mut headers = route_429_headers("user");
+        headers.insert("Retry-After", HeaderValue::from_static("30"));
+
+        proxy
+            .process_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                &headers,
+                &request_info,
+                Some(lock_token),
+            )
+            .await
+            .unwrap();
+
+        for suffix in [":count", ":reset_after"] {
+            let ttl: i64 = redis.pool.pttl(format!("{}{}", key, suffix)).await.unwrap();
+            assert!(ttl > 25_000, "{} expires in {}ms", suffix, ttl);
+        }
+
+        let reply = redis
+            .check_route_rl(
+                key,
+                &RatelimitCheckArgs {
+                    lock_token: "next",
+                    cost: 1,
+                    lock_ttl_secs: 5,
+                    global_loose_limit: 0,
+                    route_loose_limit: 0,
+                    route_safety_margin: 0,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply[..2], ["2", "5"]);
+    }
+
+    #[test]
+    fn ratelimits_are_only_bypassed_when_allowed() {
+        let bypass_headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(BYPASS_RATELIMITS_HEADER, HeaderValue::from_static(value));
+            headers
+        };
+
+        let mut config = (*AppEnvConfig::from_env().proxy).clone();
+
+        config.allow_ratelimit_bypass = false;
+        assert!(!bypasses_ratelimits(&config, &bypass_headers("true")));
+        assert!(!bypasses_ratelimits(&config, &HeaderMap::new()));
+
+        config.allow_ratelimit_bypass = true;
+        assert!(bypasses_ratelimits(&config, &bypass_headers("true")));
+        assert!(!bypasses_ratelimits(&config, &bypass_headers("false")));
+        assert!(!bypasses_ratelimits(&config, &bypass_headers("1")));
+        assert!(!bypasses_ratelimits(&config, &HeaderMap::new()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_lived_routes_outlast_the_deadline() {
+        let mut config = (*AppEnvConfig::from_env().proxy).clone();
+        config.request_deadline = Some(Duration::from_millis(500));
+        config.long_lived_routes = vec!["gateway/bot".to_string()];
+
+        let deadline =
+            |path: &str| request_deadline(&config, is_long_lived(&config, &Method::GET, path));
+
+        // Stands in for a response Discord takes its time with
+        let slow_response = || tokio::time::sleep(Duration::from_secs(60));
+
+        assert!(
+            within_deadline(deadline("/api/v10/gateway/bot"), slow_response())
+                .await
+                .is_some()
+        );
+        assert!(
+            within_deadline(deadline("/api/v10/users/@me"), slow_response())
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_ends_a_retry_storm() {
+        let started_at = tokio::time::Instant::now();
+
+        // Stands in for a request whose ratelimit checks keep asking to be retried
+        let retry_storm = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+
+        assert!(
+            within_deadline(Some(Duration::from_millis(500)), retry_storm)
+                .await
+                .is_none()
+        );
+        assert_eq!(started_at.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_deadline_run_to_completion() {
+        assert_eq!(within_deadline(None, async { 1 }).await, Some(1));
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn shadow_mode_never_contacts_discord() {
+        let app_config = AppEnvConfig::from_env();
+        let mut config = (*app_config.proxy).clone();
+        let connections = counting_upstream(&mut config).await;
+
+        config.shadow_mode = true;
+        let shadowed = Proxy::new(Arc::new(config.clone()), app_config.redis.clone())
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            let response = shadowed
+                .handle_request(new_bot_request(
+                    "/api/v10/channels/1000000000000000001/messages",
+                ))
+                .await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().contains_key("x-ratelimit-bucket"));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+
+        // The same request is sent off when shadow mode is off
+        config.shadow_mode = false;
+        let forwarding = Proxy::new(Arc::new(config), app_config.redis.clone())
+            .await
+            .unwrap();
+        forwarding
+            .handle_request(new_bot_request(
+                "/api/v10/channels/1000000000000000001/messages",
+            ))
+            .await;
+        assert!(connections.load(Ordering::SeqCst) > 0);
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn bypassed_requests_leave_ratelimits_alone() {
+        let app_config = AppEnvConfig::from_env();
+        let mut config = (*app_config.proxy).clone();
+        counting_upstream(&mut config).await;
+
+        for allowed in [true, false] {
+            config.allow_ratelimit_bypass = allowed;
+            let proxy = Proxy::new(Arc::new(config.clone()), app_config.redis.clone())
+                .await
+                .unwrap();
+
+            let mut req = new_bot_request("/api/v10/channels/1000000000000000001/messages");
+            req.headers_mut()
+                .insert(BYPASS_RATELIMITS_HEADER, HeaderValue::from_static("true"));
+
+            let request_info = DiscordRequestInfo::new(
+                req.method(),
+                req.uri().path(),
+                req.headers(),
+                &proxy.config,
+                None,
+            )
+            .unwrap();
+            let key = &request_info.route_bucket_redis_key;
+
+            proxy.handle_request(req).await;
+
+            let count: Option<String> = proxy
+                .redis_for(key)
+                .pool
+                .get(format!("{}:count", key))
+                .await
+                .unwrap();
+            assert_eq!(count.is_none(), allowed, "bypass allowed: {}", allowed);
+        }
     }
 }
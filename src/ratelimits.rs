@@ -1,16 +1,22 @@
 use core::fmt;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use axum::response::Response;
-use fred::prelude::RedisError;
+use fred::prelude::{RedisError, RedisErrorKind};
 use hyper::{Body, HeaderMap};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::{select, time::Instant, try_join};
-use tracing::{debug, error, trace, warn};
+use serde::Serialize;
+use tokio::{join, select, time::Instant};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
     buckets::Resources,
+    config::{NewBucketStrategy, RouteLockWaitStrategy},
     proxy::{Proxy, ProxyError},
+    redis::{LockError, RatelimitCheckArgs, RouteExpiryArgs},
     request::DiscordRequestInfo,
     responses,
 };
@@ -18,16 +24,64 @@ use crate::{
 #[cfg(feature = "metrics")]
 use crate::metrics;
 
-#[derive(PartialEq, Debug)]
+const DISCORD_BUCKET_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Keeps buckets with very short reset windows from expiring between two bursts of use
+const MIN_IDLE_BUCKET_TTL_MS: u64 = 60_000;
+
+/// Route buckets without a Discord bucket are only looked up in Redis again after this long, so they don't cost a round
+/// trip on every request
+const UNMAPPED_DISCORD_BUCKET_TTL: Duration = Duration::from_secs(10);
+
+/// A Discord bucket hash learned for a route bucket, cached locally until it expires. Route buckets Redis had no hash
+/// for are cached too, without one.
+pub struct DiscordBucketMapping {
+    pub bucket_hash: Option<String>,
+    pub expires_at: Option<Instant>,
+}
+
+impl DiscordBucketMapping {
+    fn new(bucket_hash: Option<&str>, bucket_ttl_ms: u64) -> Self {
+        let expires_at = match (bucket_hash, bucket_ttl_ms) {
+            (None, _) => Some(Instant::now() + UNMAPPED_DISCORD_BUCKET_TTL),
+            (Some(_), 0) => None,
+            (Some(_), ttl) => Some(Instant::now() + Duration::from_millis(ttl)),
+        };
+
+        Self {
+            bucket_hash: bucket_hash.map(str::to_string),
+            expires_at,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= Instant::now())
+            .unwrap_or(false)
+    }
+}
+
+/// Why a ratelimit check has to be run again before the request can be sent
+#[derive(PartialEq, Debug, Serialize)]
+#[serde(tag = "cause", rename_all = "snake_case")]
 pub enum RatelimitRetryCause {
     AwaitingGlobalLock,
     AwaitingRouteLock,
     HoldingGlobalLockAwaitingRouteLock,
-    GlobalRatelimitDrifted,
-    ProxyOverloaded { retry_count: u8 },
+    GlobalRatelimitDrifted {
+        check_time: u128,
+    },
+    ProxyOverloaded {
+        retry_count: u8,
+    },
+    /// A negative or unreadable request counter was found and reset
+    CounterReset {
+        counter: &'static str,
+    },
 }
 
-#[derive(PartialEq, Debug)]
+/// The outcome of a single ratelimit check, as parsed from the check scripts' replies
+#[derive(PartialEq, Debug, Serialize)]
+#[serde(tag = "decision", content = "details", rename_all = "snake_case")]
 pub enum RatelimitStatus {
     ProxyOverloaded,
     RequiresRetry(RatelimitRetryCause),
@@ -44,11 +98,46 @@ pub enum RatelimitStatus {
     Allowed {
         holds_global_lock: bool,
         holds_route_lock: bool,
+        quota: Option<ProxyQuota>,
     },
 }
 
+/// The proxy's own view of a route bucket after letting a request through
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub struct ProxyQuota {
+    pub limit: u16,
+    pub remaining: u16,
+}
+
 impl RatelimitStatus {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, RatelimitStatus::Allowed { .. })
+    }
+
+    /// True for both global and route ratelimits, the proxy answers these with its own 429
+    pub fn is_ratelimited(&self) -> bool {
+        matches!(
+            self,
+            RatelimitStatus::GlobalRatelimited { .. } | RatelimitStatus::RouteRatelimited { .. }
+        )
+    }
+
+    pub fn retry_cause(&self) -> Option<&RatelimitRetryCause> {
+        match self {
+            RatelimitStatus::RequiresRetry(cause) => Some(cause),
+            _ => None,
+        }
+    }
+
+    /// Parses the reply of a ratelimit check script, `data` being the reply's elements as strings.
+    ///
+    /// The scripts share status codes but not reply lengths: `1`, `3` and `4` are bare codes, `0` adds the global limit,
+    /// `2` adds the route limit, reset time and reset after, and `6` adds which counter was reset. `5` adds whether
+    /// the global and route locks were taken, and the check scripts that look at a route bucket also add its limit and
+    /// count. Lua's `false` comes back as `nil` and a reply can be cut short, so every field past the code is optional.
+    /// The limit scripts return is Discord's, `route_safety_margin` is taken off it for the quota like they do.
     pub fn from(
+        route_safety_margin: u16,
         overload_count: u8,
         check_started_at_timestamp: Duration,
         check_started_at: Instant,
@@ -70,16 +159,31 @@ impl RatelimitStatus {
         }
 
         if curr_time >= global_slice_reset_at {
-            return RatelimitStatus::RequiresRetry(RatelimitRetryCause::GlobalRatelimitDrifted);
+            return RatelimitStatus::RequiresRetry(RatelimitRetryCause::GlobalRatelimitDrifted {
+                check_time,
+            });
         }
 
         debug!(?data, "Ratelimit check response: {:#?}", data);
 
-        let status_code = data[0].parse::<u8>().unwrap();
+        // Only possible if the scripts in Redis don't match this build, which no amount of retrying fixes
+        let status_code = match reply_field::<u8>(&data, 0) {
+            Some(status_code) => status_code,
+            None => {
+                error!(data = ?data, "Invalid ratelimit check reply.");
+
+                return RatelimitStatus::ProxyOverloaded;
+            }
+        };
+
         match status_code {
             0 => {
                 let reset_after = (global_slice_reset_at - curr_time) as u64;
-                let limit = data[1].parse::<u16>().unwrap();
+                let limit = reply_field::<u16>(&data, 1).unwrap_or_else(|| {
+                    error!(data = ?data, "Failed to parse global limit, defaulting to 0.");
+
+                    0
+                });
 
                 RatelimitStatus::GlobalRatelimited {
                     limit,
@@ -89,17 +193,25 @@ impl RatelimitStatus {
             }
             1 => RatelimitStatus::RequiresRetry(RatelimitRetryCause::AwaitingGlobalLock),
             2 => {
-                let limit = data[1].parse::<u16>().unwrap();
+                let limit = reply_field::<u16>(&data, 1).unwrap_or_else(|| {
+                    error!(data = ?data, "Failed to parse route limit, defaulting to 0.");
+
+                    0
+                });
 
-                let reset_at = data[2].parse::<u128>().unwrap();
-                let reset_after = match data[3].parse::<u64>() {
-                    Ok(after) => after,
-                    Err(_) => {
+                let reset_after = match reply_field::<u64>(&data, 3) {
+                    Some(after) => after,
+                    None => {
                         error!(data = ?data, "Failed to parse reset_after, defaulting to 0.",);
 
                         0
                     }
                 };
+                // PEXPIRETIME answers -1 or -2 if the count has no expiry or is already gone
+                let reset_at = match reply_field::<u128>(&data, 2) {
+                    Some(reset_at) => reset_at,
+                    None => curr_time + reset_after as u128,
+                };
 
                 RatelimitStatus::RouteRatelimited {
                     limit,
@@ -112,19 +224,51 @@ impl RatelimitStatus {
                 RatelimitRetryCause::HoldingGlobalLockAwaitingRouteLock,
             ),
             5 => {
-                let holds_global_lock = data[1].as_str() == "1";
-                let holds_route_lock = data[2].as_str() == "1";
+                let holds_global_lock = reply_field::<u8>(&data, 1) == Some(1);
+                let holds_route_lock = reply_field::<u8>(&data, 2) == Some(1);
+
+                // Only known once the route's limit has been learned from Discord, and never returned by global only checks
+                let route_limit = reply_field::<u16>(&data, 3);
+                let route_count = reply_field::<u16>(&data, 4);
+
+                let quota = match (route_limit, route_count) {
+                    (Some(limit), Some(count)) if limit > 0 => {
+                        let limit = effective_limit(limit, route_safety_margin);
+
+                        Some(ProxyQuota {
+                            limit,
+                            remaining: limit.saturating_sub(count),
+                        })
+                    }
+                    _ => None,
+                };
 
                 RatelimitStatus::Allowed {
                     holds_global_lock,
                     holds_route_lock,
+                    quota,
                 }
             }
-            _ => panic!("Invalid ratelimit status code: {}", status_code),
+            6 => RatelimitStatus::RequiresRetry(RatelimitRetryCause::CounterReset {
+                counter: match data.get(1).map(|counter| counter.as_str()) {
+                    Some("global") => "global",
+                    _ => "route",
+                },
+            }),
+            _ => {
+                error!(data = ?data, "Invalid ratelimit status code: {}", status_code);
+
+                RatelimitStatus::ProxyOverloaded
+            }
         }
     }
 }
 
+/// One field of a ratelimit check reply, if the reply has it and it parses
+fn reply_field<T: FromStr>(data: &[String], index: usize) -> Option<T> {
+    data.get(index).and_then(|field| field.parse::<T>().ok())
+}
+
 impl fmt::Display for RatelimitStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -151,6 +295,7 @@ impl fmt::Display for RatelimitStatus {
             RatelimitStatus::Allowed {
                 holds_global_lock,
                 holds_route_lock,
+                ..
             } => {
                 write!(
                     f,
@@ -163,20 +308,44 @@ impl fmt::Display for RatelimitStatus {
 }
 
 type RouteLockToken = Option<String>;
+type RatelimitsAllowed = (RouteLockToken, Option<ProxyQuota>);
 type RatelimitedResponse = Response<Body>;
 
 impl Proxy {
+    /// `route_rl_strategy` is usually the configured strategy, but callers may be allowed to override it per request.
     pub async fn check_ratelimits(
         &self,
         request_info: &DiscordRequestInfo,
-    ) -> Result<Result<RouteLockToken, RatelimitedResponse>, ProxyError> {
+        route_rl_strategy: &NewBucketStrategy,
+        held_global_lock: &mut Option<(String, String)>,
+    ) -> Result<Result<RatelimitsAllowed, RatelimitedResponse>, ProxyError> {
         #[cfg(feature = "metrics")]
         let ratelimit_checks_started_at = Instant::now();
 
         let use_global_rl = !self.config.disable_global_rl && request_info.uses_global_ratelimit;
+        let use_route_rl = !self.config.disable_route_rl;
+
+        if !use_global_rl && !use_route_rl {
+            return Ok(Ok((None, None)));
+        }
+
+        let cost = self.config.request_cost(&request_info.route_display_bucket);
+
+        // Every bot gets at least the default global ratelimit, so that's safe to use until its real one is known
+        let global_loose_limit = match self.config.global_rl_strategy {
+            NewBucketStrategy::Strict => 0,
+            NewBucketStrategy::Loose => self.config.default_global_rl,
+        };
+        let route_loose_limit = match route_rl_strategy {
+            NewBucketStrategy::Strict => 0,
+            NewBucketStrategy::Loose => self.config.loose_route_burst,
+        };
 
         let mut overload_count: u8 = 0;
         let result = loop {
+            // Any global lock taken by the previous check was released by fetching the global ratelimit
+            *held_global_lock = None;
+
             let check_started_at_timestamp = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time went backwards");
@@ -185,22 +354,68 @@ impl Proxy {
             let global_rl_time_slice = &format!("-{}", check_started_at_timestamp.as_secs());
             let lock_token = random_string(8);
 
-            let data = if use_global_rl {
-                self.redis
+            let args = RatelimitCheckArgs {
+                lock_token: &lock_token,
+                cost,
+                lock_ttl_secs: self.config.lock_ttl_secs,
+
+                global_loose_limit,
+                route_loose_limit,
+
+                route_safety_margin: self.config.ratelimit_safety_margin,
+            };
+
+            // Both keys share the global id's hash tag, so they always live on the same shard
+            let redis = if use_global_rl {
+                self.redis_for(&request_info.global_id_redis_key)
+            } else {
+                self.redis_for(&request_info.route_bucket_redis_key)
+            };
+
+            let result = if use_global_rl && use_route_rl {
+                redis
                     .check_global_and_route_rl(
                         &request_info.global_id_redis_key,
                         global_rl_time_slice,
                         &request_info.route_bucket_redis_key,
-                        &lock_token,
+                        &args,
                     )
-                    .await?
+                    .await
+            } else if use_global_rl {
+                redis
+                    .check_global_rl(
+                        &request_info.global_id_redis_key,
+                        global_rl_time_slice,
+                        &args,
+                    )
+                    .await
             } else {
-                self.redis
-                    .check_route_rl(&request_info.route_bucket_redis_key, &lock_token)
-                    .await?
+                match redis
+                    .peek_route_rl(&request_info.route_bucket_redis_key, &args)
+                    .await
+                {
+                    Some(data) => Ok(data),
+                    None => {
+                        redis
+                            .check_route_rl(&request_info.route_bucket_redis_key, &args)
+                            .await
+                    }
+                }
+            };
+
+            let data = match result {
+                Ok(data) => data,
+                // The pool is too busy to run the check in time, so answer now instead of stalling the request
+                Err(err) if *err.kind() == RedisErrorKind::Timeout => {
+                    debug!("Ratelimit check timed out waiting for Redis: {:?}", err);
+
+                    break Ok(Err(overloaded(request_info)));
+                }
+                Err(err) => return Err(err.into()),
             };
 
             let status = RatelimitStatus::from(
+                self.config.ratelimit_safety_margin,
                 overload_count,
                 check_started_at_timestamp,
                 check_started_at,
@@ -209,42 +424,107 @@ impl Proxy {
 
             trace!(?status);
 
-            let result = match status {
-                RatelimitStatus::ProxyOverloaded => {
-                    #[cfg(feature = "metrics")]
-                    metrics::PROXY_REQUEST_OVERLOADED
-                        .with_label_values(&[
-                            request_info.global_id.as_str(),
-                            request_info.route_display_bucket.as_str(),
-                        ])
-                        .inc();
-
-                    Ok(Err(responses::overloaded()))
+            // Kept track of so a request that runs out of time while fetching the global ratelimit still gives up the lock
+            if matches!(
+                status,
+                RatelimitStatus::RequiresRetry(
+                    RatelimitRetryCause::HoldingGlobalLockAwaitingRouteLock
+                ) | RatelimitStatus::Allowed {
+                    holds_global_lock: true,
+                    ..
                 }
+            ) {
+                *held_global_lock =
+                    Some((request_info.global_id_redis_key.clone(), lock_token.clone()));
+            }
+
+            if self.config.ratelimit_audit_log {
+                audit_ratelimit_status(request_info, &status);
+            }
+
+            let result = match status {
+                RatelimitStatus::ProxyOverloaded => Ok(Err(overloaded(request_info))),
                 RatelimitStatus::RequiresRetry(cause) => {
-                    match cause {
+                    let queued = match cause {
                         RatelimitRetryCause::HoldingGlobalLockAwaitingRouteLock => {
-                            try_join!(
-                                self.fetch_global_ratelimit(request_info, &lock_token),
-                                self.await_lock(&request_info.route_bucket_redis_key)
-                            )?;
+                            match self.config.route_lock_wait_strategy {
+                                RouteLockWaitStrategy::Wait => {
+                                    // The global ratelimit fetch must finish even if the route lock queue is full or the
+                                    // wait times out, as it's what releases the global lock
+                                    let (fetched, queued) = join!(
+                                        self.fetch_global_ratelimit(request_info, &lock_token),
+                                        self.await_lock(&request_info.route_bucket_redis_key)
+                                    );
+
+                                    fetched?;
+                                    queued
+                                }
+                                // The retry sees the global limit as known, so it queues on the route lock like any other
+                                // request if it's still held
+                                RouteLockWaitStrategy::Optimistic => {
+                                    self.fetch_global_ratelimit(request_info, &lock_token)
+                                        .await?;
+
+                                    true
+                                }
+                            }
                         }
                         RatelimitRetryCause::AwaitingGlobalLock => {
-                            self.await_lock(&request_info.global_id_redis_key).await?;
+                            self.await_lock(&request_info.global_id_redis_key).await
                         }
                         RatelimitRetryCause::AwaitingRouteLock => {
-                            self.await_lock(&request_info.route_bucket_redis_key)
-                                .await?;
+                            self.await_lock(&request_info.route_bucket_redis_key).await
                         }
-                        RatelimitRetryCause::ProxyOverloaded { .. } => {
+                        RatelimitRetryCause::ProxyOverloaded { retry_count } => {
                             overload_count += 1;
+
+                            let backoff = overload_backoff(retry_count);
+                            debug!("Ratelimit checks overloaded, retrying in {:?}.", backoff);
+
+                            tokio::time::sleep(backoff).await;
+
+                            true
+                        }
+                        RatelimitRetryCause::GlobalRatelimitDrifted { check_time } => {
+                            #[cfg(feature = "metrics")]
+                            metrics::PROXY_REQUEST_GLOBAL_DRIFTED
+                                .with_label_values(&metrics::with_global_id(
+                                    &request_info.metrics_global_id,
+                                    &[],
+                                ))
+                                .inc();
+
+                            debug!(
+                                check_time,
+                                global_id = request_info.log_global_id.as_str(),
+                                "Global ratelimit drifted, retrying."
+                            );
+
+                            true
                         }
-                        RatelimitRetryCause::GlobalRatelimitDrifted => {
-                            debug!("Global ratelimit drifted, retrying.");
+                        RatelimitRetryCause::CounterReset { counter } => {
+                            #[cfg(feature = "metrics")]
+                            metrics::PROXY_COUNTERS_RESET
+                                .with_label_values(&[counter])
+                                .inc();
+
+                            warn!(
+                                counter,
+                                global_id = request_info.log_global_id.as_str(),
+                                route = request_info.route_display_bucket.as_str(),
+                                "Reset an inconsistent {} request counter, retrying.",
+                                counter
+                            );
+
+                            true
                         }
+                    };
+
+                    if queued {
+                        continue;
                     }
 
-                    continue;
+                    Ok(Err(overloaded(request_info)))
                 }
                 RatelimitStatus::GlobalRatelimited {
                     limit,
@@ -253,11 +533,23 @@ impl Proxy {
                 } => {
                     #[cfg(feature = "metrics")]
                     metrics::PROXY_REQUEST_GLOBAL_429
-                        .with_label_values(&[request_info.global_id.as_str()])
+                        .with_label_values(&metrics::with_global_id(
+                            &request_info.metrics_global_id,
+                            &[],
+                        ))
+                        .inc();
+
+                    #[cfg(feature = "metrics")]
+                    metrics::PROXY_REQUESTS_SERVED_LOCALLY
+                        .with_label_values(&metrics::with_global_id(
+                            &request_info.metrics_global_id,
+                            &[request_info.route_display_bucket.as_str(), "ratelimit"],
+                        ))
                         .inc();
 
                     Ok(Err(responses::ratelimited(
                         &request_info.global_id,
+                        true,
                         limit,
                         reset_at,
                         reset_after,
@@ -270,14 +562,27 @@ impl Proxy {
                 } => {
                     #[cfg(feature = "metrics")]
                     metrics::PROXY_REQUEST_ROUTE_429
-                        .with_label_values(&[
-                            request_info.global_id.as_str(),
-                            request_info.route_display_bucket.as_str(),
-                        ])
+                        .with_label_values(&metrics::with_global_id(
+                            &request_info.metrics_global_id,
+                            &[request_info.route_display_bucket.as_str()],
+                        ))
                         .inc();
 
+                    #[cfg(feature = "metrics")]
+                    metrics::PROXY_REQUESTS_SERVED_LOCALLY
+                        .with_label_values(&metrics::with_global_id(
+                            &request_info.metrics_global_id,
+                            &[request_info.route_display_bucket.as_str(), "ratelimit"],
+                        ))
+                        .inc();
+
+                    // Client libraries track buckets by Discord's hash, so that's what they get once it's known
                     Ok(Err(responses::ratelimited(
-                        &request_info.route_bucket,
+                        request_info
+                            .discord_bucket
+                            .as_deref()
+                            .unwrap_or(&request_info.route_bucket),
+                        false,
                         limit,
                         reset_at,
                         reset_after,
@@ -286,6 +591,7 @@ impl Proxy {
                 RatelimitStatus::Allowed {
                     holds_global_lock,
                     holds_route_lock,
+                    quota,
                 } => {
                     if holds_global_lock {
                         self.fetch_global_ratelimit(request_info, &lock_token)
@@ -298,19 +604,21 @@ impl Proxy {
                         None
                     };
 
-                    Ok(Ok(pass_lock_token))
+                    Ok(Ok((pass_lock_token, quota)))
                 }
             };
 
             break result;
         };
 
+        *held_global_lock = None;
+
         #[cfg(feature = "metrics")]
         metrics::PROXY_REQUEST_RATELIMIT_CHECK_TIMES
-            .with_label_values(&[
-                request_info.global_id.as_str(),
-                request_info.route_display_bucket.as_str(),
-            ])
+            .with_label_values(&metrics::with_global_id(
+                &request_info.metrics_global_id,
+                &[request_info.route_display_bucket.as_str()],
+            ))
             .observe(ratelimit_checks_started_at.elapsed().as_secs_f64());
 
         result
@@ -321,28 +629,41 @@ impl Proxy {
         request_info: &DiscordRequestInfo,
         lock_token: &str,
     ) -> Result<(), ProxyError> {
-        let mut ratelimit = 50;
+        let _probe_permit = match &self.probe_permits {
+            Some(probe_permits) => Some(
+                probe_permits
+                    .acquire()
+                    .await
+                    .expect("Probe semaphore closed."),
+            ),
+            None => None,
+        };
 
-        if request_info.global_id == "NoAuth" {
-            trace!("Global ratelimit lock acquired, but request is unauthenticated. Defaulting to 50 requests/s.");
-        } else {
-            ratelimit = match self
-                .fetch_discord_global_ratelimit(request_info.token.as_ref().unwrap())
-                .await
-            {
+        #[cfg(feature = "metrics")]
+        let fetch_started_at = Instant::now();
+
+        let default_ratelimit = self.config.default_global_rl;
+        let mut ratelimit = default_ratelimit;
+
+        if self.config.shadow_mode {
+            trace!("Global ratelimit lock acquired in shadow mode, not asking Discord. Defaulting to {} requests/s.", default_ratelimit);
+        } else if let Some(token) = &request_info.token {
+            ratelimit = match self.fetch_discord_global_ratelimit(token).await {
                 Ok(limit) => {
                     trace!("Fetched global ratelimit of {}/s from Discord.", limit);
                     limit
                 }
                 Err(err) => {
-                    warn!("Failed to fetch global ratelimit from Discord, falling back to default 50/s. Error: {}", err);
-                    50
+                    warn!("Failed to fetch global ratelimit from Discord, falling back to default {}/s. Error: {}", default_ratelimit, err);
+                    default_ratelimit
                 }
             }
+        } else {
+            trace!("Global ratelimit lock acquired, but request is unauthenticated. Defaulting to {} requests/s.", default_ratelimit);
         }
 
         if !self
-            .redis
+            .redis_for(&request_info.global_id_redis_key)
             .release_global_lock(
                 &request_info.global_id_redis_key,
                 lock_token,
@@ -352,25 +673,168 @@ impl Proxy {
             .await?
         {
             debug!("Lock expired before we could set the ratelimit.");
+
+            #[cfg(feature = "metrics")]
+            metrics::PROXY_LOCKS_EXPIRED
+                .with_label_values(&["global"])
+                .inc();
         }
 
+        #[cfg(feature = "metrics")]
+        metrics::PROXY_GLOBAL_RATELIMIT_FETCH_TIMES
+            .with_label_values(&metrics::with_global_id(
+                &request_info.metrics_global_id,
+                &[],
+            ))
+            .observe(fetch_started_at.elapsed().as_secs_f64());
+
         Ok(())
     }
 
-    async fn await_lock(&self, bucket: &str) -> Result<(), ProxyError> {
+    /// Waits for the lock on a bucket to be released, returns false if too many requests are already waiting on it.
+    async fn await_lock(&self, bucket: &str) -> bool {
         trace!("Waiting for lock on {}", bucket);
 
         select! {
-          Ok(_) = self.redis.await_lock(bucket) => {
-            trace!("Lock released.");
+          result = self.redis_for(bucket).await_lock(bucket) => match result {
+            Ok(_) => trace!("Lock released."),
+            Err(LockError::TooManyWaiters(waiters)) => {
+              debug!("{} requests are already waiting for lock on {}, rejecting.", waiters, bucket);
+              return false;
+            }
+            Err(err) => trace!("Lock wait failed: {}", err),
           },
           _ = tokio::time::sleep(self.config.lock_timeout) => {
             trace!("Lock wait expired.");
-            self.redis.cleanup_pending_locks(bucket).await;
+            self.redis_for(bucket).cleanup_pending_locks(bucket).await;
           }
         };
 
-        Ok(())
+        true
+    }
+
+    pub async fn resolve_discord_bucket(&self, request_info: &mut DiscordRequestInfo) {
+        let discord_buckets = self.discord_buckets.read().await;
+
+        let bucket_hash = match discord_buckets.get(&request_info.route_bucket) {
+            Some(mapping) if !mapping.is_expired() => match mapping.bucket_hash.clone() {
+                Some(bucket_hash) => bucket_hash,
+                None => return,
+            },
+            _ => {
+                drop(discord_buckets);
+
+                // Another proxy instance may have already learned this bucket
+                let bucket_hash = match self
+                    .redis
+                    .get_discord_bucket(&request_info.route_bucket)
+                    .await
+                {
+                    Ok(Some(bucket_hash)) => bucket_hash,
+                    Ok(None) => {
+                        self.cache_discord_bucket(&request_info.route_bucket, None)
+                            .await;
+                        return;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "[{}] Failed to fetch Discord bucket from Redis: {:?}",
+                            &request_info.route_bucket, err
+                        );
+                        return;
+                    }
+                };
+
+                self.cache_discord_bucket(&request_info.route_bucket, Some(&bucket_hash))
+                    .await;
+
+                bucket_hash
+            }
+        };
+
+        trace!(
+            "[{}] Using Discord bucket {}",
+            &request_info.route_bucket,
+            &bucket_hash
+        );
+
+        request_info.use_discord_bucket(bucket_hash);
+    }
+
+    async fn learn_discord_bucket(&self, headers: &HeaderMap, request_info: &DiscordRequestInfo) {
+        let bucket_hash = match headers
+            .get("X-RateLimit-Bucket")
+            .and_then(|bucket| bucket.to_str().ok())
+        {
+            Some(bucket_hash) => bucket_hash,
+            None => return,
+        };
+
+        if request_info.discord_bucket.as_deref() == Some(bucket_hash) {
+            return;
+        }
+
+        debug!(
+            "[{}] Learned Discord bucket {}",
+            &request_info.route_bucket, bucket_hash
+        );
+
+        self.cache_discord_bucket(&request_info.route_bucket, Some(bucket_hash))
+            .await;
+
+        let redis = self.redis.clone();
+        let route_bucket = request_info.route_bucket.clone();
+        let bucket_hash = bucket_hash.to_string();
+        let bucket_ttl = self.config.bucket_ttl_ms;
+        tokio::task::spawn(async move {
+            if let Err(err) = redis
+                .set_discord_bucket(&route_bucket, &bucket_hash, bucket_ttl)
+                .await
+            {
+                error!(
+                    "[{}] Failed to store Discord bucket in Redis: {:?}",
+                    &route_bucket, err
+                );
+            }
+        });
+    }
+
+    async fn cache_discord_bucket(&self, route_bucket: &str, bucket_hash: Option<&str>) {
+        let mut discord_buckets = self.discord_buckets.write().await;
+        discord_buckets.insert(
+            route_bucket.to_string(),
+            DiscordBucketMapping::new(bucket_hash, self.config.bucket_ttl_ms),
+        );
+
+        #[cfg(feature = "metrics")]
+        metrics::DISCORD_BUCKET_MAPPINGS.set(mapped_discord_buckets(&discord_buckets));
+    }
+
+    /// Periodically drops expired Discord bucket mappings from the local cache.
+    pub fn start_discord_bucket_sweep(&self) {
+        let proxy = self.clone();
+
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(DISCORD_BUCKET_SWEEP_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let mut discord_buckets = proxy.discord_buckets.write().await;
+                let before = discord_buckets.len();
+                discord_buckets.retain(|_, mapping| !mapping.is_expired());
+
+                if before != discord_buckets.len() {
+                    trace!(
+                        "Swept {} expired Discord bucket mappings.",
+                        before - discord_buckets.len()
+                    );
+                }
+
+                #[cfg(feature = "metrics")]
+                metrics::DISCORD_BUCKET_MAPPINGS.set(mapped_discord_buckets(&discord_buckets));
+            }
+        });
     }
 
     pub async fn update_ratelimits(
@@ -379,6 +843,16 @@ impl Proxy {
         request_info: &DiscordRequestInfo,
         lock_token: Option<String>,
     ) -> Result<(), RedisError> {
+        if self.config.disable_route_rl {
+            return Ok(());
+        }
+
+        if self.config.use_discord_buckets {
+            self.learn_discord_bucket(headers, request_info).await;
+        }
+
+        let retry_after_ms = route_retry_after_ms(headers);
+
         let headers: Option<(u16, u16, u64, u64)> = || -> Option<(u16, u16, u64, u64)> {
             let limit = match headers.get("X-RateLimit-Limit") {
                 Some(limit) => limit.clone().to_str().unwrap().parse::<u16>().unwrap(),
@@ -428,19 +902,79 @@ impl Proxy {
         }();
 
         if headers.is_none() {
+            // Nothing was learned, so hand the bucket to the next waiting request instead of leaving them all to wait
+            // out the lock's TTL
+            if let Some(lock_token) = &lock_token {
+                self.redis_for(&request_info.route_bucket_redis_key)
+                    .release_route_lock(&request_info.route_bucket_redis_key, lock_token)
+                    .await?;
+            }
+
             return Ok(());
         }
 
         let (limit, remaining, reset_at, reset_after) = headers.unwrap();
 
-        // Force 15 minute TTL for interaction routes
-        let bucket_ttl = if request_info.resource == Resources::Interactions {
-            15 * 60 * 1000
+        // A route 429's Retry-After is authoritative, so the bucket stays closed for at least that long even if the
+        // other headers say otherwise
+        let (remaining, reset_at, reset_after) = match retry_after_ms {
+            Some(retry_after) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_millis() as u64;
+
+                (
+                    0,
+                    reset_at.max(now + retry_after),
+                    reset_after.max(retry_after),
+                )
+            }
+            None => (remaining, reset_at, reset_after),
+        };
+
+        let is_interaction =
+            request_info.resource == Resources::Interactions || request_info.uses_interaction_token;
+
+        // Interaction routes get their own TTL, as the tokens they're bucketed by expire after 15 minutes
+        let bucket_ttl = if is_interaction {
+            self.config.interaction_bucket_ttl_ms
         } else {
-            self.config.bucket_ttl_ms
+            jittered_ttl(
+                self.config.bucket_ttl_ms,
+                self.config.bucket_ttl_jitter_percent,
+            )
         };
 
-        let redis = self.redis.clone();
+        let bucket_ttl = match self.config.route_bucket_max_age_ms {
+            0 => bucket_ttl,
+            max_age if bucket_ttl == 0 => max_age,
+            max_age => bucket_ttl.min(max_age),
+        };
+
+        // Rarely used buckets are dropped after a few of their reset windows instead of taking up Redis for the whole bucket TTL
+        let idle_ttl = match self.config.adaptive_bucket_ttl_windows {
+            0 => 0,
+            _ if is_interaction => 0,
+            windows => reset_after
+                .saturating_mul(windows)
+                .max(MIN_IDLE_BUCKET_TTL_MS),
+        };
+
+        let bucket_ttl = match idle_ttl {
+            0 => bucket_ttl,
+            idle_ttl if bucket_ttl == 0 => idle_ttl,
+            idle_ttl => bucket_ttl.min(idle_ttl),
+        };
+
+        // Extending the TTL on use would keep a bucket past its max age
+        let idle_ttl = if self.config.route_bucket_max_age_ms == 0 {
+            idle_ttl
+        } else {
+            0
+        };
+
+        let redis = self.redis_for(&request_info.route_bucket_redis_key).clone();
         let request_info_clone = request_info.clone();
         tokio::task::spawn(async move {
             if lock_token.is_some() {
@@ -466,11 +1000,14 @@ impl Proxy {
                 .set_route_expiry(
                     &request_info_clone.route_bucket_redis_key,
                     lock_token.clone(),
-                    limit,
-                    remaining,
-                    reset_at,
-                    reset_after,
-                    bucket_ttl,
+                    &RouteExpiryArgs {
+                        limit,
+                        remaining,
+                        reset_at,
+                        reset_after,
+                        route_info_expire_in: bucket_ttl,
+                        route_info_idle_ttl: idle_ttl,
+                    },
                 )
                 .await
             {
@@ -481,6 +1018,11 @@ impl Proxy {
                             "Lock expired before we could set the ratelimit for it: {}",
                             &request_info_clone.route_bucket
                         );
+
+                        #[cfg(feature = "metrics")]
+                        metrics::PROXY_LOCKS_EXPIRED
+                            .with_label_values(&["route"])
+                            .inc();
                     }
                 }
                 Err(err) => {
@@ -515,6 +1057,122 @@ fn ratelimit_check_is_overloaded(time_taken: u128) -> bool {
     false
 }
 
+const OVERLOAD_BACKOFF_BASE_MS: u64 = 5;
+const OVERLOAD_BACKOFF_MAX_JITTER_MS: u64 = 5;
+
+fn overload_backoff(retry_count: u8) -> Duration {
+    let exponential = OVERLOAD_BACKOFF_BASE_MS << retry_count.saturating_sub(1).min(4);
+    let jitter = thread_rng().gen_range(0..=OVERLOAD_BACKOFF_MAX_JITTER_MS);
+
+    Duration::from_millis(exponential + jitter)
+}
+
+/// Reads `Retry-After` in milliseconds from a 429 Discord returned for a route, global 429s don't say anything about it.
+fn route_retry_after_ms(headers: &HeaderMap) -> Option<u64> {
+    if headers
+        .get("X-RateLimit-Global")
+        .is_some_and(|global| global == "true")
+    {
+        return None;
+    }
+
+    let retry_after = headers
+        .get("Retry-After")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+
+    (retry_after.is_finite() && retry_after > 0.0).then(|| (retry_after * 1000.0).ceil() as u64)
+}
+
+/// Spreads out the expiry of buckets created together, so they don't all need to be relearned at the same time
+fn jittered_ttl(ttl_ms: u64, jitter_percent: u8) -> u64 {
+    if ttl_ms == 0 || jitter_percent == 0 {
+        return ttl_ms;
+    }
+
+    let max_jitter = ttl_ms.saturating_mul(jitter_percent as u64) / 100;
+
+    ttl_ms
+        .saturating_sub(max_jitter)
+        .saturating_add(thread_rng().gen_range(0..=max_jitter.saturating_mul(2)))
+        .max(1)
+}
+
+/// Emits a stable, structured event for every ratelimit decision, which can be filtered by its `ratelimit_audit` target
+fn audit_ratelimit_status(request_info: &DiscordRequestInfo, status: &RatelimitStatus) {
+    let route = request_info.route_display_bucket.as_str();
+    let global_id = request_info.log_global_id.as_str();
+
+    match status {
+        RatelimitStatus::ProxyOverloaded => {
+            info!(target: "ratelimit_audit", route, global_id, decision = "overloaded")
+        }
+        RatelimitStatus::RequiresRetry(cause) => info!(
+            target: "ratelimit_audit",
+            route,
+            global_id,
+            decision = "retry",
+            cause = ?cause
+        ),
+        RatelimitStatus::GlobalRatelimited {
+            limit,
+            reset_at,
+            reset_after,
+        } => info!(
+            target: "ratelimit_audit",
+            route,
+            global_id,
+            decision = "global_ratelimited",
+            limit,
+            reset_at = *reset_at as u64,
+            reset_after
+        ),
+        RatelimitStatus::RouteRatelimited {
+            limit,
+            reset_at,
+            reset_after,
+        } => info!(
+            target: "ratelimit_audit",
+            route,
+            global_id,
+            decision = "route_ratelimited",
+            limit,
+            reset_at = *reset_at as u64,
+            reset_after
+        ),
+        RatelimitStatus::Allowed {
+            holds_global_lock,
+            holds_route_lock,
+            quota,
+        } => info!(
+            target: "ratelimit_audit",
+            route,
+            global_id,
+            decision = "allowed",
+            holds_global_lock,
+            holds_route_lock,
+            limit = quota.map(|quota| quota.limit),
+            remaining = quota.map(|quota| quota.remaining)
+        ),
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+fn overloaded(request_info: &DiscordRequestInfo) -> Response<Body> {
+    #[cfg(feature = "metrics")]
+    metrics::PROXY_REQUEST_OVERLOADED
+        .with_label_values(&metrics::with_global_id(
+            &request_info.metrics_global_id,
+            &[request_info.route_display_bucket.as_str()],
+        ))
+        .inc();
+
+    responses::overloaded()
+}
+
 fn random_string(n: usize) -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
@@ -522,3 +1180,374 @@ fn random_string(n: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+#[cfg(feature = "metrics")]
+fn mapped_discord_buckets(discord_buckets: &ahash::AHashMap<String, DiscordBucketMapping>) -> i64 {
+    discord_buckets
+        .values()
+        .filter(|mapping| mapping.bucket_hash.is_some())
+        .count() as i64
+}
+
+/// The limit the check scripts enforce for a route bucket, see `effective_limit` in `check_route_rl.lua`
+fn effective_limit(limit: u16, safety_margin: u16) -> u16 {
+    limit.saturating_sub(safety_margin).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    /// Parses a reply as if the check had only just started, at the start of a global ratelimit slice
+    fn parse(data: &[&str]) -> RatelimitStatus {
+        parse_with_margin(0, data)
+    }
+
+    fn parse_with_margin(route_safety_margin: u16, data: &[&str]) -> RatelimitStatus {
+        RatelimitStatus::from(
+            route_safety_margin,
+            0,
+            Duration::from_secs(1_000),
+            Instant::now(),
+            data.iter().map(|field| field.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn route_429s_are_closed_for_their_retry_after() {
+        let retry_after = |headers: &[(&'static str, &'static str)]| {
+            let mut header_map = HeaderMap::new();
+            for (name, value) in headers {
+                header_map.insert(*name, HeaderValue::from_static(value));
+            }
+            route_retry_after_ms(&header_map)
+        };
+
+        assert_eq!(retry_after(&[("Retry-After", "30")]), Some(30_000));
+        assert_eq!(retry_after(&[("Retry-After", " 1.2345 ")]), Some(1_235));
+        assert_eq!(
+            retry_after(&[("Retry-After", "5"), ("X-RateLimit-Global", "false")]),
+            Some(5_000)
+        );
+
+        // Global 429s don't say anything about the route
+        assert_eq!(
+            retry_after(&[("Retry-After", "5"), ("X-RateLimit-Global", "true")]),
+            None
+        );
+
+        for invalid in ["0", "-1", "soon", "NaN", "inf"] {
+            assert_eq!(
+                retry_after(&[("Retry-After", invalid)]),
+                None,
+                "{}",
+                invalid
+            );
+        }
+        assert_eq!(retry_after(&[]), None);
+    }
+
+    #[test]
+    fn discord_bucket_mappings_expire() {
+        let mapping = DiscordBucketMapping::new(Some("abc"), 0);
+        assert_eq!(mapping.bucket_hash.as_deref(), Some("abc"));
+        assert!(mapping.expires_at.is_none());
+        assert!(!mapping.is_expired());
+
+        let mapping = DiscordBucketMapping::new(Some("abc"), 60_000);
+        assert!(!mapping.is_expired());
+
+        let expired = DiscordBucketMapping {
+            bucket_hash: Some("abc".to_string()),
+            expires_at: Some(Instant::now()),
+        };
+        assert!(expired.is_expired());
+
+        // Unmapped route buckets are looked up again soon, even if learned mappings are kept forever
+        let unmapped = DiscordBucketMapping::new(None, 0);
+        assert!(unmapped.bucket_hash.is_none());
+        assert!(!unmapped.is_expired());
+        assert!(unmapped.expires_at.unwrap() <= Instant::now() + UNMAPPED_DISCORD_BUCKET_TTL);
+    }
+
+    #[test]
+    fn quotas_leave_out_the_safety_margin() {
+        let quota = |margin: u16, data: &[&str]| match parse_with_margin(margin, data) {
+            RatelimitStatus::Allowed { quota, .. } => quota,
+            status => panic!("Expected an allowed status, got {:?}", status),
+        };
+
+        assert_eq!(
+            quota(2, &["5", "nil", "nil", "10", "3"]),
+            Some(ProxyQuota {
+                limit: 8,
+                remaining: 5
+            })
+        );
+        assert_eq!(
+            quota(2, &["5", "nil", "nil", "10", "8"]),
+            Some(ProxyQuota {
+                limit: 8,
+                remaining: 0
+            })
+        );
+        // Like the scripts, a bucket always allows at least one request
+        assert_eq!(
+            quota(5, &["5", "nil", "nil", "3", "1"]),
+            Some(ProxyQuota {
+                limit: 1,
+                remaining: 0
+            })
+        );
+    }
+
+    #[test]
+    fn parses_allowed_replies_from_check_route_rl() {
+        assert_eq!(
+            parse(&["5", "nil", "nil", "10", "3"]),
+            RatelimitStatus::Allowed {
+                holds_global_lock: false,
+                holds_route_lock: false,
+                quota: Some(ProxyQuota {
+                    limit: 10,
+                    remaining: 7
+                }),
+            }
+        );
+
+        // An unknown route limit is returned as 0, and the request holds the route lock to learn it
+        assert_eq!(
+            parse(&["5", "nil", "1", "0", "1"]),
+            RatelimitStatus::Allowed {
+                holds_global_lock: false,
+                holds_route_lock: true,
+                quota: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_allowed_replies_from_check_global_rl() {
+        assert_eq!(
+            parse(&["5", "1", "nil"]),
+            RatelimitStatus::Allowed {
+                holds_global_lock: true,
+                holds_route_lock: false,
+                quota: None,
+            }
+        );
+        assert_eq!(
+            parse(&["5", "nil", "nil"]),
+            RatelimitStatus::Allowed {
+                holds_global_lock: false,
+                holds_route_lock: false,
+                quota: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_allowed_replies_from_check_global_and_route_rl() {
+        assert_eq!(
+            parse(&["5", "1", "1", "0", "1"]),
+            RatelimitStatus::Allowed {
+                holds_global_lock: true,
+                holds_route_lock: true,
+                quota: None,
+            }
+        );
+        assert_eq!(
+            parse(&["5", "nil", "nil", "5", "5"]),
+            RatelimitStatus::Allowed {
+                holds_global_lock: false,
+                holds_route_lock: false,
+                quota: Some(ProxyQuota {
+                    limit: 5,
+                    remaining: 0
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_route_ratelimited_replies() {
+        assert_eq!(
+            parse(&["2", "5", "1000500", "500"]),
+            RatelimitStatus::RouteRatelimited {
+                limit: 5,
+                reset_at: 1_000_500,
+                reset_after: 500,
+            }
+        );
+
+        // PEXPIRETIME on a count without an expiry
+        match parse(&["2", "5", "-1", "500"]) {
+            RatelimitStatus::RouteRatelimited {
+                limit,
+                reset_at,
+                reset_after,
+            } => {
+                assert_eq!((limit, reset_after), (5, 500));
+                assert!(reset_at >= 1_000_500);
+            }
+            status => panic!("Unexpected status: {}", status),
+        }
+
+        assert_eq!(
+            parse(&["2"]),
+            RatelimitStatus::RouteRatelimited {
+                limit: 0,
+                reset_at: 1_000_000,
+                reset_after: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_global_ratelimited_replies() {
+        match parse(&["0", "50"]) {
+            RatelimitStatus::GlobalRatelimited {
+                limit,
+                reset_at,
+                reset_after,
+            } => {
+                assert_eq!((limit, reset_at), (50, 1_001_000));
+                assert!(reset_after <= 1_000);
+            }
+            status => panic!("Unexpected status: {}", status),
+        }
+
+        assert!(matches!(
+            parse(&["0"]),
+            RatelimitStatus::GlobalRatelimited { limit: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn parses_bare_status_codes() {
+        assert_eq!(
+            parse(&["1"]),
+            RatelimitStatus::RequiresRetry(RatelimitRetryCause::AwaitingGlobalLock)
+        );
+        assert_eq!(
+            parse(&["3"]),
+            RatelimitStatus::RequiresRetry(RatelimitRetryCause::AwaitingRouteLock)
+        );
+        assert_eq!(
+            parse(&["4"]),
+            RatelimitStatus::RequiresRetry(RatelimitRetryCause::HoldingGlobalLockAwaitingRouteLock)
+        );
+    }
+
+    #[test]
+    fn parses_counter_reset_replies() {
+        assert_eq!(
+            parse(&["6", "global"]),
+            RatelimitStatus::RequiresRetry(RatelimitRetryCause::CounterReset { counter: "global" })
+        );
+        assert_eq!(
+            parse(&["6", "route"]),
+            RatelimitStatus::RequiresRetry(RatelimitRetryCause::CounterReset { counter: "route" })
+        );
+    }
+
+    /// One of every variant, paired with whether it's allowed and whether it's ratelimited
+    fn every_status() -> Vec<(RatelimitStatus, bool, bool)> {
+        vec![
+            (RatelimitStatus::ProxyOverloaded, false, false),
+            (
+                RatelimitStatus::RequiresRetry(RatelimitRetryCause::AwaitingRouteLock),
+                false,
+                false,
+            ),
+            (
+                RatelimitStatus::GlobalRatelimited {
+                    limit: 50,
+                    reset_at: 1_001_000,
+                    reset_after: 1_000,
+                },
+                false,
+                true,
+            ),
+            (
+                RatelimitStatus::RouteRatelimited {
+                    limit: 5,
+                    reset_at: 1_000_500,
+                    reset_after: 500,
+                },
+                false,
+                true,
+            ),
+            (
+                RatelimitStatus::Allowed {
+                    holds_global_lock: false,
+                    holds_route_lock: false,
+                    quota: None,
+                },
+                true,
+                false,
+            ),
+        ]
+    }
+
+    #[test]
+    fn predicates_match_every_variant() {
+        for (status, allowed, ratelimited) in every_status() {
+            assert_eq!(status.is_allowed(), allowed, "{}", status);
+            assert_eq!(status.is_ratelimited(), ratelimited, "{}", status);
+
+            match &status {
+                RatelimitStatus::RequiresRetry(cause) => {
+                    assert_eq!(status.retry_cause(), Some(cause))
+                }
+                _ => assert_eq!(status.retry_cause(), None, "{}", status),
+            }
+        }
+    }
+
+    #[test]
+    fn serializes_decisions_with_their_details() {
+        assert_eq!(
+            serde_json::to_value(RatelimitStatus::ProxyOverloaded).unwrap(),
+            serde_json::json!({ "decision": "proxy_overloaded" })
+        );
+        assert_eq!(
+            serde_json::to_value(RatelimitStatus::RequiresRetry(
+                RatelimitRetryCause::CounterReset { counter: "route" }
+            ))
+            .unwrap(),
+            serde_json::json!({
+                "decision": "requires_retry",
+                "details": { "cause": "counter_reset", "counter": "route" }
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(RatelimitStatus::Allowed {
+                holds_global_lock: true,
+                holds_route_lock: false,
+                quota: Some(ProxyQuota {
+                    limit: 5,
+                    remaining: 4
+                }),
+            })
+            .unwrap(),
+            serde_json::json!({
+                "decision": "allowed",
+                "details": {
+                    "holds_global_lock": true,
+                    "holds_route_lock": false,
+                    "quota": { "limit": 5, "remaining": 4 }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn treats_invalid_replies_as_overloaded() {
+        assert_eq!(parse(&[]), RatelimitStatus::ProxyOverloaded);
+        assert_eq!(parse(&["nil"]), RatelimitStatus::ProxyOverloaded);
+        assert_eq!(parse(&["9", "1"]), RatelimitStatus::ProxyOverloaded);
+    }
+}
@@ -1,83 +1,179 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use ahash::AHashMap;
 use fred::{
     clients::SubscriberClient,
     pool::RedisPool,
-    prelude::{ClientLike, LuaInterface, PubsubInterface, RedisError},
+    prelude::{
+        ClientLike, KeysInterface, LuaInterface, PubsubInterface, RedisError, RedisErrorKind,
+    },
     types::{
-        PerformanceConfig, ReconnectPolicy, RedisConfig, RedisValue, RespVersion, Server,
-        ServerConfig,
+        CustomCommand, Expiration, FromRedis, MultipleKeys, MultipleValues, PerformanceConfig,
+        ReconnectPolicy, RedisConfig, RedisValue, RespVersion, Scanner, Server, ServerConfig,
+        SetOptions,
     },
     util::sha1_hash,
 };
+use futures_util::StreamExt;
 
 use thiserror::Error;
 use tokio::{
     select,
-    sync::{
-        oneshot::{self, error::RecvError},
-        Mutex, RwLock,
-    },
-    time::sleep,
+    sync::oneshot::error::RecvError,
+    time::{sleep, timeout},
 };
 
-use crate::config::RedisEnvConfig;
+use crate::{config::RedisEnvConfig, idempotency::PENDING};
+
+mod shards;
+mod waiters;
+pub use shards::RedisShards;
+use waiters::LockWaiters;
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
 
 struct StaticProxyScripts {
     pub check_global_and_route_rl: &'static str,
+    pub check_global_rl: &'static str,
     pub check_route_rl: &'static str,
+    pub peek_route_rl: &'static str,
+    pub read_route_rl: &'static str,
 
     pub release_global_lock: &'static str,
+    pub release_route_lock: &'static str,
+    pub seed_route_limit: &'static str,
     pub set_route_expiry: &'static str,
 }
 
 static SCRIPTS: StaticProxyScripts = StaticProxyScripts {
     check_global_and_route_rl: include_str!("./scripts/check_global_and_route_rl.lua"),
+    check_global_rl: include_str!("./scripts/check_global_rl.lua"),
     check_route_rl: include_str!("./scripts/check_route_rl.lua"),
+    peek_route_rl: include_str!("./scripts/peek_route_rl.lua"),
+    read_route_rl: include_str!("./scripts/read_route_rl.lua"),
 
     release_global_lock: include_str!("./scripts/release_global_lock.lua"),
+    release_route_lock: include_str!("./scripts/release_route_lock.lua"),
+    seed_route_limit: include_str!("./scripts/seed_route_limit.lua"),
     set_route_expiry: include_str!("./scripts/set_route_expiry.lua"),
 };
 
 struct ProxyScriptHashes {
     pub check_global_and_route_rl: String,
+    pub check_global_rl: String,
     pub check_route_rl: String,
+    pub peek_route_rl: String,
+    pub read_route_rl: String,
 
     pub release_global_lock: String,
+    pub release_route_lock: String,
+    pub seed_route_limit: String,
     pub set_route_expiry: String,
 }
 
 impl ProxyScriptHashes {
     pub fn new() -> Self {
         Self {
-            check_global_and_route_rl: sha1_hash(&SCRIPTS.check_global_and_route_rl),
-            check_route_rl: sha1_hash(&SCRIPTS.check_route_rl),
-
-            release_global_lock: sha1_hash(&SCRIPTS.release_global_lock),
-            set_route_expiry: sha1_hash(&SCRIPTS.set_route_expiry),
+            check_global_and_route_rl: sha1_hash(SCRIPTS.check_global_and_route_rl),
+            check_global_rl: sha1_hash(SCRIPTS.check_global_rl),
+            check_route_rl: sha1_hash(SCRIPTS.check_route_rl),
+            peek_route_rl: sha1_hash(SCRIPTS.peek_route_rl),
+            read_route_rl: sha1_hash(SCRIPTS.read_route_rl),
+
+            release_global_lock: sha1_hash(SCRIPTS.release_global_lock),
+            release_route_lock: sha1_hash(SCRIPTS.release_route_lock),
+            seed_route_limit: sha1_hash(SCRIPTS.seed_route_limit),
+            set_route_expiry: sha1_hash(SCRIPTS.set_route_expiry),
         }
     }
 }
 
+/// Arguments shared by the ratelimit check scripts
+pub struct RatelimitCheckArgs<'a> {
+    pub lock_token: &'a str,
+    pub cost: u16,
+    pub lock_ttl_secs: u64,
+
+    /// How many requests may pass a bucket with an unknown limit while another request holds its lock, 0 makes them wait
+    pub global_loose_limit: u16,
+    pub route_loose_limit: u16,
+
+    /// How far below its known limit a route bucket already counts as exhausted
+    pub route_safety_margin: u16,
+}
+
+/// What a response from Discord told us about a route bucket
+pub struct RouteExpiryArgs {
+    pub limit: u16,
+    pub remaining: u16,
+    pub reset_at: u64,
+    pub reset_after: u64,
+
+    /// How long the learned limit is kept, 0 keeps it until the bucket goes idle
+    pub route_info_expire_in: u64,
+    pub route_info_idle_ttl: u64,
+}
+
 #[derive(Clone)]
 pub struct ProxyRedisClient {
     pub pool: RedisPool,
+    replica_pool: Option<RedisPool>,
 
     pubsub_receiver: SubscriberClient,
-    pubsub_channels: Arc<RwLock<AHashMap<String, Arc<PubSubChannel>>>>,
+    lock_waiters: LockWaiters,
 
     script_hashes: Arc<ProxyScriptHashes>,
+
+    reconnects: Arc<AtomicU64>,
+    /// Unix timestamp in milliseconds, 0 if the pool hasn't reconnected yet
+    last_reconnect_at: Arc<AtomicU64>,
 }
 
-pub struct PubSubChannel {
-    pending_clients: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+/// Set once a cluster redirect has been logged, as every following command would log it again
+static CLUSTER_REDIRECT_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Replaces the MOVED/ASK errors a Redis Cluster node answers keys it doesn't own with one saying what's wrong, as they
+/// would otherwise fail every request with an opaque error.
+fn explain_cluster_redirect(err: RedisError) -> RedisError {
+    let details = err.details();
+    if !(details.starts_with("MOVED ") || details.starts_with("ASK ")) {
+        return err;
+    }
+
+    let explained = RedisError::new(
+        RedisErrorKind::Config,
+        format!(
+            "Redis answered with a cluster redirect ({}). It is running in cluster mode, which isn't supported, point REDIS_HOST at a standalone Redis or spread keys across several with REDIS_SHARDS instead.",
+            details
+        ),
+    );
+
+    if !CLUSTER_REDIRECT_LOGGED.swap(true, Ordering::Relaxed) {
+        tracing::error!("{}", explained.details());
+    }
+
+    explained
+}
+
+/// Redis answers EVALSHA with NOSCRIPT when a script isn't in its cache, like after a restart or failing over to a
+/// replica that never loaded it.
+fn is_missing_script(err: &RedisError) -> bool {
+    err.details().starts_with("NOSCRIPT")
 }
 
 #[derive(Error, Debug)]
 pub enum LockError {
     #[error("Error awaiting lock: {0}")]
     RecvError(#[from] RecvError),
+
+    #[error("Too many requests awaiting lock: {0}")]
+    TooManyWaiters(usize),
 }
 
 impl ProxyRedisClient {
@@ -124,36 +220,97 @@ impl ProxyRedisClient {
         let policy = ReconnectPolicy::default();
         let perf = PerformanceConfig::default();
 
+        // Only pooled commands time out, the subscriber waits on its channel indefinitely
+        let pool_perf = PerformanceConfig {
+            default_command_timeout_ms: env_config.command_timeout_ms,
+            ..perf.clone()
+        };
+
         let pool = RedisPool::new(
             config.clone(),
-            Some(perf.clone()),
+            Some(pool_perf.clone()),
             Some(policy.clone()),
             env_config.pool_size,
         )?;
 
+        let replica_pool = match &env_config.replica_host {
+            Some(_) if env_config.sentinel => {
+                eprintln!("REDIS_REPLICA_HOST is not supported with Redis Sentinel, ignoring it.");
+                None
+            }
+            Some(replica_host) => Some(RedisPool::new(
+                RedisConfig {
+                    server: ServerConfig::Centralized {
+                        server: Server {
+                            host: replica_host.clone().into(),
+                            port: env_config.replica_port,
+                            tls_server_name: None,
+                        },
+                    },
+                    ..config.clone()
+                },
+                Some(pool_perf),
+                Some(policy.clone()),
+                env_config.pool_size,
+            )?),
+            None => None,
+        };
+
         let pubsub_receiver = SubscriberClient::new(config, Some(perf), Some(policy));
 
         let instance = Self {
             pool,
+            replica_pool,
 
             pubsub_receiver,
-            pubsub_channels: Arc::new(RwLock::new(AHashMap::new())),
+            lock_waiters: LockWaiters::new(env_config.max_pending_lock_waiters),
 
             script_hashes: Arc::new(ProxyScriptHashes::new()),
+
+            reconnects: Arc::new(AtomicU64::new(0)),
+            last_reconnect_at: Arc::new(AtomicU64::new(0)),
         };
 
-        instance.pool.connect();
-        instance.pool.wait_for_connect().await?;
+        let connect_timeout = Duration::from_millis(env_config.connect_timeout_ms);
 
+        instance.pool.connect();
         instance.pubsub_receiver.connect();
-        instance.pubsub_receiver.wait_for_connect().await?;
+
+        if let Some(replica_pool) = &instance.replica_pool {
+            replica_pool.connect();
+        }
+
+        let connected = timeout(connect_timeout, async {
+            instance.pool.wait_for_connect().await?;
+            instance.pubsub_receiver.wait_for_connect().await?;
+
+            match &instance.replica_pool {
+                Some(replica_pool) => replica_pool.wait_for_connect().await,
+                None => Ok(()),
+            }
+        });
+
+        match connected.await {
+            Ok(result) => result?,
+            Err(_) => {
+                return Err(RedisError::new(
+                    RedisErrorKind::Timeout,
+                    format!(
+                        "Failed to connect to Redis within {}ms.",
+                        env_config.connect_timeout_ms
+                    ),
+                ))
+            }
+        }
 
         let mut reconnect_stream = instance.pool.on_reconnect();
         let reconnect_instance = instance.clone();
         tokio::spawn(async move {
-            while let Ok(_) = reconnect_stream.recv().await {
+            while reconnect_stream.recv().await.is_ok() {
                 println!("Pool reconnected to Redis.");
 
+                reconnect_instance.record_reconnect();
+
                 match reconnect_instance.register_scripts().await {
                     Ok(_) => tracing::debug!("Scripts reloaded."),
                     Err(e) => tracing::error!("Error reloading scripts: {}", e),
@@ -165,33 +322,120 @@ impl ProxyRedisClient {
 
         instance.register_scripts().await?;
 
+        if env_config.lock_sweep_interval_ms > 0 {
+            let sweep_instance = instance.clone();
+            let sweep_interval = Duration::from_millis(env_config.lock_sweep_interval_ms);
+            tokio::spawn(async move {
+                sweep_instance.start_lock_sweep(sweep_interval).await;
+            });
+        }
+
         let pubsub_instance = instance.clone();
+        let pubsub_retry_interval = Duration::from_millis(env_config.pubsub_retry_interval_ms);
+        let pubsub_max_attempts = env_config.pubsub_max_attempts;
         tokio::spawn(async move {
-            pubsub_instance.start_pubsub_task().await;
+            pubsub_instance
+                .start_pubsub_task(pubsub_retry_interval, pubsub_max_attempts)
+                .await;
         });
 
         Ok(instance)
     }
 
-    async fn register_scripts(&self) -> Result<(), RedisError> {
-        self.pool
-            .script_load::<(), &str>(SCRIPTS.check_global_and_route_rl)
-            .await?;
-        self.pool
-            .script_load::<(), &str>(SCRIPTS.check_route_rl)
-            .await?;
+    fn record_reconnect(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
 
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        self.last_reconnect_at.store(now, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        {
+            metrics::REDIS_RECONNECTS.inc();
+            metrics::REDIS_LAST_RECONNECT_TIMESTAMP.set((now / 1000) as i64);
+        }
+    }
+
+    /// How many times the pool has reconnected, and when it last did as a Unix timestamp in milliseconds.
+    pub fn reconnect_stats(&self) -> (u64, Option<u64>) {
+        let last_reconnect_at = match self.last_reconnect_at.load(Ordering::Relaxed) {
+            0 => None,
+            timestamp => Some(timestamp),
+        };
+
+        (self.reconnects.load(Ordering::Relaxed), last_reconnect_at)
+    }
+
+    /// Whether every connection in the pool is currently up.
+    pub fn is_connected(&self) -> bool {
         self.pool
-            .script_load::<(), &str>(SCRIPTS.release_global_lock)
-            .await?;
-        self.pool
-            .script_load::<(), &str>(SCRIPTS.set_route_expiry)
-            .await?;
+            .clients()
+            .iter()
+            .all(|client| client.is_connected())
+    }
+
+    async fn register_scripts(&self) -> Result<(), RedisError> {
+        let scripts = [
+            (
+                SCRIPTS.check_global_and_route_rl,
+                &self.script_hashes.check_global_and_route_rl,
+            ),
+            (SCRIPTS.check_global_rl, &self.script_hashes.check_global_rl),
+            (SCRIPTS.check_route_rl, &self.script_hashes.check_route_rl),
+            (SCRIPTS.peek_route_rl, &self.script_hashes.peek_route_rl),
+            (SCRIPTS.read_route_rl, &self.script_hashes.read_route_rl),
+            (
+                SCRIPTS.release_global_lock,
+                &self.script_hashes.release_global_lock,
+            ),
+            (
+                SCRIPTS.release_route_lock,
+                &self.script_hashes.release_route_lock,
+            ),
+            (
+                SCRIPTS.seed_route_limit,
+                &self.script_hashes.seed_route_limit,
+            ),
+            (
+                SCRIPTS.set_route_expiry,
+                &self.script_hashes.set_route_expiry,
+            ),
+        ];
+
+        // Scripts are cached server-wide, so loading them over a single pipelined connection is enough
+        let pipeline = self.pool.next().pipeline();
+        for (script, _) in scripts.iter() {
+            pipeline
+                .custom::<(), &str>(
+                    CustomCommand::new_static("SCRIPT", None, false),
+                    vec!["LOAD", script],
+                )
+                .await?;
+        }
+
+        let loaded_hashes: Vec<String> = pipeline.all().await?;
+
+        for ((_, expected_hash), loaded_hash) in scripts.iter().zip(loaded_hashes.iter()) {
+            if *expected_hash != loaded_hash {
+                return Err(RedisError::new(
+                    RedisErrorKind::Unknown,
+                    format!(
+                        "Loaded script hash {} does not match expected hash {}.",
+                        loaded_hash, expected_hash
+                    ),
+                ));
+            }
+        }
 
         Ok(())
     }
 
-    async fn start_pubsub_task(&self) -> () {
+    /// Failing to subscribe is logged as an error once it has happened this many times in a row
+    const PUBSUB_ESCALATE_AFTER_ATTEMPTS: u32 = 3;
+
+    async fn start_pubsub_task(&self, retry_interval: Duration, max_attempts: u32) -> () {
         let _self = self.clone();
 
         let mut message_stream = _self.pubsub_receiver.on_message();
@@ -203,7 +447,7 @@ impl ProxyRedisClient {
                     RedisValue::String(payload) => {
                         tracing::debug!("Received unlock over PubSub for {}.", &payload);
 
-                        _self.release_lock(&payload).await;
+                        _self.lock_waiters.release(&payload).await;
                     }
                     _ => tracing::warn!("Received unexpected message type over unlock channel."),
                 }
@@ -214,6 +458,7 @@ impl ProxyRedisClient {
 
         let manage_subscription_task = self.pubsub_receiver.manage_subscriptions();
 
+        let mut attempts: u32 = 0;
         loop {
             match self.pubsub_receiver.subscribe::<(), &str>("unlock").await {
                 Ok(_) => {
@@ -222,11 +467,36 @@ impl ProxyRedisClient {
                     break;
                 }
                 Err(e) => {
-                    tracing::error!(
-                        "Failed to subscribe to unlock channel. Retrying in 5 seconds: {:?}",
-                        e
-                    );
-                    sleep(Duration::from_secs(5)).await;
+                    attempts += 1;
+
+                    if max_attempts != 0 && attempts >= max_attempts {
+                        tracing::error!(
+                            "Failed to subscribe to unlock channel {} times, giving up. Lock releases from other instances won't be seen, so requests waiting on them will only retry after LOCK_WAIT_TIMEOUT: {:?}",
+                            attempts,
+                            e
+                        );
+
+                        return;
+                    }
+
+                    // A single failure is usually just Redis restarting, a subscriber that keeps failing quietly
+                    // turns every lock wait into a full timeout
+                    if attempts >= Self::PUBSUB_ESCALATE_AFTER_ATTEMPTS {
+                        tracing::error!(
+                            "Failed to subscribe to unlock channel {} times in a row. Retrying in {:?}: {:?}",
+                            attempts,
+                            retry_interval,
+                            e
+                        );
+                    } else {
+                        tracing::warn!(
+                            "Failed to subscribe to unlock channel. Retrying in {:?}: {:?}",
+                            retry_interval,
+                            e
+                        );
+                    }
+
+                    sleep(retry_interval).await;
 
                     continue;
                 }
@@ -244,114 +514,93 @@ impl ProxyRedisClient {
     }
 
     pub async fn await_lock(&self, key: &str) -> Result<(), LockError> {
-        let (tx, rx) = oneshot::channel::<()>();
-
-        let pubsub_channels_r = self.pubsub_channels.read().await;
-
-        async fn push_pending_client(channel: Arc<PubSubChannel>, tx: oneshot::Sender<()>) {
-            let mut pending_clients = channel.pending_clients.lock().await;
-            pending_clients.push(tx);
-
-            drop(pending_clients)
-        }
-
-        match pubsub_channels_r.get(key) {
-            Some(channel) => {
-                let channel = channel.clone();
-
-                push_pending_client(channel, tx).await;
-                drop(pubsub_channels_r);
-            }
-            None => {
-                drop(pubsub_channels_r);
-
-                let mut pubsub_channels_w = self.pubsub_channels.write().await;
-
-                if let Some(channel) = pubsub_channels_w.get(key) {
-                    tracing::debug!("Another thread subscribed to channel for key {} while this thread was waiting for the write lock, pushing to queue.", key);
-
-                    push_pending_client(channel.clone(), tx).await;
-                    drop(pubsub_channels_w);
-                } else {
-                    pubsub_channels_w.insert(
-                        key.to_string(),
-                        Arc::new(PubSubChannel {
-                            pending_clients: Arc::new(Mutex::new(vec![tx])),
-                        }),
-                    );
-
-                    drop(pubsub_channels_w);
-                }
-            }
-        };
-
-        rx.await?;
-        Ok(())
+        self.lock_waiters.wait(key).await
     }
 
     pub async fn cleanup_pending_locks(&self, key: &str) {
-        let pubsub_channels_r = self.pubsub_channels.read().await;
+        self.lock_waiters.cleanup(key).await
+    }
 
-        let mut emptied = false;
-        if let Some(channel) = pubsub_channels_r.get(key) {
-            let pending_clients_m = channel.pending_clients.clone();
-            drop(pubsub_channels_r);
+    /// Wakes requests waiting on locks that no longer exist, most likely because their holder crashed and the lock
+    /// expired, or its unlock message was missed. Each instance only wakes its own waiters, so nothing is published.
+    async fn start_lock_sweep(&self, interval: Duration) {
+        loop {
+            sleep(interval).await;
 
-            let mut pending_clients = pending_clients_m.lock().await;
+            let keys = self.lock_waiters.keys().await;
 
-            while let Some(index) = pending_clients.iter().position(|tx| tx.is_closed()) {
-                pending_clients.remove(index);
+            if keys.is_empty() {
+                continue;
             }
 
-            if pending_clients.len() == 0 {
-                emptied = true;
-            }
-
-            drop(pending_clients);
-        }
-
-        if emptied {
-            let mut pubsub_channels_w = self.pubsub_channels.write().await;
-
-            let channel = match pubsub_channels_w.get(key) {
-                Some(channel_m) => channel_m,
-                None => return,
+            let exists = match self.locks_exist(&keys).await {
+                Ok(exists) => exists,
+                Err(err) => {
+                    tracing::debug!("Failed to check for stale locks: {:?}", err);
+                    continue;
+                }
             };
 
-            let pending_clients = channel.pending_clients.lock().await;
+            for (key, _) in keys.iter().zip(exists).filter(|(_, exists)| !exists) {
+                tracing::debug!("Lock on {} is gone, waking its waiters.", key);
 
-            let pending_client_len = pending_clients.len();
-            drop(pending_clients);
-
-            if pending_client_len == 0 {
-                pubsub_channels_w.remove(key);
+                self.lock_waiters.release(key).await;
             }
-
-            drop(pubsub_channels_w);
         }
     }
 
-    async fn release_lock(&self, key: &str) {
-        let mut pubsub_channels_w = self.pubsub_channels.write().await;
+    async fn locks_exist(&self, keys: &[String]) -> Result<Vec<bool>, RedisError> {
+        let pipeline = self.pool.next().pipeline();
+        for key in keys {
+            pipeline.exists::<(), _>(format!("{}:lock", key)).await?;
+        }
 
-        let channel = match pubsub_channels_w.get(key) {
-            Some(channel_m) => channel_m.clone(),
-            None => return,
-        };
+        pipeline.all().await
+    }
 
-        pubsub_channels_w.remove(key);
-        drop(pubsub_channels_w);
+    /// Runs a script by its hash, falling back to sending the full script if Redis doesn't have it cached (e.g. after a failover).
+    async fn eval_script<R, K, V>(
+        &self,
+        hash: &str,
+        script: &'static str,
+        keys: K,
+        args: V,
+    ) -> Result<R, RedisError>
+    where
+        R: FromRedis,
+        K: Into<MultipleKeys> + Send,
+        V: TryInto<MultipleValues> + Send,
+        V::Error: Into<RedisError> + Send,
+    {
+        let keys: MultipleKeys = keys.into();
+        let args: MultipleValues = args.try_into().map_err(|e| e.into())?;
+
+        match self
+            .pool
+            .evalsha::<R, &str, _, _>(hash, keys.clone(), args.clone())
+            .await
+        {
+            Err(err) if is_missing_script(&err) => {
+                tracing::warn!(
+                    "Script {} is missing from Redis, falling back to EVAL and reloading scripts.",
+                    hash
+                );
+
+                let reload_instance = self.clone();
+                tokio::spawn(async move {
+                    match reload_instance.register_scripts().await {
+                        Ok(_) => tracing::debug!("Scripts reloaded."),
+                        Err(e) => tracing::error!("Error reloading scripts: {}", e),
+                    }
+                });
 
-        let mut pending_clients = channel.pending_clients.lock().await;
-        for tx in pending_clients.drain(..) {
-            match tx.send(()) {
-                Ok(_) => (),
-                Err(e) => tracing::error!("Error completing a pending lock on {}: {:?}", key, e),
+                self.pool
+                    .eval::<R, &str, _, _>(script, keys, args)
+                    .await
+                    .map_err(explain_cluster_redirect)
             }
+            result => result.map_err(explain_cluster_redirect),
         }
-        drop(pending_clients);
-
-        drop(channel);
     }
 
     pub async fn check_global_and_route_rl(
@@ -359,29 +608,110 @@ impl ProxyRedisClient {
         global_id_redis_key: &str,
         time_slice: &str,
         route_bucket_redis_key: &str,
-        lock_token: &str,
+        args: &RatelimitCheckArgs<'_>,
     ) -> Result<Vec<String>, RedisError> {
-        self.pool
-            .evalsha::<Vec<String>, &str, Vec<&str>, _>(
-                &self.script_hashes.check_global_and_route_rl,
-                vec![global_id_redis_key, time_slice, route_bucket_redis_key],
-                lock_token,
-            )
-            .await
+        self.eval_script::<Vec<String>, Vec<&str>, Vec<String>>(
+            &self.script_hashes.check_global_and_route_rl,
+            SCRIPTS.check_global_and_route_rl,
+            vec![global_id_redis_key, time_slice, route_bucket_redis_key],
+            vec![
+                args.lock_token.to_string(),
+                args.cost.to_string(),
+                args.lock_ttl_secs.to_string(),
+                args.global_loose_limit.to_string(),
+                args.route_loose_limit.to_string(),
+                args.route_safety_margin.to_string(),
+            ],
+        )
+        .await
+    }
+
+    pub async fn check_global_rl(
+        &self,
+        global_id_redis_key: &str,
+        time_slice: &str,
+        args: &RatelimitCheckArgs<'_>,
+    ) -> Result<Vec<String>, RedisError> {
+        self.eval_script::<Vec<String>, Vec<&str>, Vec<String>>(
+            &self.script_hashes.check_global_rl,
+            SCRIPTS.check_global_rl,
+            vec![global_id_redis_key, time_slice],
+            vec![
+                args.lock_token.to_string(),
+                args.cost.to_string(),
+                args.lock_ttl_secs.to_string(),
+                args.global_loose_limit.to_string(),
+            ],
+        )
+        .await
     }
 
     pub async fn check_route_rl(
         &self,
         route_rl_key: &str,
-        lock_token: &str,
+        args: &RatelimitCheckArgs<'_>,
     ) -> Result<Vec<String>, RedisError> {
-        self.pool
-            .evalsha::<Vec<String>, &str, &str, _>(
-                &self.script_hashes.check_route_rl,
+        self.eval_script::<Vec<String>, &str, Vec<String>>(
+            &self.script_hashes.check_route_rl,
+            SCRIPTS.check_route_rl,
+            route_rl_key,
+            vec![
+                args.lock_token.to_string(),
+                args.cost.to_string(),
+                args.lock_ttl_secs.to_string(),
+                args.route_loose_limit.to_string(),
+                args.route_safety_margin.to_string(),
+            ],
+        )
+        .await
+    }
+
+    /// Checks a replica for whether a known route bucket is already exhausted, returning the ratelimited result if so.
+    /// Anything else, including replica errors, falls back to the full check on the master.
+    pub async fn peek_route_rl(
+        &self,
+        route_rl_key: &str,
+        args: &RatelimitCheckArgs<'_>,
+    ) -> Option<Vec<String>> {
+        let replica_pool = self.replica_pool.as_ref()?;
+        let peek_args = vec![args.cost.to_string(), args.route_safety_margin.to_string()];
+
+        let result = match replica_pool
+            .evalsha::<Vec<String>, &str, _, _>(
+                &self.script_hashes.peek_route_rl,
                 route_rl_key,
-                lock_token,
+                peek_args.clone(),
             )
             .await
+        {
+            Err(err) if is_missing_script(&err) => {
+                replica_pool
+                    .eval::<Vec<String>, &str, _, _>(SCRIPTS.peek_route_rl, route_rl_key, peek_args)
+                    .await
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(data) if data.first().map(String::as_str) == Some("2") => Some(data),
+            Ok(_) => None,
+            Err(err) => {
+                let err = explain_cluster_redirect(err);
+                tracing::debug!("Replica ratelimit check failed: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Reads what is stored for a route bucket without counting against it, see `read_route_rl.lua` for the layout.
+    pub async fn read_route_rl(&self, route_rl_key: &str) -> Result<Vec<i64>, RedisError> {
+        self.eval_script(
+            &self.script_hashes.read_route_rl,
+            SCRIPTS.read_route_rl,
+            route_rl_key,
+            Vec::<String>::new(),
+        )
+        .await
     }
 
     pub async fn release_global_lock(
@@ -391,44 +721,636 @@ impl ProxyRedisClient {
         ratelimit: u16,
         ratelimit_info_expires_in: u64,
     ) -> Result<bool, RedisError> {
-        self.pool
-            .evalsha::<Option<bool>, &str, &str, Vec<&str>>(
-                &self.script_hashes.release_global_lock,
-                global_id_redis_key,
-                vec![
-                    &lock_token,
-                    &ratelimit.to_string(),
-                    &ratelimit_info_expires_in.to_string(),
-                ],
-            )
+        self.eval_script::<Option<bool>, &str, Vec<&str>>(
+            &self.script_hashes.release_global_lock,
+            SCRIPTS.release_global_lock,
+            global_id_redis_key,
+            vec![
+                &lock_token,
+                &ratelimit.to_string(),
+                &ratelimit_info_expires_in.to_string(),
+            ],
+        )
+        .await
+        .map(|r| r.unwrap_or(false))
+    }
+
+    /// Releases a global lock without storing a limit, for holders that gave up before learning it. Waiters then take
+    /// the lock again themselves.
+    pub async fn abandon_global_lock(
+        &self,
+        global_id_redis_key: &str,
+        lock_token: &str,
+    ) -> Result<bool, RedisError> {
+        // Both kinds of lock live under `{key}:lock`, and the route script only compares and deletes it
+        self.release_route_lock(global_id_redis_key, lock_token)
             .await
-            .map(|r| r.unwrap_or(false))
+    }
+
+    pub async fn release_route_lock(
+        &self,
+        route_rl_redis_key: &str,
+        lock_token: &str,
+    ) -> Result<bool, RedisError> {
+        self.eval_script::<Option<bool>, &str, &str>(
+            &self.script_hashes.release_route_lock,
+            SCRIPTS.release_route_lock,
+            route_rl_redis_key,
+            lock_token,
+        )
+        .await
+        .map(|r| r.unwrap_or(false))
     }
 
     pub async fn set_route_expiry(
         &self,
         route_rl_redis_key: &str,
         lock_token: Option<String>,
+        args: &RouteExpiryArgs,
+    ) -> Result<bool, RedisError> {
+        self.eval_script::<Option<bool>, &str, Vec<&str>>(
+            &self.script_hashes.set_route_expiry,
+            SCRIPTS.set_route_expiry,
+            route_rl_redis_key,
+            vec![
+                &lock_token.unwrap_or_default(),
+                &args.limit.to_string(),
+                &args.remaining.to_string(),
+                &args.reset_at.to_string(),
+                &args.reset_after.to_string(),
+                &args.route_info_expire_in.to_string(),
+                &args.route_info_idle_ttl.to_string(),
+            ],
+        )
+        .await
+        .map(|r| r.unwrap_or(false))
+    }
+
+    pub async fn get_discord_bucket(
+        &self,
+        route_bucket: &str,
+    ) -> Result<Option<String>, RedisError> {
+        self.pool.get(discord_bucket_redis_key(route_bucket)).await
+    }
+
+    pub async fn set_discord_bucket(
+        &self,
+        route_bucket: &str,
+        bucket_hash: &str,
+        bucket_ttl_ms: u64,
+    ) -> Result<(), RedisError> {
+        let expiration = match bucket_ttl_ms {
+            0 => None,
+            ttl => Some(Expiration::PX(ttl as i64)),
+        };
+
+        self.pool
+            .set(
+                discord_bucket_redis_key(route_bucket),
+                bucket_hash,
+                expiration,
+                None,
+                false,
+            )
+            .await
+    }
+
+    /// Returns the limit of every known route bucket, keyed by its Redis key, along with how long its windows are if
+    /// that is known.
+    pub async fn get_route_limits(&self) -> Result<Vec<(String, u16, Option<u64>)>, RedisError> {
+        let client = self.pool.next();
+
+        let mut keys = Vec::new();
+        for pattern in ["*route:*", "*bucket:*"] {
+            let mut pages = client.scan(pattern, Some(1000), None);
+
+            while let Some(page) = pages.next().await {
+                let mut page = page?;
+
+                if let Some(results) = page.take_results() {
+                    keys.extend(
+                        results
+                            .into_iter()
+                            .filter_map(|key| key.into_string())
+                            .filter(|key| is_route_limit_key(key)),
+                    );
+                }
+
+                page.next()?;
+            }
+        }
+
+        let mut limits = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(1000) {
+            let pipeline = client.pipeline();
+            for key in chunk {
+                pipeline.get::<(), _>(key.as_str()).await?;
+                pipeline.get::<(), _>(format!("{}:window", key)).await?;
+            }
+
+            let values: Vec<Option<String>> = pipeline.all().await?;
+
+            limits.extend(
+                chunk
+                    .iter()
+                    .zip(values.chunks(2))
+                    .filter_map(|(key, values)| {
+                        let limit = values[0].as_ref()?.parse().ok()?;
+                        let window = values[1].as_ref().and_then(|window| window.parse().ok());
+
+                        Some((key.clone(), limit, window))
+                    }),
+            );
+        }
+
+        Ok(limits)
+    }
+
+    /// Sets a route bucket's limit unless it is already known, returns whether it was set. With the length of its
+    /// windows, the bucket also opens one, so it's ratelimited rather than locked once it runs out.
+    pub async fn seed_route_limit(
+        &self,
+        route_rl_redis_key: &str,
         limit: u16,
-        remaining: u16,
-        reset_at: u64,
-        reset_after: u64,
-        route_info_expire_in: u64,
+        window_ms: Option<u64>,
+        bucket_ttl_ms: u64,
     ) -> Result<bool, RedisError> {
+        self.eval_script::<Option<bool>, &str, Vec<String>>(
+            &self.script_hashes.seed_route_limit,
+            SCRIPTS.seed_route_limit,
+            route_rl_redis_key,
+            vec![
+                limit.to_string(),
+                bucket_ttl_ms.to_string(),
+                window_ms.unwrap_or_default().to_string(),
+            ],
+        )
+        .await
+        .map(|r| r.unwrap_or(false))
+    }
+
+    /// Marks an idempotency key as in flight, returns false if it was already used.
+    pub async fn claim_idempotency_key(&self, key: &str, ttl_ms: u64) -> Result<bool, RedisError> {
         self.pool
-            .evalsha::<Option<bool>, &str, &str, Vec<&str>>(
-                &self.script_hashes.set_route_expiry,
-                route_rl_redis_key,
-                vec![
-                    &lock_token.unwrap_or_default(),
-                    &limit.to_string(),
-                    &remaining.to_string(),
-                    &reset_at.to_string(),
-                    &reset_after.to_string(),
-                    &route_info_expire_in.to_string(),
-                ],
+            .set::<Option<String>, _, _>(
+                key,
+                PENDING,
+                Some(Expiration::PX(ttl_ms as i64)),
+                Some(SetOptions::NX),
+                false,
             )
             .await
-            .map(|r| r.unwrap_or(false))
+            .map(|result| result.is_some())
+    }
+
+    pub async fn get_idempotency_key(&self, key: &str) -> Result<Option<String>, RedisError> {
+        self.pool.get(key).await
+    }
+
+    pub async fn set_idempotency_key(
+        &self,
+        key: &str,
+        value: String,
+        ttl_ms: u64,
+    ) -> Result<(), RedisError> {
+        self.pool
+            .set(key, value, Some(Expiration::PX(ttl_ms as i64)), None, false)
+            .await
+    }
+
+    pub async fn delete_idempotency_key(&self, key: &str) -> Result<(), RedisError> {
+        self.pool.del(key).await
+    }
+}
+
+/// Route buckets store their limit under the bare key, with their other state under suffixed keys
+fn is_route_limit_key(key: &str) -> bool {
+    !key.starts_with("discord_bucket:")
+        && !key.starts_with("idempotency:")
+        && ![":count", ":lock", ":reset_after", ":window"]
+            .iter()
+            .any(|suffix| key.ends_with(suffix))
+}
+
+fn discord_bucket_redis_key(route_bucket: &str) -> String {
+    format!("discord_bucket:{{{}}}", route_bucket)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::AppEnvConfig;
+
+    use super::*;
+
+    fn check_args(lock_token: &str) -> RatelimitCheckArgs<'_> {
+        RatelimitCheckArgs {
+            lock_token,
+            cost: 1,
+            lock_ttl_secs: 5,
+            global_loose_limit: 0,
+            route_loose_limit: 0,
+            route_safety_margin: 0,
+        }
+    }
+
+    #[test]
+    fn only_route_limits_are_exported() {
+        assert!(is_route_limit_key("route:{channels/1/messages/!}"));
+        assert!(is_route_limit_key("1234:route:{channels/1/messages/!}"));
+
+        assert!(!is_route_limit_key("route:{channels/1/messages/!}:count"));
+        assert!(!is_route_limit_key("route:{channels/1/messages/!}:lock"));
+        assert!(!is_route_limit_key(
+            "route:{channels/1/messages/!}:reset_after"
+        ));
+        assert!(!is_route_limit_key("route:{channels/1/messages/!}:window"));
+        assert!(!is_route_limit_key(
+            "discord_bucket:{channels/1/messages/!}"
+        ));
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn preloaded_buckets_skip_the_lock_path() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+        let key = format!("route:{{preload-test/{}}}", rand::random::<u64>());
+
+        assert!(redis
+            .seed_route_limit(&key, 2, Some(10_000), 60_000)
+            .await
+            .unwrap());
+        // Known buckets are left alone
+        assert!(!redis
+            .seed_route_limit(&key, 5, Some(10_000), 60_000)
+            .await
+            .unwrap());
+
+        for count in ["1", "2"] {
+            let reply = redis.check_route_rl(&key, &check_args("a")).await.unwrap();
+            assert_eq!(reply, ["5", "nil", "nil", "2", count]);
+        }
+
+        // Running out is a ratelimit, not a lock on the bucket
+        let reply = redis.check_route_rl(&key, &check_args("b")).await.unwrap();
+        assert_eq!(reply[..2], ["2", "2"]);
+        assert!(reply[3].parse::<i64>().unwrap() > 0);
+        assert!(!redis.release_route_lock(&key, "b").await.unwrap());
+
+        assert_eq!(
+            redis
+                .get_route_limits()
+                .await
+                .unwrap()
+                .into_iter()
+                .find(|(k, ..)| *k == key),
+            Some((key.clone(), 2, Some(10_000)))
+        );
+    }
+
+    #[test]
+    fn only_noscript_errors_fall_back_to_eval() {
+        assert!(is_missing_script(&RedisError::new(
+            RedisErrorKind::Unknown,
+            "NOSCRIPT No matching script. Please use EVAL."
+        )));
+
+        assert!(!is_missing_script(&RedisError::new(
+            RedisErrorKind::Unknown,
+            "BUSY Redis is busy running a script."
+        )));
+        assert!(!is_missing_script(&RedisError::new(
+            RedisErrorKind::Timeout,
+            "Request timed out."
+        )));
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn flushed_scripts_are_evaluated_and_reloaded() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+        let key = format!("route:{{noscript-test/{}}}", rand::random::<u64>());
+
+        redis.pool.script_flush(false).await.unwrap();
+
+        let reply = redis.check_route_rl(&key, &check_args("a")).await.unwrap();
+        assert_eq!(reply[0], "5");
+
+        // Scripts are reloaded in the background
+        sleep(Duration::from_millis(200)).await;
+        let loaded: Vec<bool> = redis
+            .pool
+            .script_exists(redis.script_hashes.check_route_rl.as_str())
+            .await
+            .unwrap();
+        assert_eq!(loaded, [true]);
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn weighted_requests_exhaust_buckets_faster() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+
+        for (cost, allowed) in [(1, 4), (2, 2), (3, 1)] {
+            let key = format!("route:{{cost-test/{}}}", rand::random::<u64>());
+            redis
+                .seed_route_limit(&key, 4, Some(10_000), 60_000)
+                .await
+                .unwrap();
+
+            let args = RatelimitCheckArgs {
+                cost,
+                ..check_args("a")
+            };
+
+            for _ in 0..allowed {
+                let reply = redis.check_route_rl(&key, &args).await.unwrap();
+                assert_eq!(reply[0], "5", "cost {}", cost);
+            }
+
+            let reply = redis.check_route_rl(&key, &args).await.unwrap();
+            assert_eq!(reply[0], "2", "cost {}", cost);
+        }
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn loose_buckets_let_first_requests_through_in_parallel() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+
+        // With no limit known, strict buckets only let their lock holder through
+        for (loose_limit, let_through) in [(0, 1), (3, 3)] {
+            let route_key = format!("route:{{loose-test/{}}}", rand::random::<u64>());
+            let global_key = format!("global:{{loose-test/{}}}", rand::random::<u64>());
+
+            let args = |lock_token| RatelimitCheckArgs {
+                global_loose_limit: loose_limit,
+                route_loose_limit: loose_limit,
+                ..check_args(lock_token)
+            };
+
+            let probe = redis
+                .check_route_rl(&route_key, &args("probe"))
+                .await
+                .unwrap();
+            assert_eq!(probe[..3], ["5", "nil", "1"]);
+
+            let probe = redis
+                .check_global_rl(&global_key, ":0", &args("probe"))
+                .await
+                .unwrap();
+            assert_eq!(probe[..2], ["5", "1"]);
+
+            for _ in 1..let_through {
+                let reply = redis
+                    .check_route_rl(&route_key, &args("other"))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    reply[..3],
+                    ["5", "nil", "nil"],
+                    "loose limit {}",
+                    loose_limit
+                );
+
+                let reply = redis
+                    .check_global_rl(&global_key, ":0", &args("other"))
+                    .await
+                    .unwrap();
+                assert_eq!(reply[..2], ["5", "nil"], "loose limit {}", loose_limit);
+            }
+
+            // The rest wait on the locks
+            let reply = redis
+                .check_route_rl(&route_key, &args("other"))
+                .await
+                .unwrap();
+            assert_eq!(reply, ["3"], "loose limit {}", loose_limit);
+
+            let reply = redis
+                .check_global_rl(&global_key, ":0", &args("other"))
+                .await
+                .unwrap();
+            assert_eq!(reply, ["1"], "loose limit {}", loose_limit);
+        }
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn corrupt_counters_are_reset() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+
+        for corrupt_count in ["-3", "not a number"] {
+            let route_key = format!("route:{{corrupt-test/{}}}", rand::random::<u64>());
+            redis
+                .seed_route_limit(&route_key, 5, Some(10_000), 60_000)
+                .await
+                .unwrap();
+            redis
+                .pool
+                .set::<(), _, _>(
+                    format!("{}:count", route_key),
+                    corrupt_count,
+                    None,
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let reply = redis
+                .check_route_rl(&route_key, &check_args("a"))
+                .await
+                .unwrap();
+            assert_eq!(reply, ["6", "route"]);
+
+            // The retry starts the bucket's count over
+            let reply = redis
+                .check_route_rl(&route_key, &check_args("a"))
+                .await
+                .unwrap();
+            assert_eq!(reply, ["5", "nil", "nil", "5", "1"]);
+
+            let global_key = format!("global:{{corrupt-test/{}}}", rand::random::<u64>());
+            redis
+                .pool
+                .set::<(), _, _>(
+                    format!("{}:0", global_key),
+                    corrupt_count,
+                    None,
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let reply = redis
+                .check_global_rl(&global_key, ":0", &check_args("a"))
+                .await
+                .unwrap();
+            assert_eq!(reply, ["6", "global"]);
+
+            let reply = redis
+                .check_global_rl(&global_key, ":0", &check_args("a"))
+                .await
+                .unwrap();
+            assert_eq!(reply[0], "5");
+        }
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn safety_margins_close_buckets_early() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+
+        // Even a margin past the limit leaves one request per window
+        for (margin, allowed) in [(0, 5), (2, 3), (10, 1)] {
+            let key = format!("route:{{margin-test/{}}}", rand::random::<u64>());
+            redis
+                .seed_route_limit(&key, 5, Some(10_000), 60_000)
+                .await
+                .unwrap();
+
+            let args = RatelimitCheckArgs {
+                route_safety_margin: margin,
+                ..check_args("a")
+            };
+
+            for _ in 0..allowed {
+                let reply = redis.check_route_rl(&key, &args).await.unwrap();
+                assert_eq!(reply[0], "5", "margin {}", margin);
+            }
+
+            let reply = redis.check_route_rl(&key, &args).await.unwrap();
+            assert_eq!(reply[0], "2", "margin {}", margin);
+        }
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn only_one_simultaneous_first_request_is_let_through() {
+        let redis = ProxyRedisClient::new(AppEnvConfig::from_env().redis)
+            .await
+            .unwrap();
+        let tokens = (0..20).map(|i| i.to_string()).collect::<Vec<_>>();
+        let args = tokens
+            .iter()
+            .map(|token| check_args(token))
+            .collect::<Vec<_>>();
+
+        let route_key = format!("route:{{burst-test/{}}}", rand::random::<u64>());
+        let replies = futures_util::future::join_all(
+            args.iter()
+                .map(|args| redis.check_route_rl(&route_key, args)),
+        )
+        .await;
+
+        let (probes, waiting): (Vec<_>, Vec<_>) = replies
+            .into_iter()
+            .map(Result::unwrap)
+            .partition(|reply| reply[0] == "5");
+        assert_eq!(probes.len(), 1);
+        assert!(waiting.iter().all(|reply| *reply == ["3"]));
+
+        // A new bot is locked on its global ratelimit first
+        let global_key = format!("global:{{burst-test/{}}}", rand::random::<u64>());
+        let route_key = format!("{}-route:channels/1", global_key);
+        let replies = futures_util::future::join_all(
+            args.iter()
+                .map(|args| redis.check_global_and_route_rl(&global_key, ":0", &route_key, args)),
+        )
+        .await;
+
+        let (probes, waiting): (Vec<_>, Vec<_>) = replies
+            .into_iter()
+            .map(Result::unwrap)
+            .partition(|reply| reply[0] == "5");
+        assert_eq!(probes.len(), 1);
+        assert_eq!(probes[0][1..3], ["1", "1"]);
+        assert!(waiting.iter().all(|reply| *reply == ["1"]));
+    }
+
+    async fn lock_and_wait(holder: &ProxyRedisClient, waiter: &ProxyRedisClient, key: &str) {
+        // Give both subscribers time to join the unlock channel
+        sleep(Duration::from_millis(200)).await;
+
+        holder
+            .check_route_rl(key, &check_args("holder"))
+            .await
+            .unwrap();
+        assert!(lock_held(waiter, key).await);
+    }
+
+    async fn lock_held(redis: &ProxyRedisClient, key: &str) -> bool {
+        redis.locks_exist(&[key.to_string()]).await.unwrap()[0]
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn releasing_a_lock_wakes_other_instances() {
+        let config = AppEnvConfig::from_env().redis;
+        let holder = ProxyRedisClient::new(config.clone()).await.unwrap();
+        let waiter = ProxyRedisClient::new(config).await.unwrap();
+        let key = format!("route:{{unlock-test/{}}}", rand::random::<u64>());
+
+        lock_and_wait(&holder, &waiter, &key).await;
+
+        let (woken, released) = tokio::join!(
+            timeout(Duration::from_secs(2), waiter.await_lock(&key)),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                holder.release_route_lock(&key, "holder").await
+            }
+        );
+
+        assert!(released.unwrap());
+        woken.expect("waiter was never woken").unwrap();
+    }
+
+    /// Runs against the Redis configured through the environment, with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn the_lock_sweep_wakes_waiters_on_vanished_locks() {
+        let mut config = (*AppEnvConfig::from_env().redis).clone();
+        config.lock_sweep_interval_ms = 0;
+        let holder = ProxyRedisClient::new(Arc::new(config.clone()))
+            .await
+            .unwrap();
+        config.lock_sweep_interval_ms = 100;
+        let waiter = ProxyRedisClient::new(Arc::new(config)).await.unwrap();
+        let key = format!("route:{{sweep-test/{}}}", rand::random::<u64>());
+
+        lock_and_wait(&holder, &waiter, &key).await;
+
+        // The lock expiring publishes nothing, only the sweep can notice it's gone
+        let (woken, deleted) = tokio::join!(
+            timeout(Duration::from_secs(2), waiter.await_lock(&key)),
+            async {
+                sleep(Duration::from_millis(100)).await;
+                holder.pool.del::<u8, _>(format!("{}:lock", key)).await
+            }
+        );
+
+        assert_eq!(deleted.unwrap(), 1);
+        woken.expect("waiter was never woken").unwrap();
     }
 }
@@ -1,28 +1,49 @@
-local function lock_bucket(key, token)
-    local result = redis.call('SET', key .. ':lock', token, 'NX', 'EX', '5')
+local function lock_bucket(key, token, ttl)
+    local result = redis.call('SET', key .. ':lock', token, 'NX', 'EX', ttl)
     return result ~= false
 end
 
-local function increment_global_count(key)
-    local global_count = redis.call('INCR', key)
+local function increment_global_count(key, cost)
+    local global_count = redis.call('INCRBY', key, cost)
 
-    if global_count == 1 then
+    if global_count == cost then
         redis.call('EXPIRE', key, 3)
     end
 
     return global_count
 end
 
-local function increment_route_count(key)
-    local route_count = tonumber(redis.call('INCR', key))
+local function increment_route_count(key, cost)
+    local route_count = tonumber(redis.call('INCRBY', key, cost))
     
-    if route_count == 1 then
+    -- Counters that were opened with a window of their own, like a preloaded bucket's, keep it
+    if route_count == cost and redis.call('PTTL', key) == -1 then
         redis.call('EXPIRE', key, 60)
     end
 
     return route_count
 end
 
+-- Loose strategy: while a bucket's limit is unknown, let a few requests through instead of waiting on its lock
+local function within_loose_limit(count, cost, loose_limit)
+    return loose_limit > 0 and count + cost <= loose_limit
+end
+
+-- Counters only go negative or unreadable through an accounting bug, resetting them lets the bucket recover on its own
+local function is_corrupt_count(raw_count)
+    if raw_count == false then
+        return false
+    end
+
+    local count = tonumber(raw_count)
+    return count == nil or count < 0
+end
+
+-- A known route limit is treated as this much lower, so the bucket is closed off before Discord's own 429s
+local function effective_limit(limit, safety_margin)
+    return math.max(limit - safety_margin, 1)
+end
+
 local global_key = KEYS[1]
 local time_slice = KEYS[2]
 local global_count_key = global_key .. time_slice
@@ -31,9 +52,24 @@ local route_key = KEYS[3]
 local route_count_key = route_key .. ':count'
 
 local lock_token = ARGV[1]
+local cost = tonumber(ARGV[2])
+local lock_ttl = ARGV[3]
+local global_loose_limit = tonumber(ARGV[4])
+local route_loose_limit = tonumber(ARGV[5])
+local route_safety_margin = tonumber(ARGV[6])
 
 local ratelimits = redis.call('MGET', global_key, route_key, global_count_key, route_count_key)
 
+if is_corrupt_count(ratelimits[3]) then
+    redis.call('DEL', global_count_key)
+    return {6, 'global'}
+end
+
+if is_corrupt_count(ratelimits[4]) then
+    redis.call('DEL', route_count_key)
+    return {6, 'route'}
+end
+
 local global_limit = tonumber(ratelimits[1])
 local route_limit = tonumber(ratelimits[2])
 
@@ -53,28 +89,28 @@ local holds_route_lock = false
 
 if route_limit == nil then
     if global_limit == nil then
-        holds_global_lock = lock_bucket(global_key, lock_token)
+        holds_global_lock = lock_bucket(global_key, lock_token, lock_ttl)
 
-        if holds_global_lock == false then
+        if holds_global_lock == false and not within_loose_limit(global_count, cost, global_loose_limit) then
             return 1
         end
     else
-        if global_count + 1 > global_limit then
+        if global_count + cost > global_limit then
             return {0, global_limit}
         end
     end
 
-    holds_route_lock = lock_bucket(route_key, lock_token)
+    holds_route_lock = lock_bucket(route_key, lock_token, lock_ttl)
 
-    if holds_route_lock == false then
+    if holds_route_lock == false and not within_loose_limit(route_count, cost, route_loose_limit) then
         if holds_global_lock then
             return 4
         else
-            return 1
+            return 3
         end
     end
 else
-    if route_count + 1 > route_limit then
+    if route_count + cost > effective_limit(route_limit, route_safety_margin) then
         local reset_after = redis.call('PTTL', route_key .. ':reset_after')
 
         if reset_after ~= -2 then
@@ -84,18 +120,18 @@ else
         end
 
         if global_limit == nil then
-            holds_global_lock = lock_bucket(global_key, lock_token)
+            holds_global_lock = lock_bucket(global_key, lock_token, lock_ttl)
 
-            if holds_global_lock == false then
+            if holds_global_lock == false and not within_loose_limit(global_count, cost, global_loose_limit) then
                 return 1
             end
         else
-            if global_count + 1 > global_limit then
+            if global_count + cost > global_limit then
                 return {0, global_limit}
             end
         end
 
-        holds_route_lock = lock_bucket(route_key, lock_token)
+        holds_route_lock = lock_bucket(route_key, lock_token, lock_ttl)
 
         if holds_route_lock == false then
             if holds_global_lock then
@@ -106,20 +142,20 @@ else
         end
     else
         if global_limit == nil then
-            holds_global_lock = lock_bucket(global_key, lock_token)
+            holds_global_lock = lock_bucket(global_key, lock_token, lock_ttl)
 
-            if holds_global_lock == false then
+            if holds_global_lock == false and not within_loose_limit(global_count, cost, global_loose_limit) then
                 return 1
             end
         else
-            if global_count + 1 > global_limit then
+            if global_count + cost > global_limit then
                 return {0, global_limit}
             end
         end
     end
 end
 
-increment_global_count(global_count_key)
-increment_route_count(route_count_key)
+increment_global_count(global_count_key, cost)
+route_count = increment_route_count(route_count_key, cost)
 
-return {5, holds_global_lock, holds_route_lock}
\ No newline at end of file
+return {5, holds_global_lock, holds_route_lock, route_limit or 0, route_count}
\ No newline at end of file
@@ -1,40 +1,65 @@
--- I don't think we need this, but its here incase/for reference
-
-local function lock_bucket(key, token)
-    local result = redis.call('SET', key .. ':lock', token, 'NX', 'EX', '5')
+local function lock_bucket(key, token, ttl)
+    local result = redis.call('SET', key .. ':lock', token, 'NX', 'EX', ttl)
     return result ~= false
 end
 
-local function increment_global_count(key)
-    local global_count = redis.call('INCR', key)
+local function increment_global_count(key, cost)
+    local global_count = redis.call('INCRBY', key, cost)
 
-    if global_count == 1 then
+    if global_count == cost then
         redis.call('EXPIRE', key, 3)
     end
 
     return global_count
 end
 
+-- Loose strategy: while a bucket's limit is unknown, let a few requests through instead of waiting on its lock
+local function within_loose_limit(count, cost, loose_limit)
+    return loose_limit > 0 and count + cost <= loose_limit
+end
+
+-- Counters only go negative or unreadable through an accounting bug, resetting them lets the bucket recover on its own
+local function is_corrupt_count(raw_count)
+    if raw_count == false then
+        return false
+    end
+
+    local count = tonumber(raw_count)
+    return count == nil or count < 0
+end
+
 local global_key = KEYS[1]
 local time_slice = KEYS[2]
 local global_count_key = global_key .. time_slice
 
 local lock_token = ARGV[1]
+local cost = tonumber(ARGV[2])
+local lock_ttl = ARGV[3]
+local global_loose_limit = tonumber(ARGV[4])
+
+if is_corrupt_count(redis.call('GET', global_count_key)) then
+    redis.call('DEL', global_count_key)
+    return {6, 'global'}
+end
 
 local global_limit = tonumber(redis.call('GET', global_key))
 
 local holds_global_lock = false
 if global_limit == nil then
-    holds_global_lock = lock_bucket(global_key, lock_token)
+    holds_global_lock = lock_bucket(global_key, lock_token, lock_ttl)
 
     if holds_global_lock == false then
-        return 1
+        local global_count = tonumber(redis.call('GET', global_count_key)) or 0
+
+        if not within_loose_limit(global_count, cost, global_loose_limit) then
+            return 1
+        end
     end
 end
 
-local global_count = increment_global_count(global_count_key)
+local global_count = increment_global_count(global_count_key, cost)
 
-if global_count > global_limit then
+if holds_global_lock == false and global_limit ~= nil and global_count > global_limit then
     return {0, global_limit}
 end
 
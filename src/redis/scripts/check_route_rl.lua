@@ -1,37 +1,71 @@
-local function lock_bucket(key, token)
-    local result = redis.call('SET', key .. ':lock', token, 'NX', 'EX', '5')
+local function lock_bucket(key, token, ttl)
+    local result = redis.call('SET', key .. ':lock', token, 'NX', 'EX', ttl)
     return result ~= false
 end
 
-local function increment_route_count(key)
-    local route_count = tonumber(redis.call('INCR', key))
+-- Loose strategy: while a bucket's limit is unknown, let a few requests through instead of waiting on its lock
+local function within_loose_limit(count, cost, loose_limit)
+    return loose_limit > 0 and count + cost <= loose_limit
+end
+
+local function increment_route_count(key, cost)
+    local route_count = tonumber(redis.call('INCRBY', key, cost))
     
-    if route_count == 1 then
+    -- Counters that were opened with a window of their own, like a preloaded bucket's, keep it
+    if route_count == cost and redis.call('PTTL', key) == -1 then
         redis.call('EXPIRE', key, 60)
     end
 
     return route_count
 end
 
+-- Counters only go negative or unreadable through an accounting bug, resetting them lets the bucket recover on its own
+local function is_corrupt_count(raw_count)
+    if raw_count == false then
+        return false
+    end
+
+    local count = tonumber(raw_count)
+    return count == nil or count < 0
+end
+
+-- A known route limit is treated as this much lower, so the bucket is closed off before Discord's own 429s
+local function effective_limit(limit, safety_margin)
+    return math.max(limit - safety_margin, 1)
+end
+
 local route_key = KEYS[1]
 local route_count_key = route_key .. ':count'
 
 local lock_token = ARGV[1]
+local cost = tonumber(ARGV[2])
+local lock_ttl = ARGV[3]
+local route_loose_limit = tonumber(ARGV[4])
+local route_safety_margin = tonumber(ARGV[5])
+
+if is_corrupt_count(redis.call('GET', route_count_key)) then
+    redis.call('DEL', route_count_key)
+    return {6, 'route'}
+end
 
 local route_limit = tonumber(redis.call('GET', route_key))
 
 local holds_route_lock = false
 if route_limit == nil then
-    holds_route_lock = lock_bucket(route_key, lock_token)
+    holds_route_lock = lock_bucket(route_key, lock_token, lock_ttl)
 
     if holds_route_lock == false then
-        return 3
+        local route_count = tonumber(redis.call('GET', route_count_key)) or 0
+
+        if not within_loose_limit(route_count, cost, route_loose_limit) then
+            return 3
+        end
     end
 end
 
-local route_count = increment_route_count(route_count_key)
+local route_count = increment_route_count(route_count_key, cost)
 
-if holds_route_lock == false and route_count > route_limit then
+if holds_route_lock == false and route_limit ~= nil and route_count > effective_limit(route_limit, route_safety_margin) then
     local reset_after = redis.call('PTTL', route_key .. ':reset_after')
 
     if reset_after ~= -2 then
@@ -40,7 +74,7 @@ if holds_route_lock == false and route_count > route_limit then
         return {2, route_limit, reset_at, reset_after} 
     end
 
-    holds_route_lock = lock_bucket(route_key, lock_token)
+    holds_route_lock = lock_bucket(route_key, lock_token, lock_ttl)
 
     if holds_route_lock == false then
         return 3
@@ -48,4 +82,4 @@ if holds_route_lock == false and route_count > route_limit then
 end
 
 local holds_global_lock = false
-return {5, holds_global_lock, holds_route_lock}
\ No newline at end of file
+return {5, holds_global_lock, holds_route_lock, route_limit or 0, route_count}
\ No newline at end of file
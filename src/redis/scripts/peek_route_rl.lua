@@ -0,0 +1,35 @@
+#!lua flags=no-writes
+-- Read-only check whether a known route bucket is exhausted, so it can be answered by a replica
+
+-- A known route limit is treated as this much lower, so the bucket is closed off before Discord's own 429s
+local function effective_limit(limit, safety_margin)
+    return math.max(limit - safety_margin, 1)
+end
+
+local route_key = KEYS[1]
+local route_count_key = route_key .. ':count'
+
+local cost = tonumber(ARGV[1])
+local route_safety_margin = tonumber(ARGV[2])
+
+local route_limit = tonumber(redis.call('GET', route_key))
+
+if route_limit == nil then
+    return {0}
+end
+
+local route_count = tonumber(redis.call('GET', route_count_key)) or 0
+
+if route_count + cost <= effective_limit(route_limit, route_safety_margin) then
+    return {0}
+end
+
+local reset_after = redis.call('PTTL', route_key .. ':reset_after')
+
+if reset_after == -2 then
+    return {0}
+end
+
+local reset_at = redis.call('PEXPIRETIME', route_count_key)
+
+return {2, route_limit, reset_at, reset_after}
@@ -0,0 +1,16 @@
+#!lua flags=no-writes
+-- Read-only snapshot of what is stored for a route bucket, for inspecting it without consuming any of its quota
+
+local route_key = KEYS[1]
+local route_count_key = route_key .. ':count'
+
+local route_limit = tonumber(redis.call('GET', route_key)) or -1
+local route_count = tonumber(redis.call('GET', route_count_key)) or 0
+
+local reset_after = redis.call('PTTL', route_key .. ':reset_after')
+local reset_at = redis.call('PEXPIRETIME', route_count_key)
+local expires_at = redis.call('PEXPIRETIME', route_key)
+
+local locked = redis.call('EXISTS', route_key .. ':lock')
+
+return {route_limit, route_count, reset_after, reset_at, expires_at, locked}
@@ -0,0 +1,23 @@
+--  Keys:
+--  - Route bucket
+--
+--  Arguments:
+--  - Lock token
+--
+--  Returns true if we unlocked the route bucket without storing any ratelimit info, false if we were too slow.
+
+local route_key = KEYS[1]
+local route_lock_key = route_key .. ':lock'
+
+local lock_token = ARGV[1]
+
+local current_lock_holder = redis.call('GET', route_lock_key)
+
+if lock_token == current_lock_holder then
+    redis.call('DEL', route_lock_key)
+    redis.call('PUBLISH', 'unlock', route_key)
+
+    return true
+end
+
+return false
\ No newline at end of file
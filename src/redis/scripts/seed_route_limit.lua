@@ -0,0 +1,30 @@
+local route_key = KEYS[1]
+
+local route_limit = ARGV[1]
+local route_info_expire_in = ARGV[2]
+local route_reset_after = ARGV[3]
+
+-- Buckets Redis already knows about are left alone
+if redis.call('EXISTS', route_key) == 1 then
+    return false
+end
+
+local function set_route_info(key, value)
+    if route_info_expire_in == '0' then
+        redis.call('SET', key, value)
+    else
+        redis.call('SET', key, value, 'PX', route_info_expire_in)
+    end
+end
+
+set_route_info(route_key, route_limit)
+
+-- With its window known, a bucket opens one right away, so going over its limit is answered with a ratelimit rather
+-- than by taking its lock
+if route_reset_after ~= '0' then
+    set_route_info(route_key .. ':window', route_reset_after)
+    redis.call('SET', route_key .. ':count', 0, 'PX', route_reset_after, 'NX')
+    redis.call('SET', route_key .. ':reset_after', '1', 'PX', route_reset_after)
+end
+
+return true
@@ -7,9 +7,22 @@ local route_remaining = ARGV[3]
 local route_reset_at = ARGV[4]
 local route_reset_after = ARGV[5]
 local route_info_expire_in = ARGV[6]
+local route_info_idle_ttl = ARGV[7]
 
 local route_count_key = route_key .. ':count'
 local route_reset_after_key = route_key .. ':reset_after'
+local route_window_key = route_key .. ':window'
+
+-- The length of a bucket's windows is kept for as long as its limit, so both can be exported together
+local function remember_window()
+    local route_info_ttl = redis.call('PTTL', route_key)
+
+    if route_info_ttl == -1 then
+        redis.call('SET', route_window_key, route_reset_after)
+    elseif route_info_ttl > 0 then
+        redis.call('SET', route_window_key, route_reset_after, 'PX', route_info_ttl)
+    end
+end
 
 if lock_token ~= '' then
     local current_lock_holder = redis.call('GET', route_lock_key)
@@ -21,6 +34,8 @@ if lock_token ~= '' then
             redis.call('SET', route_key, route_limit, 'PX', route_info_expire_in)
         end
 
+        remember_window()
+
         redis.call('SET', route_count_key, route_limit - route_remaining, 'PXAT', route_reset_at)
         redis.call('SET', route_reset_after_key, '1', 'PX', route_reset_after)
 
@@ -30,6 +45,20 @@ if lock_token ~= '' then
         return true
     end
 else
+    -- Discord adjusts route limits now and then, so keep a known limit in sync with what it reports
+    local stored_limit = redis.call('GET', route_key)
+
+    if stored_limit ~= false and stored_limit ~= route_limit then
+        redis.call('SET', route_key, route_limit, 'KEEPTTL')
+    end
+
+    -- Buckets that keep being used keep their limit, only idle ones run out
+    if stored_limit ~= false and route_info_idle_ttl ~= '0' then
+        redis.call('PEXPIRE', route_key, route_info_idle_ttl, 'GT')
+    end
+
+    remember_window()
+
     local result = redis.call('PEXPIREAT', route_count_key, route_reset_at, 'GT')
 
     if result == 1 then
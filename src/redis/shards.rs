@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use fred::util::sha1_hash;
+
+use super::ProxyRedisClient;
+
+struct RedisShard {
+    /// The shard's endpoint, hashed with keys so a shard keeps its keys when others are added or removed
+    endpoint: String,
+    weight: u32,
+    client: Arc<ProxyRedisClient>,
+}
+
+/// Splits ratelimit keys between several independent Redis endpoints by weighted rendezvous hashing.
+pub struct RedisShards {
+    shards: Vec<RedisShard>,
+}
+
+impl RedisShards {
+    pub fn new(primary_endpoint: String, primary: Arc<ProxyRedisClient>) -> Self {
+        Self {
+            shards: vec![RedisShard {
+                endpoint: primary_endpoint,
+                weight: 1,
+                client: primary,
+            }],
+        }
+    }
+
+    pub fn add(&mut self, endpoint: String, weight: u32, client: Arc<ProxyRedisClient>) {
+        self.shards.push(RedisShard {
+            endpoint,
+            weight,
+            client,
+        });
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &Arc<ProxyRedisClient>> {
+        self.shards.iter().map(|shard| &shard.client)
+    }
+
+    /// Returns the client owning a key. Keys are placed by their hash tag like in Redis Cluster, so the global and
+    /// route keys of a bot always end up on the same shard for the scripts that touch both.
+    pub fn for_key(&self, key: &str) -> &Arc<ProxyRedisClient> {
+        if self.shards.len() == 1 {
+            return &self.shards[0].client;
+        }
+
+        let owner = owner_index(
+            self.shards
+                .iter()
+                .map(|shard| (shard.endpoint.as_str(), shard.weight)),
+            key,
+        );
+
+        &self.shards[owner].client
+    }
+}
+
+/// Returns the index of the endpoint that scores highest for a key's hash tag, endpoints being given with their weights.
+fn owner_index<'a>(endpoints: impl Iterator<Item = (&'a str, u32)>, key: &str) -> usize {
+    let tag = hash_tag(key);
+
+    let mut owner = 0;
+    let mut owner_score = f64::MIN;
+
+    for (index, (endpoint, weight)) in endpoints.enumerate() {
+        let score = shard_score(endpoint, weight, tag);
+
+        if score > owner_score {
+            owner = index;
+            owner_score = score;
+        }
+    }
+
+    owner
+}
+
+/// Returns the part of a key Redis Cluster would hash, the contents of its first non-empty `{...}` or the whole key.
+fn hash_tag(key: &str) -> &str {
+    if let Some(start) = key.find('{') {
+        if let Some(len) = key[start + 1..].find('}') {
+            if len > 0 {
+                return &key[start + 1..start + 1 + len];
+            }
+        }
+    }
+
+    key
+}
+
+fn shard_score(endpoint: &str, weight: u32, tag: &str) -> f64 {
+    // SHA-1 rather than ahash, as every proxy instance has to agree on where a key lives
+    let hash = sha1_hash(&format!("{}:{}", endpoint, tag));
+    let hash = u64::from_str_radix(&hash[..16], 16).unwrap_or_default();
+
+    // Maps the top 53 bits of the hash into (0, 1), the weighted score is then -weight / ln(point)
+    let point = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+
+    -(weight as f64) / point.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner<'a>(endpoints: &[(&'a str, u32)], key: &str) -> &'a str {
+        endpoints[owner_index(endpoints.iter().copied(), key)].0
+    }
+
+    fn bot_keys() -> impl Iterator<Item = String> {
+        (0..10_000).map(|bot| format!("global:{{{}}}", 1_000_000_000_000_000_000u64 + bot))
+    }
+
+    #[test]
+    fn hash_tags_are_found_like_redis_cluster_does() {
+        assert_eq!(hash_tag("global:{1}"), "1");
+        assert_eq!(hash_tag("{1}:route:{channels/2}"), "1");
+        assert_eq!(hash_tag("route:{}:x"), "route:{}:x");
+        assert_eq!(hash_tag("no-tag"), "no-tag");
+        assert_eq!(hash_tag("unclosed:{1"), "unclosed:{1");
+    }
+
+    #[test]
+    fn keys_always_land_on_the_same_shard() {
+        let endpoints = [
+            ("redis-a:6379", 1),
+            ("redis-b:6379", 1),
+            ("redis-c:6379", 1),
+        ];
+
+        for key in bot_keys().take(100) {
+            assert_eq!(owner(&endpoints, &key), owner(&endpoints, &key));
+        }
+
+        // Only the endpoints decide, not the order they're configured in
+        let reordered = [
+            ("redis-c:6379", 1),
+            ("redis-a:6379", 1),
+            ("redis-b:6379", 1),
+        ];
+        for key in bot_keys().take(100) {
+            assert_eq!(owner(&endpoints, &key), owner(&reordered, &key));
+        }
+    }
+
+    #[test]
+    fn keys_sharing_a_hash_tag_share_a_shard() {
+        let endpoints = [
+            ("redis-a:6379", 1),
+            ("redis-b:6379", 1),
+            ("redis-c:6379", 1),
+        ];
+
+        for bot in 0..100u64 {
+            let global = owner(&endpoints, &format!("global:{{{}}}", bot));
+
+            assert_eq!(
+                owner(
+                    &endpoints,
+                    &format!("{{{}}}:route:channels/1/messages/!", bot)
+                ),
+                global
+            );
+            assert_eq!(
+                owner(&endpoints, &format!("{{{}}}:route:guilds/2:count", bot)),
+                global
+            );
+        }
+    }
+
+    #[test]
+    fn weights_decide_how_many_keys_a_shard_gets() {
+        let endpoints = [("redis-a:6379", 3), ("redis-b:6379", 1)];
+
+        let on_a = bot_keys()
+            .filter(|key| owner(&endpoints, key) == "redis-a:6379")
+            .count();
+
+        // Three quarters of the keys, give or take
+        assert!(
+            (7_000..8_000).contains(&on_a),
+            "{} keys on the heavier shard",
+            on_a
+        );
+    }
+
+    #[test]
+    fn removing_a_shard_only_moves_its_keys() {
+        let endpoints = [
+            ("redis-a:6379", 1),
+            ("redis-b:6379", 1),
+            ("redis-c:6379", 1),
+        ];
+        let without_c = [("redis-a:6379", 1), ("redis-b:6379", 1)];
+
+        let mut moved = 0;
+        for key in bot_keys() {
+            let before = owner(&endpoints, &key);
+            let after = owner(&without_c, &key);
+
+            if before == "redis-c:6379" {
+                moved += 1;
+            } else {
+                assert_eq!(before, after, "{} moved between shards that stayed", key);
+            }
+        }
+
+        assert!(moved > 0);
+    }
+}
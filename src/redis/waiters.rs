@@ -0,0 +1,312 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use ahash::AHashMap;
+use tokio::sync::{oneshot, Mutex, RwLock};
+
+use super::LockError;
+
+struct PubSubChannel {
+    pending_clients: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+    /// Set while holding `pending_clients` once the channel is removed from `channels`, waiters that found it before
+    /// then must not queue on it as nothing will wake them
+    closed: AtomicBool,
+}
+
+/// Requests on this instance waiting for a lock to be released, by the key the lock is on.
+#[derive(Clone)]
+pub struct LockWaiters {
+    channels: Arc<RwLock<AHashMap<String, Arc<PubSubChannel>>>>,
+    /// How many requests may wait on one key, 0 for no limit
+    max_waiters: usize,
+}
+
+impl LockWaiters {
+    pub fn new(max_waiters: usize) -> Self {
+        Self {
+            channels: Arc::new(RwLock::new(AHashMap::new())),
+            max_waiters,
+        }
+    }
+
+    /// Waits for the lock on a key to be released, or returns straight away if it was released while queueing.
+    pub async fn wait(&self, key: &str) -> Result<(), LockError> {
+        let (tx, rx) = oneshot::channel::<()>();
+
+        let channels_r = self.channels.read().await;
+
+        async fn push_pending_client(
+            channel: Arc<PubSubChannel>,
+            tx: oneshot::Sender<()>,
+            max_waiters: usize,
+        ) -> Result<bool, LockError> {
+            let mut pending_clients = channel.pending_clients.lock().await;
+
+            if channel.closed.load(Ordering::Acquire) {
+                return Ok(false);
+            }
+
+            if max_waiters != 0 && pending_clients.len() >= max_waiters {
+                return Err(LockError::TooManyWaiters(pending_clients.len()));
+            }
+
+            pending_clients.push(tx);
+
+            drop(pending_clients);
+            Ok(true)
+        }
+
+        let queued = match channels_r.get(key) {
+            Some(channel) => {
+                let channel = channel.clone();
+                drop(channels_r);
+
+                push_pending_client(channel, tx, self.max_waiters).await?
+            }
+            None => {
+                drop(channels_r);
+
+                let mut channels_w = self.channels.write().await;
+
+                if let Some(channel) = channels_w.get(key) {
+                    tracing::debug!("Another thread subscribed to channel for key {} while this thread was waiting for the write lock, pushing to queue.", key);
+
+                    let channel = channel.clone();
+                    drop(channels_w);
+
+                    push_pending_client(channel, tx, self.max_waiters).await?
+                } else {
+                    channels_w.insert(
+                        key.to_string(),
+                        Arc::new(PubSubChannel {
+                            pending_clients: Arc::new(Mutex::new(vec![tx])),
+                            closed: AtomicBool::new(false),
+                        }),
+                    );
+
+                    drop(channels_w);
+                    true
+                }
+            }
+        };
+
+        // The channel was released or cleaned up between finding it and queueing on it, so the lock was most likely
+        // just released and the caller should check the bucket again straight away
+        if !queued {
+            return Ok(());
+        }
+
+        rx.await?;
+        Ok(())
+    }
+
+    /// Forgets waiters that gave up on a key, and the key itself once nobody is waiting on it.
+    pub async fn cleanup(&self, key: &str) {
+        let channels_r = self.channels.read().await;
+
+        let mut emptied = false;
+        if let Some(channel) = channels_r.get(key) {
+            let pending_clients_m = channel.pending_clients.clone();
+            drop(channels_r);
+
+            let mut pending_clients = pending_clients_m.lock().await;
+
+            while let Some(index) = pending_clients.iter().position(|tx| tx.is_closed()) {
+                pending_clients.remove(index);
+            }
+
+            if pending_clients.is_empty() {
+                emptied = true;
+            }
+
+            drop(pending_clients);
+        }
+
+        if emptied {
+            let mut channels_w = self.channels.write().await;
+
+            let channel = match channels_w.get(key) {
+                Some(channel_m) => channel_m.clone(),
+                None => return,
+            };
+
+            let pending_clients = channel.pending_clients.lock().await;
+
+            if pending_clients.is_empty() {
+                channel.closed.store(true, Ordering::Release);
+                channels_w.remove(key);
+            }
+
+            drop(pending_clients);
+
+            drop(channels_w);
+        }
+    }
+
+    /// Every key something is waiting on.
+    pub async fn keys(&self) -> Vec<String> {
+        self.channels.read().await.keys().cloned().collect()
+    }
+
+    /// Wakes everything waiting on a key.
+    pub async fn release(&self, key: &str) {
+        let mut channels_w = self.channels.write().await;
+
+        let channel = match channels_w.get(key) {
+            Some(channel_m) => channel_m.clone(),
+            None => return,
+        };
+
+        channels_w.remove(key);
+        drop(channels_w);
+
+        let mut pending_clients = channel.pending_clients.lock().await;
+        channel.closed.store(true, Ordering::Release);
+
+        for tx in pending_clients.drain(..) {
+            match tx.send(()) {
+                Ok(_) => (),
+                Err(e) => tracing::error!("Error completing a pending lock on {}: {:?}", key, e),
+            }
+        }
+        drop(pending_clients);
+
+        drop(channel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::{yield_now, JoinHandle};
+
+    use super::*;
+
+    /// Starts waiting on a key in the background, letting the waiter run until it's queued
+    async fn queue(waiters: &LockWaiters, key: &str) -> JoinHandle<Result<(), LockError>> {
+        let waiters = waiters.clone();
+        let key = key.to_string();
+
+        let waiter = tokio::spawn(async move { waiters.wait(&key).await });
+        yield_now().await;
+
+        waiter
+    }
+
+    async fn pending(waiters: &LockWaiters, key: &str) -> usize {
+        match waiters.channels.read().await.get(key) {
+            Some(channel) => channel.pending_clients.lock().await.len(),
+            None => 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn waiters_past_the_limit_are_turned_away() {
+        let waiters = LockWaiters::new(2);
+
+        let queued = [queue(&waiters, "a").await, queue(&waiters, "a").await];
+        assert_eq!(pending(&waiters, "a").await, 2);
+
+        assert!(matches!(
+            waiters.wait("a").await,
+            Err(LockError::TooManyWaiters(2))
+        ));
+
+        // Other keys have limits of their own
+        let other = queue(&waiters, "b").await;
+        assert_eq!(pending(&waiters, "b").await, 1);
+
+        waiters.release("a").await;
+        for waiter in queued {
+            waiter.await.unwrap().unwrap();
+        }
+
+        // Waking the queue makes room again
+        let requeued = queue(&waiters, "a").await;
+        assert_eq!(pending(&waiters, "a").await, 1);
+
+        waiters.release("a").await;
+        waiters.release("b").await;
+        requeued.await.unwrap().unwrap();
+        other.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiters_are_unlimited_by_default() {
+        let waiters = LockWaiters::new(0);
+
+        let mut queued = Vec::new();
+        for _ in 0..100 {
+            queued.push(queue(&waiters, "a").await);
+        }
+        assert_eq!(pending(&waiters, "a").await, 100);
+
+        waiters.release("a").await;
+        for waiter in queued {
+            waiter.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn waiters_racing_a_release_are_woken() {
+        let waiters = LockWaiters::new(0);
+        let stale = queue(&waiters, "a").await;
+
+        let channel = waiters.channels.read().await["a"].clone();
+        let held = channel.pending_clients.lock().await;
+
+        // Found the channel just before it's released, queues on it while the release is emptying it
+        let racing = queue(&waiters, "a").await;
+
+        let releasing = {
+            let waiters = waiters.clone();
+            tokio::spawn(async move { waiters.release("a").await })
+        };
+        yield_now().await;
+
+        // Arrives once the released channel is gone, so it has to be woken by the next release
+        let late = queue(&waiters, "a").await;
+
+        drop(held);
+        releasing.await.unwrap();
+        stale.await.unwrap().unwrap();
+        racing.await.unwrap().unwrap();
+
+        assert!(!late.is_finished());
+        assert_eq!(pending(&waiters, "a").await, 1);
+
+        waiters.release("a").await;
+        late.await.unwrap().unwrap();
+        assert!(waiters.keys().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cleanup_keeps_keys_that_are_still_waited_on() {
+        let waiters = LockWaiters::new(0);
+
+        let timed_out = queue(&waiters, "a").await;
+        let waiting = queue(&waiters, "a").await;
+
+        timed_out.abort();
+        let _ = timed_out.await;
+
+        waiters.cleanup("a").await;
+        assert_eq!(pending(&waiters, "a").await, 1);
+
+        waiters.release("a").await;
+        waiting.await.unwrap().unwrap();
+
+        // Once the last waiter gives up the key is forgotten, and a new waiter starts a channel of its own
+        let timed_out = queue(&waiters, "a").await;
+        timed_out.abort();
+        let _ = timed_out.await;
+
+        waiters.cleanup("a").await;
+        assert!(waiters.keys().await.is_empty());
+
+        let waiting = queue(&waiters, "a").await;
+        waiters.release("a").await;
+        waiting.await.unwrap().unwrap();
+    }
+}
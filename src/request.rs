@@ -1,81 +1,265 @@
+use std::net::IpAddr;
+
 use base64_simd::forgiving_decode_to_vec;
-use http::{HeaderMap, Method};
+use fred::util::sha1_hash;
+use http::{
+    header::{ACCEPT_ENCODING, AUTHORIZATION},
+    HeaderMap, Method, Request,
+};
+use hyper::Body;
 
 use crate::{
     buckets::{BucketInfo, Resources},
+    config::ProxyEnvConfig,
     proxy::ProxyError,
 };
 
+#[cfg(feature = "metrics")]
+use crate::metrics;
+
 #[derive(Clone, Debug)]
 pub struct DiscordRequestInfo {
+    /// The bot id decoded from the token. Ratelimit keys are built from it and never from the token, so every token of a bot shares its buckets.
     pub global_id: String,
     pub token: Option<String>,
+    /// The id to log the request under, which is a hash of the bot id if bot ids are kept out of logs
+    pub log_global_id: String,
+
+    #[cfg(feature = "metrics")]
+    pub metrics_global_id: String,
 
     pub global_id_redis_key: String,
+    /// The shared global ratelimit group this bot belongs to, if any
+    pub global_rl_group: Option<String>,
 
     pub resource: Resources,
     pub uses_global_ratelimit: bool,
+    pub uses_interaction_token: bool,
 
     pub route_bucket: String,
     pub route_display_bucket: String,
 
     pub route_bucket_redis_key: String,
 
-    pub require_auth: bool,
+    /// Discord's own bucket hash for this route, if one has been learned from a previous response
+    pub discord_bucket: Option<String>,
 }
 
 impl DiscordRequestInfo {
     const DEFAULT_GLOBAL_ID: &str = "NoAuth";
 
-    pub fn new(method: &Method, path: &str, headers: &HeaderMap) -> Result<Self, ProxyError> {
-        let bucket_info = BucketInfo::new(&method, &path)?;
+    pub fn new(
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        config: &ProxyEnvConfig,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Self, ProxyError> {
+        let bucket_info = BucketInfo::new(method, &normalize_path(path)?)?;
 
         let can_ignore_auth = (bucket_info.resource == Resources::Webhooks
-            && bucket_info.route_bucket.split("/").count() != 2)
+            && bucket_info.route_bucket.split('/').count() != 2)
             || bucket_info.resource == Resources::OAuth2
             || bucket_info.resource == Resources::Interactions;
         let require_auth = !can_ignore_auth;
 
         let auth = parse_headers(headers, require_auth)?;
 
-        let (global_id, token): (String, Option<String>) = match &auth {
-            Some((id, token)) => (id.into(), Some(token.into())),
-            None => (Self::DEFAULT_GLOBAL_ID.into(), None),
+        let (global_id, token): (String, Option<String>) = match (&auth, client_ip) {
+            (Some((id, token)), _) => (id.into(), Some(token.into())),
+            // Keeps one unauthenticated client from using up the route buckets of every other one
+            (None, Some(client_ip)) if config.isolate_unauthenticated_clients => {
+                (format!("{}:{}", Self::DEFAULT_GLOBAL_ID, client_ip), None)
+            }
+            (None, _) => (Self::DEFAULT_GLOBAL_ID.into(), None),
         };
 
-        let route_uses_global_ratelimit = match bucket_info.resource {
-            Resources::Webhooks => false,
-            Resources::Interactions => false,
-            _ => true,
-        };
+        let route_uses_global_ratelimit = !matches!(
+            bucket_info.resource,
+            Resources::Webhooks | Resources::Interactions
+        );
 
-        let uses_global_ratelimit =
-            route_uses_global_ratelimit && global_id != Self::DEFAULT_GLOBAL_ID;
+        let uses_global_ratelimit = route_uses_global_ratelimit && token.is_some();
 
-        let global_id_redis_key = format!("global:{{{}}}", global_id);
+        let global_rl_group = config.global_rl_groups.get(&global_id).cloned();
+
+        let global_id_redis_key = match &global_rl_group {
+            Some(group) => format!("global:{{group:{}}}", group),
+            None => format!("global:{{{}}}", global_id),
+        };
         let route_bucket_redis_key = if uses_global_ratelimit {
-            format!("{}-route:{}", global_id_redis_key, bucket_info.route_bucket)
+            format!(
+                "{}-route:{}",
+                route_key_prefix(&global_id_redis_key, &global_rl_group, &global_id),
+                bucket_info.route_bucket
+            )
         } else {
-            format!("route:{{{}}}", bucket_info.route_bucket)
+            format!(
+                "route:{{{}}}",
+                client_scoped(&global_id, &token, &bucket_info.route_bucket)
+            )
         };
 
         Ok(Self {
+            #[cfg(feature = "metrics")]
+            // Client addresses would make for an unbounded number of label values
+            metrics_global_id: metrics::global_id_label(match token {
+                Some(_) => &global_id,
+                None => Self::DEFAULT_GLOBAL_ID,
+            }),
+
+            log_global_id: match (&token, &config.log_bot_id_salt) {
+                (Some(_), Some(salt)) => hash_bot_id(&global_id, salt),
+                _ => global_id.clone(),
+            },
+
             global_id,
             token,
 
             global_id_redis_key,
+            global_rl_group,
 
             resource: bucket_info.resource,
             uses_global_ratelimit,
+            uses_interaction_token: bucket_info.uses_interaction_token,
 
             route_bucket: bucket_info.route_bucket,
             route_display_bucket: bucket_info.route_display_bucket,
 
             route_bucket_redis_key,
 
-            require_auth,
+            discord_bucket: None,
         })
     }
+
+    /// Switches this request over to the ratelimit state stored under Discord's bucket hash, which is shared between routes Discord buckets together.
+    pub fn use_discord_bucket(&mut self, bucket_hash: String) {
+        // Discord's bucket hashes don't include the major parameter, so we still need to keep those separate
+        let major_parameter = major_parameter(&self.route_bucket);
+
+        self.route_bucket_redis_key = if self.uses_global_ratelimit {
+            format!(
+                "{}-bucket:{}:{}",
+                route_key_prefix(
+                    &self.global_id_redis_key,
+                    &self.global_rl_group,
+                    &self.global_id
+                ),
+                bucket_hash,
+                major_parameter
+            )
+        } else {
+            format!(
+                "bucket:{{{}}}",
+                client_scoped(
+                    &self.global_id,
+                    &self.token,
+                    &format!("{}:{}", bucket_hash, major_parameter)
+                )
+            )
+        };
+
+        self.discord_bucket = Some(bucket_hash);
+    }
+}
+
+/// The route bucket a request would be shown as in logs and metrics, for deciding things before it is fully parsed.
+pub fn route_display_bucket(method: &Method, path: &str) -> Option<String> {
+    let path = normalize_path(path).ok()?;
+
+    BucketInfo::new(method, &path)
+        .ok()
+        .map(|bucket_info| bucket_info.route_display_bucket)
+}
+
+/// The start of a route bucket that Discord keeps separate ratelimits for. Webhook and interaction tokens are part of
+/// it along with the id before them, so every interaction gets its own ratelimits.
+fn major_parameter(route_bucket: &str) -> &str {
+    let segments =
+        if route_bucket.starts_with("webhooks/") || route_bucket.starts_with("interactions/") {
+            3
+        } else {
+            2
+        };
+
+    match route_bucket.match_indices('/').nth(segments - 1) {
+        Some((index, _)) => &route_bucket[..index],
+        None => route_bucket,
+    }
+}
+
+/// Identifies GET requests Discord would answer with the same response, so it can be shared with or cached for
+/// identical ones. Returns None for requests whose response can't be reused.
+pub fn response_key(req: &Request<Body>) -> Option<String> {
+    if req.method() != Method::GET {
+        return None;
+    }
+
+    // Keyed on the whole token rather than the global id, which is decoded from an unverified token, so responses
+    // stay private to their caller
+    let token_hash = req
+        .headers()
+        .get(AUTHORIZATION)
+        .map(|token| sha1_hash(&String::from_utf8_lossy(token.as_bytes())))
+        .unwrap_or_default();
+
+    // Discord may compress its response depending on what the caller accepts
+    let accept_encoding = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    let path_and_query = req.uri().path_and_query()?.as_str();
+
+    Some(format!(
+        "{}:{}:{}",
+        token_hash, accept_encoding, path_and_query
+    ))
+}
+
+/// A short, stable stand-in for a bot id, so its log lines can still be correlated without revealing which bot it is
+fn hash_bot_id(global_id: &str, salt: &str) -> String {
+    sha1_hash(&format!("{}{}", salt, global_id))[..12].to_string()
+}
+
+/// Collapses the ways one route can be spelled, so clients can't get around its ratelimit by varying the path.
+fn normalize_path(path: &str) -> Result<String, ProxyError> {
+    // An encoded slash would be decoded into a different route than the one it was bucketed as
+    if path.to_ascii_lowercase().contains("%2f") {
+        return Err(ProxyError::InvalidRequest(format!(
+            "Invalid Path: {}, encoded slashes are not allowed",
+            path
+        )));
+    }
+
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    Ok(format!("/{}", segments.join("/")))
+}
+
+/// Prefixes shared route buckets with the client's address when unauthenticated requests are isolated per client
+fn client_scoped(global_id: &str, token: &Option<String>, bucket: &str) -> String {
+    if token.is_none() && global_id != DiscordRequestInfo::DEFAULT_GLOBAL_ID {
+        format!("{}:{}", global_id, bucket)
+    } else {
+        bucket.to_string()
+    }
+}
+
+/// Route ratelimits stay per bot within a global ratelimit group, but share the group's hash tag so they can be checked alongside its global ratelimit
+fn route_key_prefix(
+    global_id_redis_key: &str,
+    global_rl_group: &Option<String>,
+    global_id: &str,
+) -> String {
+    match global_rl_group {
+        Some(_) => format!("{}-bot:{}", global_id_redis_key, global_id),
+        None => global_id_redis_key.to_string(),
+    }
 }
 
 fn parse_headers(
@@ -107,17 +291,23 @@ fn parse_headers(
         }
     };
 
-    let jwt = if token.starts_with("Bot ") {
-        &token[4..]
-    } else if token.starts_with("Bearer ") {
-        &token[7..]
+    let (jwt, is_bot_token) = if let Some(jwt) = token.strip_prefix("Bot ") {
+        (jwt, true)
+    } else if let Some(jwt) = token.strip_prefix("Bearer ") {
+        (jwt, false)
     } else {
         return Err(ProxyError::InvalidRequest(
             "Invalid Authorization header".into(),
-        ))
+        ));
     };
 
-    let base64_bot_id = match jwt.split('.').nth(0) {
+    if jwt.trim().is_empty() {
+        return Err(ProxyError::InvalidRequest(
+            "Empty token in Authorization header".into(),
+        ));
+    }
+
+    let base64_bot_id = match jwt.split('.').next() {
         Some(base64_bot_id) => base64_bot_id.as_bytes(),
         None => {
             return Err(ProxyError::InvalidRequest(
@@ -132,5 +322,296 @@ fn parse_headers(
     )
     .map_err(|_| ProxyError::InvalidRequest("Invalid Authorization header".into()))?;
 
+    // An empty id would share one global ratelimit between every such token
+    if bot_id.trim().is_empty() {
+        return Err(ProxyError::InvalidRequest(
+            "Invalid Authorization header".into(),
+        ));
+    }
+
+    // Anything but a snowflake means the token isn't shaped like the ones we know, and bucketing it by whatever it
+    // decoded to would give it a made up global ratelimit
+    if is_bot_token && !is_bot_id(&bot_id) {
+        return Err(ProxyError::InvalidRequest(
+            "Unrecognized bot token format, expected a base64 encoded bot id before the first '.'"
+                .into(),
+        ));
+    }
+
     Ok(Some((bot_id, token)))
 }
+
+/// Bot ids are snowflakes, which have 17 digits for the oldest bots and won't outgrow 20.
+fn is_bot_id(id: &str) -> bool {
+    (17..=20).contains(&id.len()) && id.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+    use crate::config::AppEnvConfig;
+
+    /// The default config, with the settings these tests depend on pinned
+    fn config() -> ProxyEnvConfig {
+        let mut config = (*AppEnvConfig::from_env().proxy).clone();
+        config.isolate_unauthenticated_clients = false;
+        config.global_rl_groups = Default::default();
+        config.log_bot_id_salt = None;
+        config
+    }
+
+    fn request_info(
+        method: Method,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> Result<DiscordRequestInfo, ProxyError> {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(
+                "Authorization",
+                HeaderValue::from_str(authorization).unwrap(),
+            );
+        }
+
+        DiscordRequestInfo::new(&method, path, &headers, &config(), None)
+    }
+
+    /// A bot token for the given bot id, shaped like the ones Discord issues
+    fn bot_token(bot_id: &str) -> String {
+        format!(
+            "Bot {}.GhIjKl.aSecretPartOfTheToken",
+            base64_simd::STANDARD_NO_PAD.encode_to_string(bot_id)
+        )
+    }
+
+    #[test]
+    fn routes_with_one_discord_bucket_share_its_state() {
+        let token = bot_token("1000000000000000001");
+        let discord_bucket = |method: Method, path: &str| {
+            let mut request_info = request_info(method, path, Some(&token)).unwrap();
+            request_info.use_discord_bucket("abcd1234".to_string());
+            request_info.route_bucket_redis_key
+        };
+
+        let edit = discord_bucket(
+            Method::PATCH,
+            "/api/v10/channels/1000000000000000002/messages/1000000000000000003",
+        );
+        let reaction = discord_bucket(
+            Method::PUT,
+            "/api/v10/channels/1000000000000000002/messages/1000000000000000003/reactions/%F0%9F%91%8D/@me",
+        );
+        assert_eq!(edit, reaction);
+        assert!(edit.contains("abcd1234:channels/1000000000000000002"));
+
+        // The major parameter still keeps channels apart
+        assert_ne!(
+            edit,
+            discord_bucket(
+                Method::PATCH,
+                "/api/v10/channels/1000000000000000004/messages/1000000000000000003",
+            )
+        );
+    }
+
+    #[test]
+    fn grouped_bots_share_a_global_ratelimit() {
+        let mut config = config();
+        config.global_rl_groups = [
+            ("1000000000000000001", "pool"),
+            ("1000000000000000002", "pool"),
+        ]
+        .into_iter()
+        .map(|(id, group)| (id.to_string(), group.to_string()))
+        .collect();
+
+        let request_info = |bot_id: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "Authorization",
+                HeaderValue::from_str(&bot_token(bot_id)).unwrap(),
+            );
+
+            DiscordRequestInfo::new(
+                &Method::POST,
+                "/api/v10/channels/1000000000000000010/messages",
+                &headers,
+                &config,
+                None,
+            )
+            .unwrap()
+        };
+
+        let first = request_info("1000000000000000001");
+        let second = request_info("1000000000000000002");
+        let ungrouped = request_info("1000000000000000003");
+
+        assert_eq!(first.global_rl_group.as_deref(), Some("pool"));
+        assert_eq!(first.global_id_redis_key, "global:{group:pool}");
+        assert_eq!(second.global_id_redis_key, first.global_id_redis_key);
+
+        // Route ratelimits are still each bot's own, but live alongside the group's global ratelimit
+        assert_ne!(second.route_bucket_redis_key, first.route_bucket_redis_key);
+        assert!(first
+            .route_bucket_redis_key
+            .starts_with("global:{group:pool}-bot:1000000000000000001"));
+
+        assert_eq!(ungrouped.global_rl_group, None);
+        assert_eq!(
+            ungrouped.global_id_redis_key,
+            "global:{1000000000000000003}"
+        );
+        assert!(ungrouped
+            .route_bucket_redis_key
+            .starts_with("global:{1000000000000000003}-route:"));
+    }
+
+    #[test]
+    fn major_parameters_include_webhook_and_interaction_tokens() {
+        for (route_bucket, expected) in [
+            ("channels/1/messages/!*", "channels/1"),
+            ("guilds/1", "guilds/1"),
+            ("gateway/bot", "gateway/bot"),
+            ("webhooks/1/!", "webhooks/1/!"),
+            ("webhooks/1/!/messages/!*", "webhooks/1/!"),
+            ("webhooks/1/2/messages/@original", "webhooks/1/2"),
+            ("webhooks/1", "webhooks/1"),
+            ("interactions/2/!/callback", "interactions/2/!"),
+        ] {
+            assert_eq!(major_parameter(route_bucket), expected, "{}", route_bucket);
+        }
+    }
+
+    #[test]
+    fn interactions_keep_their_discord_buckets_apart() {
+        let interaction_token = |interaction_id: &str| {
+            base64_simd::STANDARD.encode_to_string(format!(
+                "interaction:{}:aSecretPartOfTheTokenThatIsLongEnoughToBeMasked",
+                interaction_id
+            ))
+        };
+        let discord_bucket = |path: String| {
+            let mut request_info = request_info(Method::PATCH, &path, None).unwrap();
+            request_info.use_discord_bucket("abcd1234".to_string());
+            request_info.route_bucket_redis_key
+        };
+
+        let followup = |interaction_id: &str| {
+            discord_bucket(format!(
+                "/api/v10/webhooks/1000000000000000001/{}/messages/@original",
+                interaction_token(interaction_id)
+            ))
+        };
+        assert_eq!(
+            followup("1100000000000000001"),
+            "bucket:{abcd1234:webhooks/1000000000000000001/1100000000000000001}"
+        );
+        assert_ne!(
+            followup("1100000000000000001"),
+            followup("1100000000000000002")
+        );
+
+        let webhook = discord_bucket(
+            "/api/v10/webhooks/1000000000000000001/aPlainWebhookToken/messages/1000000000000000002"
+                .to_string(),
+        );
+        assert_eq!(webhook, "bucket:{abcd1234:webhooks/1000000000000000001/!}");
+        assert!(!webhook.contains("aPlainWebhookToken"));
+    }
+
+    #[test]
+    fn path_variants_share_one_bucket() {
+        let token = bot_token("1000000000000000001");
+        let bucket = |path: &str| {
+            request_info(Method::GET, path, Some(&token))
+                .unwrap()
+                .route_bucket_redis_key
+        };
+
+        let expected = bucket("/api/v10/channels/1000000000000000002/messages");
+
+        for path in [
+            "/api/v10/channels/1000000000000000002/messages/",
+            "/api//v10//channels/1000000000000000002//messages",
+            "//api/v10/channels/1000000000000000002/messages//",
+        ] {
+            assert_eq!(bucket(path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn encoded_slashes_are_rejected() {
+        let token = bot_token("1000000000000000001");
+
+        for path in [
+            "/api/v10/channels/1000000000000000002%2Fmessages",
+            "/api/v10/channels%2f1000000000000000002/messages",
+        ] {
+            assert!(
+                request_info(Method::GET, path, Some(&token)).is_err(),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn bot_ids_are_decoded_from_tokens() {
+        for bot_id in ["80351110224678912", "1000000000000000001"] {
+            let request_info =
+                request_info(Method::GET, "/api/v10/users/@me", Some(&bot_token(bot_id))).unwrap();
+
+            assert_eq!(request_info.global_id, bot_id);
+            assert_eq!(
+                request_info.global_id_redis_key,
+                format!("global:{{{}}}", bot_id)
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_and_unknown_token_shapes_are_rejected() {
+        for authorization in [
+            "1000000000000000001",
+            "Bot ",
+            "Bot !!!.GhIjKl.secret",
+            // Decodes, but not to a snowflake
+            "Bot aW50ZXJhY3Rpb246MTAwMA.GhIjKl.secret",
+            // Shaped like a future token that prefixes or lengthens the id
+            &bot_token("v2:1000000000000000001"),
+            &bot_token("100000000000000000000001"),
+            &bot_token("1000"),
+        ] {
+            assert!(
+                request_info(Method::GET, "/api/v10/users/@me", Some(authorization)).is_err(),
+                "{} should be rejected",
+                authorization
+            );
+        }
+
+        assert!(request_info(Method::GET, "/api/v10/users/@me", None).is_err());
+    }
+
+    #[test]
+    fn webhooks_skip_the_global_ratelimit() {
+        let token = "aWebhookTokenThatIsShorterThanTheUsualSixtyFourCharacters";
+
+        for method in [Method::POST, Method::PATCH, Method::DELETE] {
+            let request_info = request_info(
+                method,
+                &format!("/api/v10/webhooks/1000000000000000001/{}", token),
+                None,
+            )
+            .unwrap();
+
+            assert!(!request_info.uses_global_ratelimit);
+            assert_eq!(
+                request_info.route_bucket_redis_key,
+                "route:{webhooks/1000000000000000001/!}"
+            );
+            assert!(!request_info.route_bucket_redis_key.contains(token));
+        }
+    }
+}
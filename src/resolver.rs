@@ -0,0 +1,56 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    vec,
+};
+
+use ahash::AHashMap;
+use hyper::{
+    client::connect::dns::{GaiResolver, Name},
+    service::Service,
+};
+
+/// Resolves hosts from `DISCORD_RESOLVE_OVERRIDE` to their pinned addresses, and everything else through the system resolver.
+#[derive(Clone)]
+pub struct OverrideResolver {
+    overrides: Arc<AHashMap<String, Vec<IpAddr>>>,
+    system: GaiResolver,
+}
+
+impl OverrideResolver {
+    pub fn new(overrides: AHashMap<String, Vec<IpAddr>>) -> Self {
+        Self {
+            overrides: Arc::new(overrides),
+            system: GaiResolver::new(),
+        }
+    }
+}
+
+impl Service<Name> for OverrideResolver {
+    type Response = vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.system.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        // The connector fills in the port from the request's URI
+        if let Some(addrs) = self.overrides.get(name.as_str()) {
+            let addrs = addrs
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, 0))
+                .collect::<Vec<_>>();
+
+            return Box::pin(async move { Ok(addrs.into_iter()) });
+        }
+
+        let resolving = self.system.call(name);
+        Box::pin(async move { Ok(resolving.await?.collect::<Vec<_>>().into_iter()) })
+    }
+}
@@ -1,9 +1,19 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use axum::response::Response;
 use http::response::Builder;
 use hyper::Body;
 
+const SHADOW_LIMIT: u16 = 5;
+const SHADOW_RESET_AFTER_MS: u64 = 1000;
+
+pub const PROXY_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const PROXY_GIT_SHA: &str = env!("GIT_SHA");
+
 fn proxy_response_builder() -> Builder {
-    Response::builder().header("x-sent-by-proxy", "true")
+    Response::builder()
+        .header("x-sent-by-proxy", "true")
+        .header("x-proxy-version", PROXY_VERSION)
 }
 
 pub fn invalid_request(message: String) -> Response<Body> {
@@ -13,10 +23,70 @@ pub fn invalid_request(message: String) -> Response<Body> {
         .expect("Response builder failed.")
 }
 
-pub fn ratelimited(bucket: &str, limit: u16, reset_at: u128, reset_after: u64) -> Response<Body> {
+pub fn not_found(message: String) -> Response<Body> {
+    proxy_response_builder()
+        .status(404)
+        .body(message.into())
+        .expect("Response builder failed.")
+}
+
+pub fn method_not_allowed(method: &str) -> Response<Body> {
+    proxy_response_builder()
+        .status(405)
+        .header("allow", "GET, HEAD, POST, PUT, PATCH, DELETE")
+        .body(format!("Method Not Allowed: {}", method).into())
+        .expect("Response builder failed.")
+}
+
+pub fn expectation_failed(message: String) -> Response<Body> {
+    proxy_response_builder()
+        .status(417)
+        .body(message.into())
+        .expect("Response builder failed.")
+}
+
+pub fn unauthorized() -> Response<Body> {
     proxy_response_builder()
+        .status(401)
+        .body(Body::empty())
+        .expect("Response builder failed.")
+}
+
+pub fn forbidden() -> Response<Body> {
+    proxy_response_builder()
+        .status(403)
+        .body(Body::empty())
+        .expect("Response builder failed.")
+}
+
+pub fn idempotency_conflict() -> Response<Body> {
+    proxy_response_builder()
+        .status(409)
+        .body("A request with this Idempotency-Key is still in progress.".into())
+        .expect("Response builder failed.")
+}
+
+/// `global` marks the response the way Discord marks a global 429, so client libraries pause every request of the bot instead of just the route.
+pub fn ratelimited(
+    bucket: &str,
+    global: bool,
+    limit: u16,
+    reset_at: u128,
+    reset_after: u64,
+) -> Response<Body> {
+    let builder = proxy_response_builder()
         .status(429)
+        .header("x-proxy-served", "ratelimit")
         .header("x-ratelimit-bucket", bucket)
+        .header("x-ratelimit-scope", if global { "global" } else { "user" });
+
+    let builder = if global {
+        builder.header("x-ratelimit-global", "true")
+    } else {
+        builder
+    };
+
+    builder
         .header("x-ratelimit-limit", limit)
         .header("x-ratelimit-remaining", 0)
         .header("x-ratelimit-reset", (reset_at as f64 / 1000.0).to_string())
@@ -28,9 +98,42 @@ pub fn ratelimited(bucket: &str, limit: u16, reset_at: u128, reset_after: u64) -
         .expect("Response builder failed.")
 }
 
+/// A transient 503, callers should back off briefly and retry.
 pub fn overloaded() -> Response<Body> {
     proxy_response_builder()
         .status(503)
+        .header("x-proxy-state", "overloaded")
+        .body(Body::empty())
+        .expect("Response builder failed.")
+}
+
+/// A 503 for while the proxy is deliberately not sending requests to Discord, so callers can back off for longer.
+pub fn disabled(retry_after_secs: u64) -> Response<Body> {
+    let builder = proxy_response_builder()
+        .status(503)
+        .header("x-proxy-state", "disabled");
+
+    let builder = if retry_after_secs > 0 {
+        builder.header("retry-after", retry_after_secs)
+    } else {
+        builder
+    };
+
+    builder
+        .body(Body::empty())
+        .expect("Response builder failed.")
+}
+
+pub fn bad_gateway() -> Response<Body> {
+    proxy_response_builder()
+        .status(502)
+        .body(Body::empty())
+        .expect("Response builder failed.")
+}
+
+pub fn deadline_exceeded() -> Response<Body> {
+    proxy_response_builder()
+        .status(504)
         .body(Body::empty())
         .expect("Response builder failed.")
 }
@@ -41,3 +144,77 @@ pub fn internal_error() -> Response<Body> {
         .body(Body::empty())
         .expect("Response builder failed.")
 }
+
+pub fn shadowed(bucket: &str) -> Response<Body> {
+    let reset_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards.")
+        .as_millis()
+        + SHADOW_RESET_AFTER_MS as u128;
+
+    proxy_response_builder()
+        .status(200)
+        .header("x-ratelimit-bucket", bucket)
+        .header("x-ratelimit-limit", SHADOW_LIMIT)
+        .header("x-ratelimit-remaining", SHADOW_LIMIT - 1)
+        .header(
+            "x-ratelimit-reset",
+            format!("{:.3}", reset_at as f64 / 1000.0),
+        )
+        .header(
+            "x-ratelimit-reset-after",
+            format!("{:.3}", SHADOW_RESET_AFTER_MS as f64 / 1000.0),
+        )
+        .body(Body::empty())
+        .expect("Response builder failed.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overloaded_responses_have_no_retry_after() {
+        let response = overloaded();
+
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["x-proxy-state"], "overloaded");
+        assert!(!response.headers().contains_key("retry-after"));
+    }
+
+    #[test]
+    fn disabled_responses_only_have_a_configured_retry_after() {
+        let response = disabled(0);
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["x-proxy-state"], "disabled");
+        assert!(!response.headers().contains_key("retry-after"));
+
+        let response = disabled(30);
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["x-proxy-state"], "disabled");
+        assert_eq!(response.headers()["retry-after"], "30");
+    }
+
+    #[test]
+    fn shadowed_responses_look_like_discords() {
+        let response = shadowed("channels/1/messages/!");
+        let headers = response.headers();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(headers["x-ratelimit-bucket"], "channels/1/messages/!");
+        assert_eq!(headers["x-ratelimit-limit"], "5");
+        assert_eq!(headers["x-ratelimit-remaining"], "4");
+        assert_eq!(headers["x-ratelimit-reset-after"], "1.000");
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let reset: f64 = headers["x-ratelimit-reset"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(reset > now && reset <= now + 1.0);
+    }
+}
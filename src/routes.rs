@@ -1,21 +1,167 @@
-use axum::{extract::State, response::Response};
-use http::Request;
+use axum::{
+    extract::{Query, State},
+    handler::Handler,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use http::{header::ACCEPT, Request, StatusCode};
 use hyper::Body;
+use serde_json::{json, Value};
 
-use crate::proxy::Proxy;
+use crate::{
+    debug::{DebugBucketQuery, DebugRatelimitQuery},
+    proxy::Proxy,
+    responses::{PROXY_GIT_SHA, PROXY_VERSION},
+};
 
-pub async fn health() -> &'static str {
-    "OK"
+impl Proxy {
+    /// All of the proxy's routes, for mounting into an existing app.
+    pub fn router(&self) -> Router {
+        self.api_router().merge(self.internal_router())
+    }
+
+    /// The proxied Discord API, under `/api` and under `PATH_PREFIX_STRIP` if one is set.
+    pub fn api_router(&self) -> Router {
+        let router = Router::new().route_service("/api/*path", proxy.with_state(self.clone()));
+
+        match &self.config.path_prefix_strip {
+            Some(prefix) => router.route_service(
+                &format!("{}/api/*path", prefix),
+                proxy.with_state(self.clone()),
+            ),
+            None => router,
+        }
+    }
+
+    /// Health checks, metrics and the optional debug and admin endpoints.
+    pub fn internal_router(&self) -> Router {
+        let internal_app = Router::new()
+            .route("/health", get(health).with_state(self.clone()))
+            .route("/health/ready", get(ready).with_state(self.clone()))
+            .route("/version", get(version))
+            .route("/metrics", get(metrics).with_state(self.clone()));
+
+        let internal_app = if self.config.debug_auth_token.is_some() {
+            internal_app
+                .route("/debug/bucket", get(debug_bucket).with_state(self.clone()))
+                .route(
+                    "/debug/ratelimit",
+                    get(debug_ratelimit).with_state(self.clone()),
+                )
+        } else {
+            internal_app
+        };
+
+        if self.config.admin_auth_token.is_some() {
+            internal_app.route(
+                "/admin/buckets/export",
+                get(export_buckets).with_state(self.clone()),
+            )
+        } else {
+            internal_app
+        }
+    }
+}
+
+/// Answers a plain `OK` for liveness probes, or uptime and status details to callers that accept JSON.
+pub async fn health(State(proxy): State<Proxy>, req: Request<Body>) -> Response {
+    let wants_json = req
+        .headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"));
+
+    if !wants_json {
+        return "OK".into_response();
+    }
+
+    Json(json!({
+        "status": "OK",
+        "version": PROXY_VERSION,
+        "uptime_secs": proxy.uptime().as_secs(),
+        "disabled": proxy.is_disabled(),
+    }))
+    .into_response()
+}
+
+/// Reports whether every Redis shard is reachable, along with how often the connections to them have dropped.
+pub async fn ready(State(proxy): State<Proxy>) -> (StatusCode, Json<Value>) {
+    let connected = proxy.redis_shards().all(|redis| redis.is_connected());
+    let (reconnects, last_reconnect_at) = proxy
+        .redis_shards()
+        .map(|redis| redis.reconnect_stats())
+        .fold(
+            (0, None),
+            |(reconnects, last_reconnect_at), (shard_reconnects, shard_last_reconnect_at)| {
+                (
+                    reconnects + shard_reconnects,
+                    last_reconnect_at.max(shard_last_reconnect_at),
+                )
+            },
+        );
+
+    let status = if connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "ready": connected,
+            "redis": {
+                "connected": connected,
+                "reconnects": reconnects,
+                "last_reconnect_at": last_reconnect_at,
+            },
+        })),
+    )
+}
+
+pub async fn version() -> Json<Value> {
+    let features: &[&str] = &[
+        #[cfg(feature = "metrics")]
+        "metrics",
+    ];
+
+    Json(json!({
+        "version": PROXY_VERSION,
+        "git_sha": PROXY_GIT_SHA,
+        "features": features,
+    }))
 }
 
 pub async fn proxy(State(proxy): State<Proxy>, req: Request<Body>) -> Response<Body> {
     proxy.handle_request(req).await
 }
 
-pub async fn metrics(State(_proxy): State<Proxy>) -> Response<Body> {
+pub async fn metrics(State(_proxy): State<Proxy>, _req: Request<Body>) -> Response<Body> {
     #[cfg(feature = "metrics")]
-    return _proxy.get_metrics();
+    return _proxy.get_metrics(_req.headers());
 
     #[cfg(not(feature = "metrics"))]
     return Response::new(Body::from("Metrics are disabled."));
 }
+
+pub async fn debug_bucket(
+    State(proxy): State<Proxy>,
+    Query(query): Query<DebugBucketQuery>,
+    req: Request<Body>,
+) -> Response<Body> {
+    proxy.debug_bucket(query, req.headers()).await
+}
+
+pub async fn debug_ratelimit(
+    State(proxy): State<Proxy>,
+    Query(query): Query<DebugRatelimitQuery>,
+    req: Request<Body>,
+) -> Response<Body> {
+    proxy.debug_ratelimit(query, req.headers()).await
+}
+
+pub async fn export_buckets(State(proxy): State<Proxy>, req: Request<Body>) -> Response<Body> {
+    proxy.export_buckets(req.headers()).await
+}
@@ -0,0 +1,219 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_rustls::server::TlsStream;
+
+/// Handshakes that take longer than this are dropped, so slow clients can't tie up connections
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts TLS connections, handshaking each one in its own task so a slow client doesn't hold up the others.
+pub struct TlsAcceptor {
+    connections: mpsc::Receiver<TlsConnection>,
+}
+
+impl TlsAcceptor {
+    /// Serves on a non-blocking listener. The certificate and key are read again on SIGHUP.
+    pub fn bind(
+        listener: std::net::TcpListener,
+        cert_path: String,
+        key_path: String,
+    ) -> io::Result<Self> {
+        let server_config = Arc::new(RwLock::new(Arc::new(load_server_config(
+            &cert_path, &key_path,
+        )?)));
+        let listener = TcpListener::from_std(listener)?;
+
+        let (sender, connections) = mpsc::channel(128);
+
+        #[cfg(unix)]
+        tokio::spawn(reload_on_hangup(server_config.clone(), cert_path, key_path));
+
+        tokio::spawn(accept_connections(listener, server_config, sender));
+
+        Ok(Self { connections })
+    }
+}
+
+impl Accept for TlsAcceptor {
+    type Conn = TlsConnection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.connections
+            .poll_recv(cx)
+            .map(|connection| connection.map(Ok))
+    }
+}
+
+pub struct TlsConnection {
+    stream: TlsStream<TcpStream>,
+    remote_addr: SocketAddr,
+}
+
+impl Connected<&TlsConnection> for SocketAddr {
+    fn connect_info(connection: &TlsConnection) -> Self {
+        connection.remote_addr
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+    sender: mpsc::Sender<TlsConnection>,
+) {
+    while !sender.is_closed() {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Usually running out of file descriptors, which retrying straight away won't fix
+                tracing::error!("Failed to accept connection: {}", err);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let acceptor = tokio_rustls::TlsAcceptor::from(
+            server_config
+                .read()
+                .expect("TLS config lock poisoned.")
+                .clone(),
+        );
+        let sender = sender.clone();
+
+        tokio::spawn(async move {
+            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = sender
+                        .send(TlsConnection {
+                            stream,
+                            remote_addr,
+                        })
+                        .await;
+                }
+                Ok(Err(err)) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", remote_addr, err)
+                }
+                Err(_) => tracing::debug!("TLS handshake with {} timed out.", remote_addr),
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+    cert_path: String,
+    key_path: String,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::error!(
+                "Failed to listen for SIGHUP, TLS certificates won't be reloaded: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        match load_server_config(&cert_path, &key_path) {
+            Ok(reloaded) => {
+                *server_config.write().expect("TLS config lock poisoned.") = Arc::new(reloaded);
+                tracing::info!("Reloaded TLS certificate from {}.", cert_path);
+            }
+            Err(err) => tracing::error!(
+                "Failed to reload TLS certificate, keeping the current one: {}",
+                err
+            ),
+        }
+    }
+}
+
+fn load_server_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", cert_path),
+        ));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No private key found in {}", key_path),
+            )
+        })?;
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
@@ -0,0 +1,135 @@
+use std::{
+    io,
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::ready;
+use hyper::server::accept::Accept;
+use tokio::net::{UnixListener, UnixStream};
+
+pub struct UnixAcceptor {
+    listener: UnixListener,
+}
+
+impl UnixAcceptor {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        // Clean up a stale socket left behind by a previous run, but never anything that isn't a socket or a socket
+        // another process is still listening on
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another process", path.display()),
+                    ));
+                }
+
+                std::fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+}
+
+impl Accept for UnixAcceptor {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let (stream, _addr) = ready!(self.listener.poll_accept(cx))?;
+
+        Poll::Ready(Some(Ok(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use futures_util::future::poll_fn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "discord-api-proxy-{}-{}.sock",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn accepts_connections() {
+        let path = socket_path("accepts");
+        let mut acceptor = UnixAcceptor::bind(&path).unwrap();
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let mut server = poll_fn(|cx| Pin::new(&mut acceptor).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_sockets() {
+        let path = socket_path("stale");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let _acceptor = UnixAcceptor::bind(&path).unwrap();
+        UnixStream::connect(&path).await.unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_sockets_in_use() {
+        let path = socket_path("in-use");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let err = UnixAcceptor::bind(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_removes_other_files() {
+        let path = socket_path("regular-file");
+        std::fs::write(&path, "not a socket").unwrap();
+
+        let err = UnixAcceptor::bind(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use std::fs;
+
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Method, StatusCode,
+};
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::token_matches, discord::DiscordError, proxy::Proxy, request::DiscordRequestInfo,
+    responses,
+};
+
+const ADMIN_TOKEN_HEADER: &str = "x-proxy-admin-token";
+
+/// Known route limits, as exported by `GET /admin/buckets/export`
+#[derive(Serialize, Deserialize)]
+pub struct BucketSnapshot {
+    pub buckets: Vec<BucketSnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BucketSnapshotEntry {
+    pub key: String,
+    pub limit: u16,
+    /// How long the bucket's windows are, missing from older snapshots and for buckets that haven't been used lately
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_after_ms: Option<u64>,
+}
+
+impl Proxy {
+    /// Exports the limits and window lengths of every route bucket currently known to Redis.
+    pub async fn export_buckets(&self, headers: &HeaderMap) -> Response<Body> {
+        let authorized = match &self.config.admin_auth_token {
+            Some(admin_auth_token) => token_matches(
+                headers
+                    .get(ADMIN_TOKEN_HEADER)
+                    .and_then(|token| token.to_str().ok()),
+                admin_auth_token,
+            ),
+            None => false,
+        };
+
+        if !authorized {
+            return responses::unauthorized();
+        }
+
+        let mut buckets = Vec::new();
+        for redis in self.redis_shards() {
+            match redis.get_route_limits().await {
+                Ok(shard_buckets) => buckets.extend(shard_buckets),
+                Err(err) => {
+                    tracing::error!("Failed to export buckets: {:?}", err);
+                    return responses::internal_error();
+                }
+            }
+        }
+
+        let snapshot = BucketSnapshot {
+            buckets: buckets
+                .into_iter()
+                .map(|(key, limit, reset_after_ms)| BucketSnapshotEntry {
+                    key,
+                    limit,
+                    reset_after_ms,
+                })
+                .collect(),
+        };
+
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&snapshot).expect("Failed to serialize bucket snapshot."),
+            ))
+            .expect("Response builder failed.")
+    }
+
+    /// Seeds route limits from a snapshot file, so the first requests after a cold start don't all need to take bucket locks.
+    /// Buckets Redis already knows about are left alone.
+    pub async fn preload_buckets(&self, path: &str) {
+        let snapshot = match fs::read(path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| {
+                serde_json::from_slice::<BucketSnapshot>(&data).map_err(|err| err.to_string())
+            }) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                tracing::error!("Failed to read bucket snapshot from {}: {}", path, err);
+                return;
+            }
+        };
+
+        let (mut seeded, mut failed) = (0, 0);
+        for entry in snapshot.buckets.iter().filter(|entry| entry.limit > 0) {
+            match self
+                .redis_for(&entry.key)
+                .seed_route_limit(
+                    &entry.key,
+                    entry.limit,
+                    entry.reset_after_ms,
+                    self.config.bucket_ttl_ms,
+                )
+                .await
+            {
+                Ok(true) => seeded += 1,
+                Ok(false) => {}
+                // One bucket failing shouldn't keep the rest from being preloaded
+                Err(err) => {
+                    tracing::error!("Failed to preload bucket {}: {:?}", entry.key, err);
+                    failed += 1;
+                }
+            }
+        }
+
+        tracing::info!(
+            "Preloaded {} of {} buckets from {}, {} failed.",
+            seeded,
+            snapshot.buckets.len(),
+            path,
+            failed
+        );
+    }
+
+    /// Checks every token in a file against Discord and logs which ones it no longer accepts, so a misconfigured
+    /// bot shows up at startup rather than with its first request. Problems are only logged, never fatal.
+    pub async fn validate_tokens(&self, path: &str) {
+        let tokens = match fs::read_to_string(path) {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                tracing::error!("Failed to read tokens to validate from {}: {}", path, err);
+                return;
+            }
+        };
+
+        let (mut valid, mut invalid) = (0, 0);
+        for (index, token) in tokens
+            .lines()
+            .map(str::trim)
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        {
+            let token = if token.starts_with("Bot ") || token.starts_with("Bearer ") {
+                token.to_string()
+            } else {
+                format!("Bot {}", token)
+            };
+
+            let mut headers = HeaderMap::new();
+            let request_info = match HeaderValue::from_str(&token)
+                .map_err(|err| err.to_string())
+                .and_then(|token| {
+                    headers.insert(AUTHORIZATION, token);
+                    DiscordRequestInfo::new(
+                        &Method::GET,
+                        "/gateway/bot",
+                        &headers,
+                        &self.config,
+                        None,
+                    )
+                    .map_err(|err| err.to_string())
+                }) {
+                Ok(request_info) => request_info,
+                Err(err) => {
+                    tracing::warn!(
+                        "Token on line {} of {} is malformed: {}",
+                        index + 1,
+                        path,
+                        err
+                    );
+                    invalid += 1;
+                    continue;
+                }
+            };
+
+            let global_id = request_info.log_global_id.as_str();
+
+            let result = self.fetch_discord_global_ratelimit(&token).await;
+
+            match token_validity(&result) {
+                TokenValidity::Valid => {
+                    tracing::debug!(global_id, "Token on line {} is valid.", index + 1);
+                    valid += 1;
+                }
+                TokenValidity::Invalid => {
+                    tracing::warn!(
+                        global_id,
+                        "Token on line {} of {} is invalid or has been revoked.",
+                        index + 1,
+                        path
+                    );
+                    invalid += 1;
+                }
+                TokenValidity::Unknown => {
+                    if let Err(err) = result {
+                        tracing::warn!(
+                            global_id,
+                            "Couldn't validate the token on line {} of {}: {}",
+                            index + 1,
+                            path,
+                            err
+                        )
+                    }
+                }
+            }
+        }
+
+        tracing::info!(
+            "Validated tokens from {}: {} valid, {} invalid.",
+            path,
+            valid,
+            invalid
+        );
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TokenValidity {
+    Valid,
+    Invalid,
+    /// Discord couldn't be asked, or answered with something other than a verdict on the token
+    Unknown,
+}
+
+/// Only a 401 means Discord rejected the token, other failures say nothing about it.
+fn token_validity<T>(result: &Result<T, DiscordError>) -> TokenValidity {
+    match result {
+        Ok(_) => TokenValidity::Valid,
+        Err(DiscordError::BadStatus(StatusCode::UNAUTHORIZED)) => TokenValidity::Invalid,
+        Err(_) => TokenValidity::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_unauthorized_tokens_are_invalid() {
+        // A 200 from /gateway/bot is parsed into the bot's global limit
+        assert_eq!(
+            token_validity(&Ok::<_, DiscordError>(50)),
+            TokenValidity::Valid
+        );
+
+        assert_eq!(
+            token_validity::<u16>(&Err(DiscordError::BadStatus(StatusCode::UNAUTHORIZED))),
+            TokenValidity::Invalid
+        );
+
+        for status in [
+            StatusCode::FORBIDDEN,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::BAD_GATEWAY,
+        ] {
+            assert_eq!(
+                token_validity::<u16>(&Err(DiscordError::BadStatus(status))),
+                TokenValidity::Unknown
+            );
+        }
+
+        let parse_error = serde_json::from_str::<u16>("{").unwrap_err();
+        assert_eq!(
+            token_validity::<u16>(&Err(DiscordError::Parse(parse_error))),
+            TokenValidity::Unknown
+        );
+    }
+}